@@ -1,7 +1,10 @@
 extern crate criterion;
+use slidestream::generator::openslide::{decode_buffer, decode_buffer_into, WordRepresentation};
+use slidestream::generator::resample::{self, ResampleFilter};
 use slidestream::generator::DeepZoomGenerator;
 
 use criterion::{criterion_group, criterion_main, Criterion};
+use image::RgbaImage;
 use std::path::Path;
 
 fn bench_get_tile(c: &mut Criterion) {
@@ -11,5 +14,54 @@ fn bench_get_tile(c: &mut Criterion) {
     c.bench_function("get_tile", move |b| b.iter(|| g.get_tile(9, 1, 1).unwrap()));
 }
 
-criterion_group!(benches, bench_get_tile);
+fn bench_resize(c: &mut Criterion) {
+    // A region read for an intermediate Deep Zoom level, twice the size of the tile.
+    let region = RgbaImage::from_fn(512, 512, |x, y| image::Rgba([x as u8, y as u8, 128, 255]));
+
+    let mut group = c.benchmark_group("resize_2x");
+    for filter in [
+        ResampleFilter::Nearest,
+        ResampleFilter::Area,
+        ResampleFilter::Bilinear,
+        ResampleFilter::Lanczos3,
+    ] {
+        group.bench_function(format!("{:?}", filter).to_lowercase(), |b| {
+            b.iter(|| resample::resize(&region, 256, 256, filter))
+        });
+    }
+    group.bench_function("thumbnail", |b| {
+        b.iter(|| image::imageops::thumbnail(&region, 256, 256))
+    });
+    group.finish();
+}
+
+fn bench_decode_buffer(c: &mut Criterion) {
+    // A read_region() buffer of a tile with a transparent border, as OpenSlide returns it.
+    let (width, height) = (512u32, 512u32);
+    let buffer: Vec<u32> = (0..width * height)
+        .map(|i| match i % width < 32 {
+            true => 0,
+            false => 0xff000000 | i.wrapping_mul(2654435761) >> 8,
+        })
+        .collect();
+
+    c.bench_function("decode_buffer", |b| {
+        b.iter(|| decode_buffer(&buffer, height, width, WordRepresentation::BigEndian).unwrap())
+    });
+    let mut output = RgbaImage::new(width, height);
+    c.bench_function("decode_buffer_into", |b| {
+        b.iter(|| {
+            decode_buffer_into(
+                &buffer,
+                height,
+                width,
+                WordRepresentation::BigEndian,
+                &mut output,
+            )
+            .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, bench_get_tile, bench_resize, bench_decode_buffer);
 criterion_main!(benches);
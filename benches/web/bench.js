@@ -7,7 +7,7 @@ async function main() {
     const row_range = 20
     const level = 18
     const queue = new PQueue({ concurrency: 50 })
-    const res = await axios.get("http://localhost:8080/slide_1.dzi")
+    const res = await axios.get("http://localhost:8080/CMU-1-Small-Region.svs.dzi")
     console.log(res.data)
     const code = `${col_range * row_range} total requests`
     console.time(code)
@@ -15,7 +15,7 @@ async function main() {
         for (let col = 0; col < col_range; col++) {
             queue.add(async () => {
                 try {
-                    const url = `http://localhost:8080/slide_1_files/${level}/${col}_${row}.jpg`
+                    const url = `http://localhost:8080/CMU-1-Small-Region.svs_files/${level}/${col}_${row}.jpg`
                     const resp = await axios.get(url)
                     return resp.data.length
                 } catch (e) {
@@ -0,0 +1,418 @@
+//! Sources of slide pixels.
+//!
+//! A `SlideBackend` is a multi-resolution image: a number of levels, each a downsample of level 0,
+//! from which regions can be read. The Deep Zoom generator, the region and IIIF endpoints and the
+//! exports only use this interface, so they work the same for every slide format.
+//!
+//! Aperio SVS and generic tiled TIFF files are read by `TiffFile`, which can serve their stored
+//! JPEG tiles as they are. OpenSlide reads all other slide formats, and the TIFFs `TiffFile` can
+//! not decode. Ordinary images (PNG, JPEG, plain TIFF, ...) that OpenSlide does not recognize are
+//! served by `ImageFile`. A directory of DICOM whole slide image instances is read by `DicomSlide`.
+//!
+//! Backends report errors as `OpenSlideError`, whose variants are not specific to OpenSlide.
+
+pub mod dicom;
+pub mod image_file;
+pub mod tiff_file;
+
+use crate::generator::openslide::{OpenSlide, OpenSlideError};
+use crate::generator::resample::ResampleFilter;
+use image::RgbaImage;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub use self::dicom::DicomSlide;
+pub use self::image_file::ImageFile;
+pub use self::tiff_file::TiffFile;
+
+/// Number of decoded tiles kept in memory by the backends that decode tiles themselves.
+const DECODED_TILE_CACHE_SIZE: usize = 64;
+
+/// Adobe APP14 segment saying the components of a JPEG are RGB, not YCbCr.
+const ADOBE_RGB: [u8; 16] = [
+    0xff, 0xee, 0, 14, b'A', b'd', b'o', b'b', b'e', 0, 100, 0, 0, 0, 0, 0,
+];
+
+/// A multi-resolution image that slides are served from.
+///
+/// Levels are numbered from 0, the full resolution, with increasing downsamples. Backends must be
+/// usable from several threads at once.
+pub trait SlideBackend: Send + Sync {
+    /// Number of levels.
+    fn level_count(&self) -> Result<u32, OpenSlideError>;
+
+    /// The (width, height) of a level, in pixels.
+    fn level_dimensions(&self, level: u32) -> Result<(u64, u64), OpenSlideError>;
+
+    /// The downsample factor of a level relative to level 0.
+    fn level_downsample(&self, level: u32) -> Result<f64, OpenSlideError>;
+
+    /// Read `size` (width, height) pixels of a level, starting at the level 0 pixel `location`
+    /// (x, y), as `openslide_read_region` does. Pixels outside the image are transparent.
+    fn read_region(
+        &self,
+        location: (u64, u64),
+        level: u32,
+        size: (u64, u64),
+    ) -> Result<RgbaImage, OpenSlideError>;
+
+    /// The (width, height) of level 0.
+    fn level0_dimensions(&self) -> Result<(u64, u64), OpenSlideError> {
+        self.level_dimensions(0)
+    }
+
+    /// The level to read from to display the given downsample: the most downsampled level that is
+    /// still at least as detailed.
+    fn best_level_for_downsample(&self, downsample: f64) -> Result<u32, OpenSlideError> {
+        if downsample < 0.0 {
+            return Err(OpenSlideError::InvalidArgument(format!(
+                "Only non-negative downsample factors are allowed, not {}",
+                downsample
+            )));
+        }
+        let level_count = self.level_count()?;
+        for level in 1..level_count {
+            if downsample < self.level_downsample(level)? {
+                return Ok(level - 1);
+            }
+        }
+        Ok(level_count.saturating_sub(1))
+    }
+
+    /// Name of the format or scanner vendor.
+    fn vendor(&self) -> Option<String> {
+        None
+    }
+
+    fn objective_power(&self) -> Option<u32> {
+        None
+    }
+
+    /// Micrometers per pixel (x, y) at level 0.
+    fn mpp(&self) -> Option<(f64, f64)> {
+        None
+    }
+
+    /// The JPEG stored for the `size` (width, height) pixels of a level at `location` (x, y), in
+    /// pixels of that level, if the backend stores exactly that area as a single JPEG.
+    ///
+    /// Serving it as it is avoids decoding and re-encoding the pixels. Backends that do not store
+    /// JPEG tiles return `None`, as do the others for any area that is not exactly one tile.
+    fn read_jpeg_tile(
+        &self,
+        _level: u32,
+        _location: (u64, u64),
+        _size: (u64, u64),
+    ) -> Result<Option<Vec<u8>>, OpenSlideError> {
+        Ok(None)
+    }
+
+    /// The level 0 bounds (x, y, width, height) of the non-empty area of the image, as far as they
+    /// are known.
+    fn bounds(&self) -> (Option<u64>, Option<u64>, Option<u64>, Option<u64>) {
+        (None, None, None, None)
+    }
+
+    /// All metadata of the image as key-value pairs, named like OpenSlide properties.
+    fn properties(&self) -> Result<HashMap<String, String>, OpenSlideError> {
+        Ok(HashMap::new())
+    }
+
+    /// Names of the associated images (e.g. `label` or `macro`).
+    fn associated_image_names(&self) -> Result<Vec<String>, OpenSlideError> {
+        Ok(Vec::new())
+    }
+
+    fn read_associated_image(&self, name: &str) -> Result<RgbaImage, OpenSlideError> {
+        Err(OpenSlideError::AssociatedImageNotFound(name.to_string()))
+    }
+
+    /// The error that made the backend unusable, if any. Such a backend should be reopened.
+    fn error(&self) -> Option<String> {
+        None
+    }
+}
+
+impl SlideBackend for OpenSlide {
+    fn level_count(&self) -> Result<u32, OpenSlideError> {
+        self.get_level_count()
+    }
+
+    fn level_dimensions(&self, level: u32) -> Result<(u64, u64), OpenSlideError> {
+        self.get_level_dimensions(level)
+    }
+
+    fn level_downsample(&self, level: u32) -> Result<f64, OpenSlideError> {
+        self.get_level_downsample(level)
+    }
+
+    fn read_region(
+        &self,
+        (x, y): (u64, u64),
+        level: u32,
+        (width, height): (u64, u64),
+    ) -> Result<RgbaImage, OpenSlideError> {
+        // Note that the rust openslide bindings expect (row, col) and (height, width).
+        OpenSlide::read_region(self, y, x, level as u64, height, width)
+    }
+
+    fn level0_dimensions(&self) -> Result<(u64, u64), OpenSlideError> {
+        self.get_level0_dimensions()
+    }
+
+    fn best_level_for_downsample(&self, downsample: f64) -> Result<u32, OpenSlideError> {
+        self.get_best_level_for_downsample(downsample)
+    }
+
+    fn vendor(&self) -> Option<String> {
+        self.properties.vendor()
+    }
+
+    fn objective_power(&self) -> Option<u32> {
+        self.properties.objective_power()
+    }
+
+    fn mpp(&self) -> Option<(f64, f64)> {
+        match (self.properties.mpp_x(), self.properties.mpp_y()) {
+            (Some(x), Some(y)) if x > 0.0 && y > 0.0 => Some((x as f64, y as f64)),
+            _ => None,
+        }
+    }
+
+    fn bounds(&self) -> (Option<u64>, Option<u64>, Option<u64>, Option<u64>) {
+        (
+            self.properties.bounds_x(),
+            self.properties.bounds_y(),
+            self.properties.bounds_width(),
+            self.properties.bounds_height(),
+        )
+    }
+
+    fn properties(&self) -> Result<HashMap<String, String>, OpenSlideError> {
+        self.get_properties()
+    }
+
+    fn associated_image_names(&self) -> Result<Vec<String>, OpenSlideError> {
+        self.get_associated_image_names()
+    }
+
+    fn read_associated_image(&self, name: &str) -> Result<RgbaImage, OpenSlideError> {
+        OpenSlide::read_associated_image(self, name)
+    }
+
+    fn error(&self) -> Option<String> {
+        self.get_error()
+    }
+}
+
+/// Extensions of the slide formats OpenSlide reads, in lowercase.
+const SLIDE_EXTENSIONS: &[&str] = &[
+    "bif", "mrxs", "ndpi", "scn", "svs", "svslide", "tif", "tiff", "vms", "vmu",
+];
+
+/// Whether `path` looks like a slide that one of the backends can open.
+///
+/// Files are judged by their extension and directories by the header of a single file, so that
+/// finding the slides in a large tree does not need to read every file in it. Whether a slide can
+/// really be read only shows when it is opened.
+pub fn is_supported(path: &Path) -> bool {
+    if path.is_dir() {
+        return DicomSlide::is_supported(path);
+    }
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    extension.is_some_and(|extension| SLIDE_EXTENSIONS.contains(&extension.as_str()))
+        || ImageFile::is_supported(path)
+}
+
+/// Open the slide at `path` with the first backend that supports it.
+///
+/// Backends that build their own pyramid (`ImageFile`) downsample its levels with `resample`.
+pub fn open(
+    path: &Path,
+    resample: ResampleFilter,
+) -> Result<Arc<dyn SlideBackend>, OpenSlideError> {
+    if !path.exists() {
+        return Err(OpenSlideError::NotFound(path.to_path_buf()));
+    }
+    if path.is_dir() {
+        return Ok(Arc::new(DicomSlide::open(path)?));
+    }
+    // Other TIFF based formats (e.g. Philips, Ventana, Leica) need OpenSlide to be read right.
+    let vendor = OpenSlide::detect_vendor(path)?;
+    if matches!(
+        vendor.as_deref(),
+        None | Some("aperio") | Some("generic-tiff")
+    ) {
+        match TiffFile::open(path) {
+            Ok(tiff) => return Ok(Arc::new(tiff)),
+            Err(OpenSlideError::Unsupported(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    if vendor.is_some() {
+        return Ok(Arc::new(OpenSlide::new(path)?));
+    }
+    if ImageFile::is_supported(path) {
+        return Ok(Arc::new(ImageFile::open(path, resample)?));
+    }
+    Err(OpenSlideError::Unsupported(path.to_path_buf()))
+}
+
+/// Recently decoded tiles of a backend, by level and tile index.
+struct DecodedTiles(Mutex<LruCache<(u32, u64), Arc<RgbaImage>>>);
+
+impl DecodedTiles {
+    fn new() -> DecodedTiles {
+        let size = NonZeroUsize::new(DECODED_TILE_CACHE_SIZE).expect("cache size is not zero");
+        DecodedTiles(Mutex::new(LruCache::new(size)))
+    }
+
+    /// The tile from the cache, or else from `decode`.
+    fn get_or_decode<F>(&self, key: (u32, u64), decode: F) -> Result<Arc<RgbaImage>, OpenSlideError>
+    where
+        F: FnOnce() -> Result<RgbaImage, OpenSlideError>,
+    {
+        if let Some(tile) = self.0.lock().unwrap().get(&key) {
+            return Ok(tile.clone());
+        }
+        // Not holding the lock while decoding; a tile decoded twice at once is no problem.
+        let tile = Arc::new(decode()?);
+        self.0.lock().unwrap().put(key, tile.clone());
+        Ok(tile)
+    }
+}
+
+/// Read `size` (width, height) pixels at `location` (x, y) of an image of `image_size` pixels,
+/// stored in tiles of `tile_size`.
+///
+/// `tile(col, row)` gives the pixels of a tile, or `None` for a tile that is not stored. Pixels
+/// outside the image and in missing tiles are transparent.
+fn read_tiled_region<F>(
+    image_size: (u64, u64),
+    tile_size: (u64, u64),
+    (x, y): (u64, u64),
+    (width, height): (u64, u64),
+    mut tile: F,
+) -> Result<RgbaImage, OpenSlideError>
+where
+    F: FnMut(u64, u64) -> Result<Option<Arc<RgbaImage>>, OpenSlideError>,
+{
+    let mut region = RgbaImage::new(width as u32, height as u32);
+    let right = (x + width).min(image_size.0);
+    let bottom = (y + height).min(image_size.1);
+    for row in y / tile_size.1..bottom.div_ceil(tile_size.1) {
+        for col in x / tile_size.0..right.div_ceil(tile_size.0) {
+            let pixels = match tile(col, row)? {
+                Some(pixels) => pixels,
+                None => continue,
+            };
+            // The part of the tile inside the region and the image, in image coordinates.
+            let (tile_x, tile_y) = (col * tile_size.0, row * tile_size.1);
+            let left = tile_x.max(x);
+            let top = tile_y.max(y);
+            let tile_right = (tile_x + pixels.width() as u64).min(right);
+            let tile_bottom = (tile_y + pixels.height() as u64).min(bottom);
+            for image_y in top..tile_bottom {
+                for image_x in left..tile_right {
+                    let pixel =
+                        pixels.get_pixel((image_x - tile_x) as u32, (image_y - tile_y) as u32);
+                    region.put_pixel((image_x - x) as u32, (image_y - y) as u32, *pixel);
+                }
+            }
+        }
+    }
+    Ok(region)
+}
+
+/// Read exactly `buf.len()` bytes of `file`, starting at `offset`.
+///
+/// This does not use the position of the file, so several threads can read from it at once.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// Read exactly `buf.len()` bytes of `file`, starting at `offset`.
+///
+/// This moves the position of the file, but reads do not depend on it, so several threads can
+/// read from it at once.
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// A complete JPEG stream from a JPEG compressed tile.
+///
+/// TIFF tiles usually lack the quantization and Huffman tables, which are stored once for the
+/// whole image in `tables` (itself a JPEG stream holding just the tables). They are inserted after
+/// the start of image marker. Aperio stores RGB instead of YCbCr components in many files, which a
+/// decoder only knows from an Adobe APP14 segment, added if `rgb`.
+fn complete_jpeg(tables: Option<&[u8]>, tile: &[u8], rgb: bool) -> Vec<u8> {
+    const SOI: [u8; 2] = [0xff, 0xd8];
+    const EOI: [u8; 2] = [0xff, 0xd9];
+    let tile = tile.strip_prefix(&SOI).unwrap_or(tile);
+    let tables = tables.map_or(&[][..], |tables| {
+        let tables = tables.strip_prefix(&SOI).unwrap_or(tables);
+        tables.strip_suffix(&EOI).unwrap_or(tables)
+    });
+    let mut jpeg = Vec::with_capacity(2 + ADOBE_RGB.len() + tables.len() + tile.len());
+    jpeg.extend_from_slice(&SOI);
+    if rgb {
+        jpeg.extend_from_slice(&ADOBE_RGB);
+    }
+    jpeg.extend_from_slice(tables);
+    jpeg.extend_from_slice(tile);
+    jpeg
+}
+
+#[test]
+fn test_complete_jpeg() {
+    let tables = [0xff, 0xd8, 0xff, 0xdb, 0, 2, 0xff, 0xd9];
+    let tile = [0xff, 0xd8, 0xff, 0xc0, 0, 2, 0xff, 0xd9];
+    assert_eq!(
+        complete_jpeg(Some(&tables), &tile, false),
+        vec![0xff, 0xd8, 0xff, 0xdb, 0, 2, 0xff, 0xc0, 0, 2, 0xff, 0xd9]
+    );
+    let jpeg = complete_jpeg(None, &tile, true);
+    assert_eq!(jpeg[..2], [0xff, 0xd8]);
+    assert_eq!(jpeg[2..18], ADOBE_RGB);
+    assert_eq!(jpeg[18..], tile[2..]);
+}
+
+#[test]
+fn test_read_tiled_region() {
+    let tile = |value: u8| Arc::new(RgbaImage::from_pixel(4, 4, image::Rgba([value, 0, 0, 255])));
+    // A 10 x 6 image of 4 pixel tiles, with tile (1, 1) missing.
+    let region = read_tiled_region((10, 6), (4, 4), (2, 2), (10, 10), |col, row| {
+        Ok(((col, row) != (1, 1)).then(|| tile((row * 3 + col) as u8)))
+    })
+    .unwrap();
+    assert_eq!(region.dimensions(), (10, 10));
+    assert_eq!(region.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    assert_eq!(region.get_pixel(2, 0).0, [1, 0, 0, 255]);
+    assert_eq!(region.get_pixel(0, 2).0, [3, 0, 0, 255]);
+    assert_eq!(region.get_pixel(2, 2).0, [0, 0, 0, 0]);
+    assert_eq!(region.get_pixel(7, 3).0, [5, 0, 0, 255]);
+    // Outside the image.
+    assert_eq!(region.get_pixel(8, 0).0, [0, 0, 0, 0]);
+    assert_eq!(region.get_pixel(0, 4).0, [0, 0, 0, 0]);
+}
@@ -0,0 +1,838 @@
+//! DICOM whole slide images (VL Whole Slide Microscopy Image, DICOM Supplement 145).
+//!
+//! A slide is a directory holding the instances of a series, as exported by a PACS. Every instance
+//! holds one level of the pyramid, or a part of one (a concatenation), as a number of tiled frames.
+//! The levels are told apart by the size of their total pixel matrix. Label, overview and thumbnail
+//! images are the associated images `label`, `macro` and `thumbnail`.
+//!
+//! Frames can be uncompressed or baseline JPEG. JPEG frames are served as they are where a Deep
+//! Zoom tile has exactly their pixels, like the tiles of a `TiffFile`.
+
+pub mod dataset;
+
+use self::dataset::{DataSet, DicomFile, PixelData, Tag, MEDIA_STORAGE_SOP_CLASS_UID};
+use super::{complete_jpeg, read_exact_at, read_tiled_region, DecodedTiles, SlideBackend};
+use crate::generator::openslide::OpenSlideError;
+use image::{ImageFormat, Rgba, RgbaImage};
+use log::warn;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+/// SOP class of VL Whole Slide Microscopy Image instances.
+const WSI_SOP_CLASS: &str = "1.2.840.10008.5.1.4.1.1.77.1.6";
+
+// Transfer syntaxes read.
+const IMPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+const JPEG_BASELINE: &str = "1.2.840.10008.1.2.4.50";
+const JPEG_EXTENDED: &str = "1.2.840.10008.1.2.4.51";
+
+// Attributes read.
+const IMAGE_TYPE: Tag = (0x0008, 0x0008);
+const STUDY_DATE: Tag = (0x0008, 0x0020);
+const MANUFACTURER: Tag = (0x0008, 0x0070);
+const MANUFACTURER_MODEL_NAME: Tag = (0x0008, 0x1090);
+const SERIES_DESCRIPTION: Tag = (0x0008, 0x103e);
+const DEVICE_SERIAL_NUMBER: Tag = (0x0018, 0x1000);
+const SOFTWARE_VERSIONS: Tag = (0x0018, 0x1020);
+const STUDY_INSTANCE_UID: Tag = (0x0020, 0x000d);
+const SERIES_INSTANCE_UID: Tag = (0x0020, 0x000e);
+const CONCATENATION_FRAME_OFFSET_NUMBER: Tag = (0x0020, 0x9161);
+const DIMENSION_ORGANIZATION_TYPE: Tag = (0x0020, 0x9311);
+const SAMPLES_PER_PIXEL: Tag = (0x0028, 0x0002);
+const PHOTOMETRIC_INTERPRETATION: Tag = (0x0028, 0x0004);
+const PLANAR_CONFIGURATION: Tag = (0x0028, 0x0006);
+const NUMBER_OF_FRAMES: Tag = (0x0028, 0x0008);
+const ROWS: Tag = (0x0028, 0x0010);
+const COLUMNS: Tag = (0x0028, 0x0011);
+const PIXEL_SPACING: Tag = (0x0028, 0x0030);
+const BITS_ALLOCATED: Tag = (0x0028, 0x0100);
+const PIXEL_MEASURES_SEQUENCE: Tag = (0x0028, 0x9110);
+const CONTAINER_IDENTIFIER: Tag = (0x0040, 0x0512);
+const IMAGED_VOLUME_WIDTH: Tag = (0x0048, 0x0001);
+const IMAGED_VOLUME_HEIGHT: Tag = (0x0048, 0x0002);
+const TOTAL_PIXEL_MATRIX_COLUMNS: Tag = (0x0048, 0x0006);
+const TOTAL_PIXEL_MATRIX_ROWS: Tag = (0x0048, 0x0007);
+const OPTICAL_PATH_SEQUENCE: Tag = (0x0048, 0x0105);
+const OBJECTIVE_LENS_POWER: Tag = (0x0048, 0x0112);
+const PLANE_POSITION_SLIDE_SEQUENCE: Tag = (0x0048, 0x021a);
+const COLUMN_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX: Tag = (0x0048, 0x021e);
+const ROW_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX: Tag = (0x0048, 0x021f);
+const SHARED_FUNCTIONAL_GROUPS_SEQUENCE: Tag = (0x5200, 0x9229);
+const PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE: Tag = (0x5200, 0x9230);
+
+/// Largest number of tiles of a level, to guard against corrupt files. A slide of 200,000 pixels
+/// square has about 600,000 tiles of 256 pixels.
+const MAX_LEVEL_TILES: u64 = 1 << 22;
+
+/// The frames of an instance, and how to decode them.
+struct Instance {
+    path: PathBuf,
+    file: File,
+    /// The fragments (offset, length) of every frame.
+    frames: Vec<Vec<(u64, u64)>>,
+    jpeg: bool,
+    photometric: String,
+    samples_per_pixel: u16,
+    /// Width and height of the frames.
+    frame_size: (u64, u64),
+}
+
+impl Instance {
+    fn read_frame_data(&self, frame: usize) -> Result<Vec<u8>, OpenSlideError> {
+        let fragments = self.frames.get(frame).ok_or_else(|| {
+            OpenSlideError::Read(format!("{}: no frame {}", self.path.display(), frame))
+        })?;
+        let mut data =
+            Vec::with_capacity(fragments.iter().map(|(_, length)| *length as usize).sum());
+        for (offset, length) in fragments {
+            let start = data.len();
+            data.resize(start + *length as usize, 0);
+            read_exact_at(&self.file, &mut data[start..], *offset)
+                .map_err(|err| read_error(&self.path, err))?;
+        }
+        Ok(data)
+    }
+
+    /// The frame as a complete JPEG stream.
+    fn read_jpeg(&self, frame: usize) -> Result<Vec<u8>, OpenSlideError> {
+        let data = self.read_frame_data(frame)?;
+        Ok(complete_jpeg(None, &data, self.photometric == "RGB"))
+    }
+
+    fn decode_frame(&self, frame: usize) -> Result<RgbaImage, OpenSlideError> {
+        let decode_error = |msg: String| {
+            OpenSlideError::Read(format!("{}: frame {}: {}", self.path.display(), frame, msg))
+        };
+        if self.jpeg {
+            let jpeg = self.read_jpeg(frame)?;
+            let image = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg)
+                .map_err(|err| decode_error(err.to_string()))?;
+            return Ok(image.into_rgba8());
+        }
+        let data = self.read_frame_data(frame)?;
+        let (width, height) = self.frame_size;
+        let channels = self.samples_per_pixel as usize;
+        if data.len() < (width * height) as usize * channels {
+            return Err(decode_error(format!("only {} bytes", data.len())));
+        }
+        let monochrome1 = self.photometric == "MONOCHROME1";
+        Ok(RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+            let offset = (y as usize * width as usize + x as usize) * channels;
+            match channels {
+                3 => Rgba([data[offset], data[offset + 1], data[offset + 2], 255]),
+                _ if monochrome1 => Rgba([
+                    255 - data[offset],
+                    255 - data[offset],
+                    255 - data[offset],
+                    255,
+                ]),
+                _ => Rgba([data[offset], data[offset], data[offset], 255]),
+            }
+        }))
+    }
+}
+
+/// A level of the pyramid, possibly spread over several instances.
+struct Level {
+    width: u64,
+    height: u64,
+    tile_width: u64,
+    tile_height: u64,
+    /// The instance and frame of every tile, row by row. Sparse images can lack tiles.
+    tiles: Vec<Option<(usize, usize)>>,
+}
+
+impl Level {
+    fn columns(&self) -> u64 {
+        self.width.div_ceil(self.tile_width)
+    }
+}
+
+/// A DICOM whole slide image series in a directory.
+pub struct DicomSlide {
+    instances: Vec<Instance>,
+    levels: Vec<Level>,
+    /// The name, instance and size of the associated images.
+    associated: Vec<(String, usize, (u64, u64))>,
+    properties: HashMap<String, String>,
+    decoded_tiles: DecodedTiles,
+}
+
+impl DicomSlide {
+    /// Whether `path` is a directory with whole slide image instances.
+    ///
+    /// Only the files named `*.dcm` or without an extension are looked at, up to the first DICOM
+    /// file: the instances of a series are all of the same SOP class.
+    pub fn is_supported(path: &Path) -> bool {
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let candidate = path
+                .extension()
+                .is_none_or(|extension| extension.eq_ignore_ascii_case("dcm"));
+            if !candidate || !path.is_file() {
+                continue;
+            }
+            let meta = File::open(&path)
+                .and_then(|file| dataset::read_meta(BufReader::new(file)))
+                .ok()
+                .flatten();
+            if let Some(meta) = meta {
+                return meta.string(MEDIA_STORAGE_SOP_CLASS_UID).as_deref() == Some(WSI_SOP_CLASS);
+            }
+        }
+        false
+    }
+
+    /// Open the whole slide image in the directory at `path`.
+    ///
+    /// If the directory holds several series, the one with the largest image is opened.
+    pub fn open(path: &Path) -> Result<DicomSlide, OpenSlideError> {
+        if !path.exists() {
+            return Err(OpenSlideError::NotFound(path.to_path_buf()));
+        }
+        let mut files = Vec::new();
+        let entries = std::fs::read_dir(path).map_err(|err| read_error(path, err))?;
+        for entry in entries {
+            let file_path = entry.map_err(|err| read_error(path, err))?.path();
+            if !file_path.is_file() {
+                continue;
+            }
+            let file = File::open(&file_path).map_err(|err| read_error(&file_path, err))?;
+            let dicom = match dataset::read(BufReader::new(file)) {
+                Ok(Some(dicom)) => dicom,
+                // Other files, e.g. a DICOMDIR or notes, are ignored.
+                Ok(None) | Err(_) => continue,
+            };
+            if dicom.meta.string(MEDIA_STORAGE_SOP_CLASS_UID).as_deref() == Some(WSI_SOP_CLASS) {
+                files.push((file_path, dicom));
+            }
+        }
+        let largest = files
+            .iter()
+            .max_by_key(|(_, dicom)| total_pixel_matrix(&dicom.dataset))
+            .ok_or_else(|| OpenSlideError::Unsupported(path.to_path_buf()))?;
+        let series = largest.1.dataset.string(SERIES_INSTANCE_UID);
+        files.retain(|(_, dicom)| dicom.dataset.string(SERIES_INSTANCE_UID) == series);
+        // Instances in the order of their levels, and of their frames within a level.
+        files.sort_by_key(|(_, dicom)| {
+            let (width, height) = total_pixel_matrix(&dicom.dataset);
+            let offset = dicom.dataset.u32(CONCATENATION_FRAME_OFFSET_NUMBER);
+            (std::cmp::Reverse(width * height), offset.unwrap_or(0))
+        });
+
+        let mut instances = Vec::new();
+        let mut levels: Vec<Level> = Vec::new();
+        let mut associated = Vec::new();
+        let mut first = None;
+        for (file_path, dicom) in files {
+            let image_type = dicom.dataset.strings(IMAGE_TYPE);
+            let flavor = image_type
+                .get(2)
+                .map_or("VOLUME", String::as_str)
+                .to_string();
+            let instance = match open_instance(&file_path, &dicom) {
+                Ok(instance) => instance,
+                // Only the levels are needed to serve the slide, e.g. a label may well be JPEG
+                // 2000.
+                Err(err) if flavor != "VOLUME" => {
+                    warn!("Skipping {} image: {}", flavor, err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let index = instances.len();
+            instances.push(instance);
+            let size = total_pixel_matrix(&dicom.dataset);
+            match flavor.as_str() {
+                "VOLUME" => {
+                    add_to_level(&mut levels, &dicom.dataset, index, &instances[index])?;
+                    first.get_or_insert(dicom);
+                }
+                "LABEL" => associated.push(("label".to_string(), index, size)),
+                "OVERVIEW" => associated.push(("macro".to_string(), index, size)),
+                "THUMBNAIL" => associated.push(("thumbnail".to_string(), index, size)),
+                _ => {}
+            }
+        }
+        let first = first.ok_or_else(|| OpenSlideError::Unsupported(path.to_path_buf()))?;
+        let properties = dicom_properties(&first.dataset, &levels);
+        Ok(DicomSlide {
+            instances,
+            levels,
+            associated,
+            properties,
+            decoded_tiles: DecodedTiles::new(),
+        })
+    }
+
+    fn level(&self, level: u32) -> Result<&Level, OpenSlideError> {
+        self.levels
+            .get(level as usize)
+            .ok_or(OpenSlideError::LevelOutOfRange {
+                level,
+                level_count: self.levels.len() as u32,
+            })
+    }
+}
+
+/// The (width, height) of the whole image of an instance.
+fn total_pixel_matrix(dataset: &DataSet) -> (u64, u64) {
+    let size = |total, frame| {
+        dataset
+            .u32(total)
+            .map(|size| size as u64)
+            .or_else(|| dataset.u16(frame).map(|size| size as u64))
+            .unwrap_or(0)
+    };
+    (
+        size(TOTAL_PIXEL_MATRIX_COLUMNS, COLUMNS),
+        size(TOTAL_PIXEL_MATRIX_ROWS, ROWS),
+    )
+}
+
+fn open_instance(path: &Path, dicom: &DicomFile) -> Result<Instance, OpenSlideError> {
+    let unsupported = |what: String| {
+        OpenSlideError::Read(format!("{}: {} is not supported", path.display(), what))
+    };
+    let transfer_syntax = dicom.transfer_syntax().unwrap_or_default();
+    let jpeg = match transfer_syntax.as_str() {
+        IMPLICIT_VR_LITTLE_ENDIAN | EXPLICIT_VR_LITTLE_ENDIAN => false,
+        JPEG_BASELINE | JPEG_EXTENDED => true,
+        other => return Err(unsupported(format!("Transfer syntax {}", other))),
+    };
+    let dataset = &dicom.dataset;
+    let photometric = dataset
+        .string(PHOTOMETRIC_INTERPRETATION)
+        .unwrap_or_default();
+    let samples_per_pixel = dataset.u16(SAMPLES_PER_PIXEL).unwrap_or(1);
+    let frame_size = (
+        dataset.u16(COLUMNS).unwrap_or(0) as u64,
+        dataset.u16(ROWS).unwrap_or(0) as u64,
+    );
+    let frame_count = dataset.number(NUMBER_OF_FRAMES).unwrap_or(1.0) as u64;
+    let native_supported = match photometric.as_str() {
+        "RGB" => samples_per_pixel == 3 && dataset.u16(PLANAR_CONFIGURATION).unwrap_or(0) == 0,
+        "MONOCHROME1" | "MONOCHROME2" => samples_per_pixel == 1,
+        _ => false,
+    };
+    if !jpeg && (!native_supported || dataset.u16(BITS_ALLOCATED) != Some(8)) {
+        return Err(unsupported(format!(
+            "Uncompressed {} with {} samples",
+            photometric, samples_per_pixel
+        )));
+    }
+
+    let frames = match &dicom.pixel_data {
+        Some(PixelData::Encapsulated { frames }) => frames.clone(),
+        Some(PixelData::Native { offset, length }) => {
+            let frame_length = frame_size
+                .0
+                .checked_mul(frame_size.1)
+                .and_then(|pixels| pixels.checked_mul(samples_per_pixel as u64))
+                .filter(|frame_length| *frame_length > 0)
+                .ok_or_else(|| {
+                    OpenSlideError::Read(format!(
+                        "{}: invalid frame size {}x{}",
+                        path.display(),
+                        frame_size.0,
+                        frame_size.1
+                    ))
+                })?;
+            // Only the frames that are present in the pixel data are read.
+            (0..frame_count.min(length / frame_length))
+                .map(|frame| vec![(offset + frame * frame_length, frame_length)])
+                .collect()
+        }
+        None => {
+            return Err(OpenSlideError::Read(format!(
+                "{}: no pixel data",
+                path.display()
+            )))
+        }
+    };
+    let file = File::open(path).map_err(|err| read_error(path, err))?;
+    Ok(Instance {
+        path: path.to_path_buf(),
+        file,
+        frames,
+        jpeg,
+        photometric,
+        samples_per_pixel,
+        frame_size,
+    })
+}
+
+/// Add the frames of an instance to the level of its size, creating the level if needed.
+///
+/// Returns an error if the level would have more than `MAX_LEVEL_TILES` tiles.
+fn add_to_level(
+    levels: &mut Vec<Level>,
+    dataset: &DataSet,
+    index: usize,
+    instance: &Instance,
+) -> Result<(), OpenSlideError> {
+    let (width, height) = total_pixel_matrix(dataset);
+    let (tile_width, tile_height) = instance.frame_size;
+    if tile_width == 0 || tile_height == 0 {
+        return Ok(());
+    }
+    let level = match levels
+        .iter()
+        .position(|level| (level.width, level.height) == (width, height))
+    {
+        Some(level) => level,
+        None => {
+            let tiles = width
+                .div_ceil(tile_width)
+                .checked_mul(height.div_ceil(tile_height))
+                .filter(|tiles| *tiles <= MAX_LEVEL_TILES)
+                .ok_or_else(|| {
+                    OpenSlideError::Read(format!(
+                        "{}: {}x{} image has too many tiles of {}x{}",
+                        instance.path.display(),
+                        width,
+                        height,
+                        tile_width,
+                        tile_height
+                    ))
+                })?;
+            levels.push(Level {
+                width,
+                height,
+                tile_width,
+                tile_height,
+                tiles: vec![None; tiles as usize],
+            });
+            levels.len() - 1
+        }
+    };
+    let level = &mut levels[level];
+    let columns = level.columns();
+
+    // Sparse tiles give their position, full tiling is in row order. With several focal planes or
+    // optical paths, the first frame of a tile is used.
+    let per_frame = dataset.sequence(PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE);
+    let sparse = !per_frame.is_empty()
+        && dataset.string(DIMENSION_ORGANIZATION_TYPE).as_deref() != Some("TILED_FULL");
+    let frame_offset = dataset.u32(CONCATENATION_FRAME_OFFSET_NUMBER).unwrap_or(0) as u64;
+    for frame in 0..instance.frames.len() {
+        let tile = if sparse {
+            let position = per_frame
+                .get(frame)
+                .and_then(|groups| groups.sequence(PLANE_POSITION_SLIDE_SEQUENCE).first())
+                .and_then(|position| {
+                    // Positions are 1-based.
+                    let column = position.i32(COLUMN_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX)?;
+                    let row = position.i32(ROW_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX)?;
+                    Some(((column.max(1) - 1) as u64, (row.max(1) - 1) as u64))
+                });
+            match position {
+                Some((x, y)) => (y / level.tile_height) * columns + x / level.tile_width,
+                None => continue,
+            }
+        } else {
+            frame_offset + frame as u64
+        };
+        if let Some(slot) = level.tiles.get_mut(tile as usize) {
+            slot.get_or_insert((index, frame));
+        }
+    }
+    Ok(())
+}
+
+impl SlideBackend for DicomSlide {
+    fn level_count(&self) -> Result<u32, OpenSlideError> {
+        Ok(self.levels.len() as u32)
+    }
+
+    fn level_dimensions(&self, level: u32) -> Result<(u64, u64), OpenSlideError> {
+        let level = self.level(level)?;
+        Ok((level.width, level.height))
+    }
+
+    fn level_downsample(&self, level: u32) -> Result<f64, OpenSlideError> {
+        // Like OpenSlide, the average of the downsamples of the width and height.
+        let level = self.level(level)?;
+        let (l0_width, l0_height) = (self.levels[0].width, self.levels[0].height);
+        Ok((l0_width as f64 / level.width as f64 + l0_height as f64 / level.height as f64) / 2.0)
+    }
+
+    fn read_region(
+        &self,
+        (x, y): (u64, u64),
+        level: u32,
+        (width, height): (u64, u64),
+    ) -> Result<RgbaImage, OpenSlideError> {
+        let image = self.level(level)?;
+        let downsample = self.level_downsample(level)?;
+        let location = (
+            (x as f64 / downsample) as u64,
+            (y as f64 / downsample) as u64,
+        );
+        let columns = image.columns();
+        read_tiled_region(
+            (image.width, image.height),
+            (image.tile_width, image.tile_height),
+            location,
+            (width, height),
+            |col, row| {
+                let index = row * columns + col;
+                let (instance, frame) = match image.tiles[index as usize] {
+                    Some(tile) => tile,
+                    None => return Ok(None),
+                };
+                self.decoded_tiles
+                    .get_or_decode((level, index), || {
+                        self.instances[instance].decode_frame(frame)
+                    })
+                    .map(Some)
+            },
+        )
+    }
+
+    fn read_jpeg_tile(
+        &self,
+        level: u32,
+        (x, y): (u64, u64),
+        (width, height): (u64, u64),
+    ) -> Result<Option<Vec<u8>>, OpenSlideError> {
+        let image = self.level(level)?;
+        let aligned = x % image.tile_width == 0 && y % image.tile_height == 0;
+        // Frames on the right and bottom edges are padded, so they are larger than the region.
+        let inside = x + width <= image.width && y + height <= image.height;
+        if (width, height) != (image.tile_width, image.tile_height) || !aligned || !inside {
+            return Ok(None);
+        }
+        let index = (y / image.tile_height) * image.columns() + x / image.tile_width;
+        match image.tiles[index as usize] {
+            Some((instance, frame)) if self.instances[instance].jpeg => {
+                Ok(Some(self.instances[instance].read_jpeg(frame)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn vendor(&self) -> Option<String> {
+        Some("dicom".to_string())
+    }
+
+    fn objective_power(&self) -> Option<u32> {
+        self.properties
+            .get("openslide.objective-power")
+            .and_then(|power| power.parse().ok())
+    }
+
+    fn mpp(&self) -> Option<(f64, f64)> {
+        let mpp = |key: &str| self.properties.get(key).and_then(|mpp| mpp.parse().ok());
+        match (mpp("openslide.mpp-x"), mpp("openslide.mpp-y")) {
+            (Some(x), Some(y)) if x > 0.0 && y > 0.0 => Some((x, y)),
+            _ => None,
+        }
+    }
+
+    fn properties(&self) -> Result<HashMap<String, String>, OpenSlideError> {
+        Ok(self.properties.clone())
+    }
+
+    fn associated_image_names(&self) -> Result<Vec<String>, OpenSlideError> {
+        Ok(self
+            .associated
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect())
+    }
+
+    fn read_associated_image(&self, name: &str) -> Result<RgbaImage, OpenSlideError> {
+        let (_, index, (width, height)) = self
+            .associated
+            .iter()
+            .find(|(associated, _, _)| associated == name)
+            .ok_or_else(|| OpenSlideError::AssociatedImageNotFound(name.to_string()))?;
+        // Associated images are a single frame, which can be padded.
+        let frame = self.instances[*index].decode_frame(0)?;
+        let (width, height) = (
+            (*width as u32).min(frame.width()),
+            (*height as u32).min(frame.height()),
+        );
+        Ok(image::imageops::crop_imm(&frame, 0, 0, width, height).to_image())
+    }
+}
+
+fn read_error(path: &Path, err: io::Error) -> OpenSlideError {
+    OpenSlideError::Read(format!("{}: {}", path.display(), err))
+}
+
+/// OpenSlide style properties of a slide, from the instance of its level 0.
+fn dicom_properties(dataset: &DataSet, levels: &[Level]) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    properties.insert("openslide.vendor".to_string(), "dicom".to_string());
+    properties.insert(
+        "openslide.level-count".to_string(),
+        levels.len().to_string(),
+    );
+    for (level, image) in levels.iter().enumerate() {
+        let downsample = (levels[0].width as f64 / image.width as f64
+            + levels[0].height as f64 / image.height as f64)
+            / 2.0;
+        for (key, value) in [
+            ("width", image.width.to_string()),
+            ("height", image.height.to_string()),
+            ("downsample", downsample.to_string()),
+            ("tile-width", image.tile_width.to_string()),
+            ("tile-height", image.tile_height.to_string()),
+        ] {
+            properties.insert(format!("openslide.level[{}].{}", level, key), value);
+        }
+    }
+
+    for (tag, name) in [
+        (MANUFACTURER, "Manufacturer"),
+        (MANUFACTURER_MODEL_NAME, "ManufacturerModelName"),
+        (DEVICE_SERIAL_NUMBER, "DeviceSerialNumber"),
+        (SOFTWARE_VERSIONS, "SoftwareVersions"),
+        (STUDY_DATE, "StudyDate"),
+        (STUDY_INSTANCE_UID, "StudyInstanceUID"),
+        (SERIES_INSTANCE_UID, "SeriesInstanceUID"),
+        (SERIES_DESCRIPTION, "SeriesDescription"),
+        (CONTAINER_IDENTIFIER, "ContainerIdentifier"),
+    ] {
+        if let Some(value) = dataset.string(tag) {
+            properties.insert(format!("dicom.{}", name), value);
+        }
+    }
+
+    // Pixel Spacing is the (row, column) spacing in millimeters. Without it, the size of the
+    // imaged volume gives the same.
+    let spacing: Vec<f64> = dataset
+        .sequence(SHARED_FUNCTIONAL_GROUPS_SEQUENCE)
+        .first()
+        .and_then(|groups| groups.sequence(PIXEL_MEASURES_SEQUENCE).first())
+        .map(|measures| measures.strings(PIXEL_SPACING))
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|value| value.parse().ok())
+        .collect();
+    let (width, height) = (levels[0].width as f64, levels[0].height as f64);
+    let mpp = match spacing[..] {
+        [row, column] => Some((column * 1000.0, row * 1000.0)),
+        _ => dataset
+            .f32(IMAGED_VOLUME_WIDTH)
+            .zip(dataset.f32(IMAGED_VOLUME_HEIGHT))
+            .map(|(x, y)| (x as f64 * 1000.0 / width, y as f64 * 1000.0 / height)),
+    };
+    if let Some((mpp_x, mpp_y)) = mpp.filter(|(x, y)| *x > 0.0 && *y > 0.0) {
+        if !spacing.is_empty() {
+            properties.insert("dicom.PixelSpacing".to_string(), spacing_property(&spacing));
+        }
+        properties.insert("openslide.mpp-x".to_string(), mpp_x.to_string());
+        properties.insert("openslide.mpp-y".to_string(), mpp_y.to_string());
+    }
+    let power = dataset
+        .sequence(OPTICAL_PATH_SEQUENCE)
+        .first()
+        .and_then(|path| path.number(OBJECTIVE_LENS_POWER));
+    if let Some(power) = power.filter(|power| power.fract() == 0.0 && *power > 0.0) {
+        properties.insert("openslide.objective-power".to_string(), power.to_string());
+    }
+    properties
+}
+
+fn spacing_property(spacing: &[f64]) -> String {
+    spacing
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+#[test]
+fn test_dicom_slide() {
+    use self::dataset::{encode_element, encode_header};
+    use image::Pixel;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        if bytes.len() % 2 == 1 {
+            bytes.push(b' ');
+        }
+        bytes
+    }
+    // A TILED_FULL instance of 128 by 96 pixel frames, uncompressed.
+    let instance = |image_type: &str, image: &RgbaImage, rgb: bool| {
+        let (width, height) = (image.width() as u64, image.height() as u64);
+        let (columns, rows) = (width.div_ceil(128), height.div_ceil(96));
+        let mut pixels = Vec::new();
+        for frame in 0..columns * rows {
+            for y in 0..96 {
+                for x in 0..128 {
+                    let (x, y) = ((frame % columns) * 128 + x, (frame / columns) * 96 + y);
+                    let pixel = match x < width && y < height {
+                        true => image.get_pixel(x as u32, y as u32).to_rgb().0,
+                        false => [0; 3],
+                    };
+                    pixels.extend_from_slice(if rgb { &pixel } else { &pixel[..1] });
+                }
+            }
+        }
+        let mut uid = WSI_SOP_CLASS.as_bytes().to_vec();
+        uid.push(0);
+        let mut file = encode_header(EXPLICIT_VR_LITTLE_ENDIAN);
+        file.extend(encode_element(MEDIA_STORAGE_SOP_CLASS_UID, "UI", &uid));
+        let mut spacing = encode_element(PIXEL_SPACING, "DS", b"0.0005\\0.00025");
+        spacing.splice(0..0, [0xfe, 0xff, 0x00, 0xe0]);
+        spacing.splice(4..4, ((spacing.len() - 4) as u32).to_le_bytes());
+        let mut measures = encode_element(PIXEL_MEASURES_SEQUENCE, "SQ", &spacing);
+        measures.splice(0..0, [0xfe, 0xff, 0x00, 0xe0]);
+        measures.splice(4..4, ((measures.len() - 4) as u32).to_le_bytes());
+        let photometric = if rgb { "RGB" } else { "MONOCHROME2" };
+        for (tag, vr, value) in [
+            (
+                IMAGE_TYPE,
+                "CS",
+                string(&format!("ORIGINAL\\PRIMARY\\{}", image_type)),
+            ),
+            (MANUFACTURER, "LO", string("Test")),
+            (SERIES_INSTANCE_UID, "UI", string("1.2.3")),
+            (DIMENSION_ORGANIZATION_TYPE, "CS", string("TILED_FULL")),
+            (
+                SAMPLES_PER_PIXEL,
+                "US",
+                (if rgb { 3u16 } else { 1 }).to_le_bytes().to_vec(),
+            ),
+            (PHOTOMETRIC_INTERPRETATION, "CS", string(photometric)),
+            (
+                NUMBER_OF_FRAMES,
+                "IS",
+                string(&(columns * rows).to_string()),
+            ),
+            (ROWS, "US", 96u16.to_le_bytes().to_vec()),
+            (COLUMNS, "US", 128u16.to_le_bytes().to_vec()),
+            (BITS_ALLOCATED, "US", 8u16.to_le_bytes().to_vec()),
+            (
+                TOTAL_PIXEL_MATRIX_COLUMNS,
+                "UL",
+                (width as u32).to_le_bytes().to_vec(),
+            ),
+            (
+                TOTAL_PIXEL_MATRIX_ROWS,
+                "UL",
+                (height as u32).to_le_bytes().to_vec(),
+            ),
+            (SHARED_FUNCTIONAL_GROUPS_SEQUENCE, "SQ", measures),
+            (dataset::PIXEL_DATA, "OB", pixels),
+        ] {
+            file.extend(encode_element(tag, vr, &value));
+        }
+        file
+    };
+
+    let level_0 = RgbaImage::from_fn(300, 200, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+    let level_1 = RgbaImage::from_fn(150, 100, |x, y| Rgba([x as u8, y as u8, 100, 255]));
+    let label = RgbaImage::from_pixel(40, 20, Rgba([9, 9, 9, 255]));
+    // An overview with a photometric interpretation that can not be read is skipped.
+    let mut overview = instance(
+        "OVERVIEW",
+        &RgbaImage::from_pixel(60, 30, Rgba([5, 5, 5, 255])),
+        true,
+    );
+    let photometric = overview
+        .windows(4)
+        .position(|bytes| bytes == b"RGB ")
+        .unwrap();
+    overview[photometric..photometric + 4].copy_from_slice(b"HSV ");
+    let dir = std::env::temp_dir().join(format!("slidestream-dicom-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, file) in [
+        ("0.dcm", instance("VOLUME", &level_0, true)),
+        ("1.dcm", instance("VOLUME", &level_1, true)),
+        ("label.dcm", instance("LABEL", &label, false)),
+        ("overview.dcm", overview),
+    ] {
+        std::fs::write(dir.join(name), file).unwrap();
+    }
+    std::fs::write(dir.join("README"), "not a DICOM file").unwrap();
+    let supported = DicomSlide::is_supported(&dir);
+    let slide = DicomSlide::open(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(supported);
+    let slide = slide.unwrap();
+    assert_eq!(slide.level_count().unwrap(), 2);
+    assert_eq!(slide.level_dimensions(1).unwrap(), (150, 100));
+    assert_eq!(slide.level_downsample(1).unwrap(), 2.0);
+    assert_eq!(slide.mpp(), Some((0.25, 0.5)));
+    let properties = slide.properties().unwrap();
+    assert_eq!(properties["dicom.Manufacturer"], "Test");
+    assert_eq!(properties["openslide.level[1].tile-width"], "128");
+
+    // Across four frames, and over the edge of the image.
+    let region = slide.read_region((120, 90), 0, (200, 20)).unwrap();
+    assert_eq!(region.get_pixel(0, 0).0, [120, 90, 0, 255]);
+    assert_eq!(region.get_pixel(10, 10).0, [130, 100, 0, 255]);
+    assert_eq!(region.get_pixel(179, 19).0, [43, 109, 0, 255]);
+    assert_eq!(region.get_pixel(180, 0).0, [0, 0, 0, 0]);
+    let region = slide.read_region((260, 0), 1, (20, 10)).unwrap();
+    assert_eq!(region.get_pixel(0, 5).0, [130, 5, 100, 255]);
+    // Uncompressed frames can not be passed through.
+    assert_eq!(slide.read_jpeg_tile(0, (0, 0), (128, 96)).unwrap(), None);
+
+    assert_eq!(slide.associated_image_names().unwrap(), vec!["label"]);
+    let label = slide.read_associated_image("label").unwrap();
+    assert_eq!(label.dimensions(), (40, 20));
+    assert_eq!(label.get_pixel(0, 0).0, [9, 9, 9, 255]);
+
+    // Corrupt values of a level instance of 2 x 2 frames.
+    let corrupt = |elements: &[(Tag, &str, Vec<u8>, Vec<u8>)]| {
+        let mut file = instance("VOLUME", &level_1, true);
+        for (tag, vr, from, to) in elements {
+            let from = encode_element(*tag, vr, from);
+            let position = file
+                .windows(from.len())
+                .position(|bytes| bytes == from)
+                .unwrap();
+            file.splice(
+                position..position + from.len(),
+                encode_element(*tag, vr, to),
+            );
+        }
+        let dir =
+            std::env::temp_dir().join(format!("slidestream-dicom-corrupt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("0.dcm"), file).unwrap();
+        let slide = DicomSlide::open(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        slide
+    };
+    // Only the frames that are present are read.
+    let slide = corrupt(&[(NUMBER_OF_FRAMES, "IS", string("4"), string("1000000000"))]).unwrap();
+    assert_eq!(slide.instances[0].frames.len(), 4);
+    assert!(matches!(
+        corrupt(&[(COLUMNS, "US", 128u16.to_le_bytes().to_vec(), vec![0, 0])]),
+        Err(OpenSlideError::Read(_))
+    ));
+    let huge = u32::MAX.to_le_bytes().to_vec();
+    assert!(matches!(
+        corrupt(&[
+            (
+                TOTAL_PIXEL_MATRIX_COLUMNS,
+                "UL",
+                150u32.to_le_bytes().to_vec(),
+                huge.clone()
+            ),
+            (
+                TOTAL_PIXEL_MATRIX_ROWS,
+                "UL",
+                100u32.to_le_bytes().to_vec(),
+                huge
+            ),
+        ]),
+        Err(OpenSlideError::Read(_))
+    ));
+}
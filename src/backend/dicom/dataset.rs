@@ -0,0 +1,515 @@
+//! A reader for the parts of DICOM files that whole slide images need.
+//!
+//! Only the little endian transfer syntaxes, with implicit or explicit VRs, are read. Large values
+//! are skipped, and of the pixel data only the location of every frame in the file is recorded,
+//! so even files of several gigabytes are read quickly.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// A data element tag: (group, element).
+pub type Tag = (u16, u16);
+
+pub const TRANSFER_SYNTAX_UID: Tag = (0x0002, 0x0010);
+pub const MEDIA_STORAGE_SOP_CLASS_UID: Tag = (0x0002, 0x0002);
+pub const PIXEL_DATA: Tag = (0x7fe0, 0x0010);
+const ITEM: Tag = (0xfffe, 0xe000);
+const ITEM_DELIMITATION: Tag = (0xfffe, 0xe00d);
+const SEQUENCE_DELIMITATION: Tag = (0xfffe, 0xe0dd);
+
+/// Sequences that are read, and so have to be recognized in files with implicit VRs.
+const SEQUENCES: [Tag; 5] = [
+    // Shared and Per-Frame Functional Groups
+    (0x5200, 0x9229),
+    (0x5200, 0x9230),
+    // Pixel Measures
+    (0x0028, 0x9110),
+    // Plane Position (Slide)
+    (0x0048, 0x021a),
+    // Optical Path
+    (0x0048, 0x0105),
+];
+
+/// Values longer than this are skipped.
+const MAX_VALUE_LENGTH: u32 = 1 << 16;
+
+const UNDEFINED_LENGTH: u32 = 0xffff_ffff;
+
+/// A value of a data element.
+pub enum Value {
+    Bytes(Vec<u8>),
+    Sequence(Vec<DataSet>),
+}
+
+/// The data elements of a file or of a sequence item, by tag.
+#[derive(Default)]
+pub struct DataSet {
+    elements: HashMap<Tag, Value>,
+}
+
+impl DataSet {
+    pub fn bytes(&self, tag: Tag) -> Option<&[u8]> {
+        match self.elements.get(&tag)? {
+            Value::Bytes(bytes) => Some(bytes),
+            Value::Sequence(_) => None,
+        }
+    }
+
+    /// The items of a sequence, empty if there is no such sequence.
+    pub fn sequence(&self, tag: Tag) -> &[DataSet] {
+        match self.elements.get(&tag) {
+            Some(Value::Sequence(items)) => items,
+            _ => &[],
+        }
+    }
+
+    /// All values of a string element, without padding.
+    pub fn strings(&self, tag: Tag) -> Vec<String> {
+        match self.bytes(tag) {
+            Some(bytes) => String::from_utf8_lossy(bytes)
+                .split('\\')
+                .map(|value| {
+                    value
+                        .trim_matches(|c: char| c == ' ' || c == '\0')
+                        .to_string()
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The (first) value of a string element.
+    pub fn string(&self, tag: Tag) -> Option<String> {
+        self.strings(tag)
+            .into_iter()
+            .next()
+            .filter(|value| !value.is_empty())
+    }
+
+    /// The (first) value of a numeric string (IS or DS) element.
+    pub fn number(&self, tag: Tag) -> Option<f64> {
+        self.string(tag)?.parse().ok()
+    }
+
+    /// The value of an unsigned short (US) element.
+    pub fn u16(&self, tag: Tag) -> Option<u16> {
+        let bytes = self.bytes(tag)?;
+        Some(u16::from_le_bytes(bytes.get(..2)?.try_into().ok()?))
+    }
+
+    /// The value of an unsigned or signed long (UL or SL) element.
+    pub fn u32(&self, tag: Tag) -> Option<u32> {
+        let bytes = self.bytes(tag)?;
+        Some(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+    }
+
+    pub fn i32(&self, tag: Tag) -> Option<i32> {
+        Some(self.u32(tag)? as i32)
+    }
+
+    /// The value of a single precision float (FL) element.
+    pub fn f32(&self, tag: Tag) -> Option<f32> {
+        let bytes = self.bytes(tag)?;
+        Some(f32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+    }
+}
+
+/// Where the pixel data is in the file.
+pub enum PixelData {
+    /// Uncompressed frames, one after another.
+    Native { offset: u64, length: u64 },
+    /// Compressed frames, each in one or more fragments (offset, length).
+    Encapsulated { frames: Vec<Vec<(u64, u64)>> },
+}
+
+/// A DICOM file, read up to its pixel data.
+pub struct DicomFile {
+    /// The file meta information (group 0002).
+    pub meta: DataSet,
+    pub dataset: DataSet,
+    pub pixel_data: Option<PixelData>,
+}
+
+impl DicomFile {
+    pub fn transfer_syntax(&self) -> Option<String> {
+        self.meta.string(TRANSFER_SYNTAX_UID)
+    }
+}
+
+/// Read the meta information of a DICOM file, or `None` if it is not a DICOM file.
+pub fn read_meta<R: Read + Seek>(reader: R) -> io::Result<Option<DataSet>> {
+    let mut parser = Parser {
+        reader,
+        position: 0,
+        explicit: true,
+    };
+    if !parser.read_preamble()? {
+        return Ok(None);
+    }
+    parser.read_meta().map(Some)
+}
+
+/// Read a DICOM file, or `None` if it is not a DICOM file.
+pub fn read<R: Read + Seek>(reader: R) -> io::Result<Option<DicomFile>> {
+    let mut parser = Parser {
+        reader,
+        position: 0,
+        explicit: true,
+    };
+    if !parser.read_preamble()? {
+        return Ok(None);
+    }
+    let meta = parser.read_meta()?;
+    let transfer_syntax = meta.string(TRANSFER_SYNTAX_UID).unwrap_or_default();
+    // Implicit VR Little Endian; the others read are explicit.
+    parser.explicit = transfer_syntax != "1.2.840.10008.1.2";
+    let mut pixel_data = None;
+    let dataset = parser.read_dataset(None, &mut pixel_data)?;
+    let pixel_data = pixel_data.map(|pixel_data| match pixel_data {
+        RawPixelData::Native { offset, length } => PixelData::Native { offset, length },
+        RawPixelData::Encapsulated {
+            offset_table,
+            fragments,
+        } => {
+            let frame_count = dataset
+                .number((0x0028, 0x0008))
+                .map_or(1, |count| count as usize);
+            PixelData::Encapsulated {
+                frames: split_frames(&offset_table, &fragments, frame_count),
+            }
+        }
+    });
+    Ok(Some(DicomFile {
+        meta,
+        dataset,
+        pixel_data,
+    }))
+}
+
+/// Group the fragments (item offset, data offset, length) of encapsulated pixel data into frames.
+fn split_frames(
+    offset_table: &[u32],
+    fragments: &[(u64, u64, u64)],
+    frame_count: usize,
+) -> Vec<Vec<(u64, u64)>> {
+    let data = |fragment: &(u64, u64, u64)| (fragment.1, fragment.2);
+    if fragments.is_empty() {
+        return Vec::new();
+    }
+    if !offset_table.is_empty() {
+        // Offsets are relative to the first fragment item.
+        let first = fragments[0].0;
+        let mut frames: Vec<Vec<(u64, u64)>> = vec![Vec::new(); offset_table.len()];
+        for fragment in fragments {
+            let offset = fragment.0 - first;
+            let frame = offset_table.partition_point(|start| *start as u64 <= offset);
+            if frame > 0 {
+                frames[frame - 1].push(data(fragment));
+            }
+        }
+        return frames;
+    }
+    if frame_count <= 1 {
+        vec![fragments.iter().map(data).collect()]
+    } else {
+        // Without offset table, frames of a multi-frame image have one fragment each.
+        fragments
+            .iter()
+            .map(|fragment| vec![data(fragment)])
+            .collect()
+    }
+}
+
+enum RawPixelData {
+    Native {
+        offset: u64,
+        length: u64,
+    },
+    Encapsulated {
+        offset_table: Vec<u32>,
+        fragments: Vec<(u64, u64, u64)>,
+    },
+}
+
+struct Parser<R> {
+    reader: R,
+    position: u64,
+    explicit: bool,
+}
+
+impl<R: Read + Seek> Parser<R> {
+    fn read_exact(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        self.reader.read_exact(buffer)?;
+        self.position += buffer.len() as u64;
+        Ok(())
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        self.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn skip(&mut self, length: u64) -> io::Result<()> {
+        self.position += length;
+        self.reader.seek(SeekFrom::Start(self.position))?;
+        Ok(())
+    }
+
+    /// Read the 128 byte preamble and the `DICM` prefix, if there are.
+    fn read_preamble(&mut self) -> io::Result<bool> {
+        let mut preamble = [0; 132];
+        match self.read_exact(&mut preamble) {
+            Ok(()) => Ok(&preamble[128..] == b"DICM"),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Read the elements of group 0002, which are always explicit VR little endian.
+    fn read_meta(&mut self) -> io::Result<DataSet> {
+        let mut meta = DataSet::default();
+        loop {
+            let start = self.position;
+            let group = self.read_u16()?;
+            if group != 0x0002 {
+                self.position = start;
+                self.reader.seek(SeekFrom::Start(start))?;
+                return Ok(meta);
+            }
+            let element = self.read_u16()?;
+            let (_, length) = self.read_vr_and_length((group, element))?;
+            if length <= MAX_VALUE_LENGTH {
+                let mut value = vec![0; length as usize];
+                self.read_exact(&mut value)?;
+                meta.elements.insert((group, element), Value::Bytes(value));
+            } else {
+                self.skip(length as u64)?;
+            }
+        }
+    }
+
+    /// Read the VR (if explicit) and value length of an element.
+    fn read_vr_and_length(&mut self, tag: Tag) -> io::Result<(Option<[u8; 2]>, u32)> {
+        // Items and delimiters never have a VR.
+        if !self.explicit || tag.0 == 0xfffe {
+            return Ok((None, self.read_u32()?));
+        }
+        let mut vr = [0; 2];
+        self.read_exact(&mut vr)?;
+        let length = match &vr {
+            b"OB" | b"OD" | b"OF" | b"OL" | b"OV" | b"OW" | b"SQ" | b"SV" | b"UC" | b"UN"
+            | b"UR" | b"UT" | b"UV" => {
+                self.skip(2)?;
+                self.read_u32()?
+            }
+            _ => self.read_u16()? as u32,
+        };
+        Ok((Some(vr), length))
+    }
+
+    /// Read the elements up to `end`, or up to an item delimiter if the length is undefined.
+    fn read_dataset(
+        &mut self,
+        end: Option<u64>,
+        pixel_data: &mut Option<RawPixelData>,
+    ) -> io::Result<DataSet> {
+        let mut dataset = DataSet::default();
+        while end.is_none_or(|end| self.position < end) {
+            let tag = match self.read_u16() {
+                Ok(group) => (group, self.read_u16()?),
+                // The dataset of a file ends with the file.
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && end.is_none() => break,
+                Err(err) => return Err(err),
+            };
+            let (vr, length) = self.read_vr_and_length(tag)?;
+            if tag == ITEM_DELIMITATION {
+                break;
+            }
+            if tag == PIXEL_DATA {
+                *pixel_data = Some(self.read_pixel_data(length)?);
+                continue;
+            }
+            let is_sequence = match vr {
+                Some(vr) => &vr == b"SQ" || (&vr == b"UN" && length == UNDEFINED_LENGTH),
+                None => SEQUENCES.contains(&tag) || length == UNDEFINED_LENGTH,
+            };
+            if is_sequence {
+                // Undefined length UN elements are sequences with implicit VRs.
+                let explicit = self.explicit;
+                if vr.as_ref() == Some(b"UN") {
+                    self.explicit = false;
+                }
+                let items = self.read_sequence(length);
+                self.explicit = explicit;
+                dataset.elements.insert(tag, Value::Sequence(items?));
+            } else if length <= MAX_VALUE_LENGTH {
+                let mut value = vec![0; length as usize];
+                self.read_exact(&mut value)?;
+                dataset.elements.insert(tag, Value::Bytes(value));
+            } else {
+                self.skip(length as u64)?;
+            }
+        }
+        Ok(dataset)
+    }
+
+    fn read_sequence(&mut self, length: u32) -> io::Result<Vec<DataSet>> {
+        let end = (length != UNDEFINED_LENGTH).then(|| self.position + length as u64);
+        let mut items = Vec::new();
+        while end.is_none_or(|end| self.position < end) {
+            let tag = (self.read_u16()?, self.read_u16()?);
+            let item_length = self.read_u32()?;
+            match tag {
+                ITEM => {
+                    let item_end = (item_length != UNDEFINED_LENGTH)
+                        .then(|| self.position + item_length as u64);
+                    // Pixel data does not occur in items.
+                    items.push(self.read_dataset(item_end, &mut None)?);
+                }
+                SEQUENCE_DELIMITATION => break,
+                _ => return Err(invalid_data(format!("unexpected {:?} in sequence", tag))),
+            }
+        }
+        Ok(items)
+    }
+
+    fn read_pixel_data(&mut self, length: u32) -> io::Result<RawPixelData> {
+        if length != UNDEFINED_LENGTH {
+            let offset = self.position;
+            self.skip(length as u64)?;
+            return Ok(RawPixelData::Native {
+                offset,
+                length: length as u64,
+            });
+        }
+        // Encapsulated: an item with the basic offset table, then an item per fragment.
+        let mut offset_table = Vec::new();
+        let mut fragments = Vec::new();
+        let mut first = true;
+        loop {
+            let item = self.position;
+            let tag = (self.read_u16()?, self.read_u16()?);
+            let length = self.read_u32()?;
+            match tag {
+                ITEM if first => {
+                    for _ in 0..length / 4 {
+                        offset_table.push(self.read_u32()?);
+                    }
+                    first = false;
+                }
+                ITEM => {
+                    fragments.push((item, self.position, length as u64));
+                    self.skip(length as u64)?;
+                }
+                SEQUENCE_DELIMITATION => break,
+                _ => return Err(invalid_data(format!("unexpected {:?} in pixel data", tag))),
+            }
+        }
+        Ok(RawPixelData::Encapsulated {
+            offset_table,
+            fragments,
+        })
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Encode an explicit VR little endian element, for writing test files.
+#[cfg(test)]
+pub fn encode_element(tag: Tag, vr: &str, value: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&tag.0.to_le_bytes());
+    bytes.extend_from_slice(&tag.1.to_le_bytes());
+    bytes.extend_from_slice(vr.as_bytes());
+    if matches!(vr, "OB" | "OW" | "SQ" | "UN" | "UT") {
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    } else {
+        bytes.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    }
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// The preamble and file meta information of a test file with the given transfer syntax.
+#[cfg(test)]
+pub fn encode_header(transfer_syntax: &str) -> Vec<u8> {
+    let mut bytes = vec![0; 128];
+    bytes.extend_from_slice(b"DICM");
+    let mut uid = transfer_syntax.as_bytes().to_vec();
+    if uid.len() % 2 == 1 {
+        uid.push(0);
+    }
+    bytes.extend(encode_element(TRANSFER_SYNTAX_UID, "UI", &uid));
+    bytes
+}
+
+#[test]
+fn test_read_dataset() {
+    use std::io::Cursor;
+
+    let mut item = encode_element((0x0028, 0x0030), "DS", b"0.00025\\0.0005");
+    item.splice(0..0, [0xfe, 0xff, 0x00, 0xe0]);
+    item.splice(4..4, ((item.len() - 4) as u32).to_le_bytes());
+    let mut file = encode_header("1.2.840.10008.1.2.4.50");
+    file.extend(encode_element((0x0028, 0x0008), "IS", b"2 "));
+    file.extend(encode_element((0x0028, 0x9110), "SQ", &item));
+    file.extend(encode_element(
+        (0x0048, 0x0006),
+        "UL",
+        &1000u32.to_le_bytes(),
+    ));
+    // Encapsulated pixel data: an empty offset table and two fragments.
+    file.extend_from_slice(&[0xe0, 0x7f, 0x10, 0x00, b'O', b'B', 0, 0]);
+    file.extend_from_slice(&UNDEFINED_LENGTH.to_le_bytes());
+    file.extend_from_slice(&[0xfe, 0xff, 0x00, 0xe0, 0, 0, 0, 0]);
+    file.extend_from_slice(&[0xfe, 0xff, 0x00, 0xe0, 4, 0, 0, 0, 1, 2, 3, 4]);
+    file.extend_from_slice(&[0xfe, 0xff, 0x00, 0xe0, 2, 0, 0, 0, 5, 6]);
+    file.extend_from_slice(&[0xfe, 0xff, 0xdd, 0xe0, 0, 0, 0, 0]);
+    // The data of the first fragment, before the second fragment and the delimiter.
+    let first = (file.len() - 4 - 10 - 8) as u64;
+
+    let dicom = read(Cursor::new(&file)).unwrap().unwrap();
+    assert_eq!(
+        dicom.transfer_syntax().as_deref(),
+        Some("1.2.840.10008.1.2.4.50")
+    );
+    assert_eq!(dicom.dataset.number((0x0028, 0x0008)), Some(2.0));
+    assert_eq!(dicom.dataset.u32((0x0048, 0x0006)), Some(1000));
+    let measures = dicom.dataset.sequence((0x0028, 0x9110));
+    assert_eq!(measures.len(), 1);
+    assert_eq!(
+        measures[0].strings((0x0028, 0x0030)),
+        vec!["0.00025", "0.0005"]
+    );
+    match dicom.pixel_data {
+        Some(PixelData::Encapsulated { frames }) => {
+            assert_eq!(frames, vec![vec![(first, 4)], vec![(first + 12, 2)]]);
+        }
+        _ => panic!("expected encapsulated pixel data"),
+    }
+    assert!(read(Cursor::new(vec![0; 10])).unwrap().is_none());
+}
+
+#[test]
+fn test_split_frames() {
+    let fragments = [(0, 8, 10), (18, 26, 10), (36, 44, 10)];
+    assert_eq!(
+        split_frames(&[0, 36], &fragments, 2),
+        vec![vec![(8, 10), (26, 10)], vec![(44, 10)]]
+    );
+    assert_eq!(
+        split_frames(&[], &fragments, 1),
+        vec![vec![(8, 10), (26, 10), (44, 10)]]
+    );
+    assert_eq!(split_frames(&[], &fragments, 3).len(), 3);
+}
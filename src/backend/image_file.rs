@@ -0,0 +1,203 @@
+//! Ordinary images (PNG, JPEG, plain TIFF, ...) as slides, e.g. microscopy stitches or screenshots.
+//!
+//! The image is decoded into memory when it is opened, and downsampled by powers of two into a
+//! pyramid of levels, so that low resolution tiles are cheap. The levels are downsampled with the
+//! filter the tiles are resampled with, so that e.g. label masks keep their exact values. Images
+//! larger than `MAX_PIXELS` are refused rather than decoded, as they would take gigabytes of
+//! memory.
+
+use super::SlideBackend;
+use crate::generator::openslide::OpenSlideError;
+use crate::generator::resample::{self, ResampleFilter};
+use image::{imageops, ImageFormat, RgbaImage};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Levels are downsampled until they fit in this many pixels.
+const MIN_LEVEL_SIZE: u32 = 512;
+
+/// Largest number of pixels of an image that is opened, about 530 MB in memory with its levels.
+const MAX_PIXELS: u64 = 100_000_000;
+
+/// An image decoded into memory, with its downsampled levels.
+pub struct ImageFile {
+    levels: Vec<RgbaImage>,
+    format: Option<ImageFormat>,
+}
+
+impl ImageFile {
+    /// Whether the `image` crate can decode the file, judged by its extension.
+    pub fn is_supported(path: &Path) -> bool {
+        ImageFormat::from_path(path).is_ok_and(|format| format.can_read())
+    }
+
+    /// Decode the image at `path`, downsampling its levels with `filter`.
+    pub fn open(path: &Path, filter: ResampleFilter) -> Result<ImageFile, OpenSlideError> {
+        if !path.exists() {
+            return Err(OpenSlideError::NotFound(path.to_path_buf()));
+        }
+        let format = ImageFormat::from_path(path)
+            .map_err(|_| OpenSlideError::Unsupported(path.to_path_buf()))?;
+        let read_error =
+            |err: image::ImageError| OpenSlideError::Read(format!("{}: {}", path.display(), err));
+        // Only the header is read to check the size.
+        let (width, height) = image::image_dimensions(path).map_err(read_error)?;
+        if width as u64 * height as u64 > MAX_PIXELS {
+            return Err(OpenSlideError::Read(format!(
+                "{}: image of {}x{} pixels is larger than the limit of {} pixels",
+                path.display(),
+                width,
+                height,
+                MAX_PIXELS
+            )));
+        }
+        let image = image::open(path).map_err(read_error)?;
+        let mut file = ImageFile::from_image(image.into_rgba8(), filter);
+        file.format = Some(format);
+        Ok(file)
+    }
+
+    /// Serve an image that is already in memory, downsampling its levels with `filter`.
+    pub fn from_image(image: RgbaImage, filter: ResampleFilter) -> ImageFile {
+        let mut levels = vec![image];
+        loop {
+            let last = levels.last().expect("at least level 0");
+            let (width, height) = last.dimensions();
+            if width <= MIN_LEVEL_SIZE && height <= MIN_LEVEL_SIZE {
+                break;
+            }
+            let next = resample::resize(last, width.div_ceil(2), height.div_ceil(2), filter);
+            levels.push(next);
+        }
+        ImageFile {
+            levels,
+            format: None,
+        }
+    }
+
+    fn level(&self, level: u32) -> Result<&RgbaImage, OpenSlideError> {
+        self.levels
+            .get(level as usize)
+            .ok_or(OpenSlideError::LevelOutOfRange {
+                level,
+                level_count: self.levels.len() as u32,
+            })
+    }
+}
+
+impl SlideBackend for ImageFile {
+    fn level_count(&self) -> Result<u32, OpenSlideError> {
+        Ok(self.levels.len() as u32)
+    }
+
+    fn level_dimensions(&self, level: u32) -> Result<(u64, u64), OpenSlideError> {
+        let (width, height) = self.level(level)?.dimensions();
+        Ok((width as u64, height as u64))
+    }
+
+    fn level_downsample(&self, level: u32) -> Result<f64, OpenSlideError> {
+        let (width, height) = self.level(level)?.dimensions();
+        let (l0_width, l0_height) = self.levels[0].dimensions();
+        Ok((l0_width as f64 / width as f64 + l0_height as f64 / height as f64) / 2.0)
+    }
+
+    fn read_region(
+        &self,
+        (x, y): (u64, u64),
+        level: u32,
+        (width, height): (u64, u64),
+    ) -> Result<RgbaImage, OpenSlideError> {
+        let image = self.level(level)?;
+        let downsample = self.level_downsample(level)?;
+        let mut region = RgbaImage::new(width as u32, height as u32);
+        let (l_x, l_y) = (
+            (x as f64 / downsample) as u64,
+            (y as f64 / downsample) as u64,
+        );
+        let (l_width, l_height) = (image.width() as u64, image.height() as u64);
+        if l_x < l_width && l_y < l_height {
+            let visible = imageops::crop_imm(
+                image,
+                l_x as u32,
+                l_y as u32,
+                width.min(l_width - l_x) as u32,
+                height.min(l_height - l_y) as u32,
+            );
+            imageops::replace(&mut region, &visible.to_image(), 0, 0);
+        }
+        Ok(region)
+    }
+
+    fn vendor(&self) -> Option<String> {
+        Some("image".to_string())
+    }
+
+    fn properties(&self) -> Result<HashMap<String, String>, OpenSlideError> {
+        let mut properties = HashMap::new();
+        properties.insert("openslide.vendor".to_string(), "image".to_string());
+        if let Some(format) = self.format {
+            properties.insert("image.format".to_string(), format!("{:?}", format));
+        }
+        Ok(properties)
+    }
+}
+
+#[test]
+fn test_image_file_levels() {
+    let image = ImageFile::from_image(RgbaImage::new(2000, 600), ResampleFilter::Area);
+    assert_eq!(image.level_count().unwrap(), 3);
+    assert_eq!(image.level_dimensions(1).unwrap(), (1000, 300));
+    assert_eq!(image.level_dimensions(2).unwrap(), (500, 150));
+    assert_eq!(image.level_downsample(2).unwrap(), 4.0);
+    assert_eq!(image.best_level_for_downsample(1.0).unwrap(), 0);
+    assert_eq!(image.best_level_for_downsample(3.0).unwrap(), 1);
+    assert_eq!(image.best_level_for_downsample(100.0).unwrap(), 2);
+    assert!(matches!(
+        image.level_dimensions(3),
+        Err(OpenSlideError::LevelOutOfRange { level: 3, .. })
+    ));
+
+    // A label mask keeps its values in all levels when they are sampled with the nearest pixel.
+    let labels = RgbaImage::from_fn(2000, 600, |x, y| {
+        image::Rgba([if (x / 3 + y) % 2 == 0 { 3 } else { 7 }, 0, 0, 255])
+    });
+    let image = ImageFile::from_image(labels, ResampleFilter::Nearest);
+    let level = image.read_region((0, 0), 2, (500, 150)).unwrap();
+    assert!(level.pixels().all(|pixel| [3, 7].contains(&pixel[0])));
+}
+
+#[test]
+fn test_image_file_read_region() {
+    let image = ImageFile::from_image(
+        RgbaImage::from_fn(100, 50, |x, y| image::Rgba([x as u8, y as u8, 0, 255])),
+        ResampleFilter::Area,
+    );
+    let region = image.read_region((90, 40), 0, (20, 20)).unwrap();
+    assert_eq!(region.dimensions(), (20, 20));
+    assert_eq!(region.get_pixel(0, 0).0, [90, 40, 0, 255]);
+    assert_eq!(region.get_pixel(9, 9).0, [99, 49, 0, 255]);
+    // Outside the image.
+    assert_eq!(region.get_pixel(10, 0).0, [0, 0, 0, 0]);
+    assert_eq!(region.get_pixel(0, 10).0, [0, 0, 0, 0]);
+}
+
+#[test]
+fn test_image_file_too_large() {
+    // Only the header of a 20000x20000 BMP, which is all that is read.
+    let mut bmp = b"BM".to_vec();
+    for value in [0u32, 0, 54, 40, 20_000, 20_000] {
+        bmp.extend(value.to_le_bytes());
+    }
+    bmp.extend(1u16.to_le_bytes());
+    bmp.extend(24u16.to_le_bytes());
+    bmp.extend([0; 24]);
+    let path = std::env::temp_dir().join(format!("slidestream-large-{}.bmp", std::process::id()));
+    std::fs::write(&path, bmp).unwrap();
+    let opened = ImageFile::open(&path, ResampleFilter::Area);
+    std::fs::remove_file(&path).unwrap();
+
+    match opened {
+        Err(OpenSlideError::Read(msg)) => assert!(msg.contains("20000x20000"), "{}", msg),
+        _ => panic!("image above the pixel limit was opened"),
+    }
+}
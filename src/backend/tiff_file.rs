@@ -0,0 +1,963 @@
+//! Tiled TIFF and BigTIFF files, including Aperio SVS, read without OpenSlide.
+//!
+//! Every tiled image in the file is a level of the pyramid. Tiles are read and decompressed here,
+//! and kept in a small cache of decoded tiles. JPEG tiles can also be returned as stored, with the
+//! shared `JPEGTables` spliced in, so that Deep Zoom tiles matching them are served without
+//! decoding and re-encoding.
+//!
+//! Supported are 8 bit RGB, YCbCr (JPEG) and grayscale images without compression, or with LZW,
+//! JPEG or Deflate compression. Other files are left to OpenSlide.
+
+use super::{complete_jpeg, read_exact_at, read_tiled_region, DecodedTiles, SlideBackend};
+use crate::generator::openslide::OpenSlideError;
+use flate2::read::ZlibDecoder;
+use image::{ImageFormat, Rgba, RgbaImage};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// TIFF tags read.
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const IMAGE_DESCRIPTION: u16 = 270;
+const MAKE: u16 = 271;
+const MODEL: u16 = 272;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const X_RESOLUTION: u16 = 282;
+const Y_RESOLUTION: u16 = 283;
+const PLANAR_CONFIGURATION: u16 = 284;
+const RESOLUTION_UNIT: u16 = 296;
+const SOFTWARE: u16 = 305;
+const DATE_TIME: u16 = 306;
+const PREDICTOR: u16 = 317;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+const JPEG_TABLES: u16 = 347;
+
+// Compression schemes.
+const COMPRESSION_NONE: u16 = 1;
+const COMPRESSION_LZW: u16 = 5;
+const COMPRESSION_JPEG: u16 = 7;
+const COMPRESSION_DEFLATE: u16 = 8;
+const COMPRESSION_ADOBE_DEFLATE: u16 = 32946;
+
+// Photometric interpretations.
+const WHITE_IS_ZERO: u16 = 0;
+const BLACK_IS_ZERO: u16 = 1;
+const RGB: u16 = 2;
+const YCBCR: u16 = 6;
+
+/// Largest tag value read, in bytes, to guard against corrupt files.
+const MAX_VALUE_SIZE: u64 = 1 << 28;
+
+/// Largest number of entries of an image file directory read. A classic TIFF can not have more.
+const MAX_DIRECTORY_ENTRIES: u64 = u16::MAX as u64;
+
+/// Largest number of pixels of a tile (or strip) decoded, to guard against corrupt files.
+const MAX_TILE_PIXELS: u64 = 4096 * 4096;
+
+/// A tiled (or stripped) image in a TIFF file. Strips are read as tiles as wide as the image.
+struct TiffImage {
+    width: u64,
+    height: u64,
+    tile_width: u64,
+    tile_height: u64,
+    offsets: Vec<u64>,
+    byte_counts: Vec<u64>,
+    compression: u16,
+    photometric: u16,
+    samples_per_pixel: u16,
+    predictor: u16,
+    jpeg_tables: Option<Vec<u8>>,
+}
+
+impl TiffImage {
+    /// Number of tiles (columns, rows).
+    fn tiles(&self) -> (u64, u64) {
+        (
+            self.width.div_ceil(self.tile_width),
+            self.height.div_ceil(self.tile_height),
+        )
+    }
+}
+
+/// A pyramidal TIFF file.
+pub struct TiffFile {
+    path: PathBuf,
+    file: File,
+    levels: Vec<TiffImage>,
+    associated: Vec<(String, TiffImage)>,
+    properties: HashMap<String, String>,
+    decoded_tiles: DecodedTiles,
+}
+
+impl TiffFile {
+    /// Whether the file starts with a TIFF or BigTIFF header.
+    pub fn is_supported(path: &Path) -> bool {
+        let mut header = [0; 4];
+        File::open(path)
+            .and_then(|mut file| file.read_exact(&mut header))
+            .is_ok()
+            && parse_byte_order(&header).is_some()
+    }
+
+    /// Open the TIFF at `path`.
+    ///
+    /// Returns `OpenSlideError::Unsupported` if it is not a tiled TIFF this reader can decode.
+    pub fn open(path: &Path) -> Result<TiffFile, OpenSlideError> {
+        if !path.exists() {
+            return Err(OpenSlideError::NotFound(path.to_path_buf()));
+        }
+        let unsupported = || OpenSlideError::Unsupported(path.to_path_buf());
+        let mut file = File::open(path).map_err(|err| read_error(path, err))?;
+        let directories = match read_directories(&mut file) {
+            Ok(Some(directories)) => directories,
+            Ok(None) => return Err(unsupported()),
+            Err(err) => return Err(read_error(path, err)),
+        };
+        let first = directories.first().ok_or_else(unsupported)?;
+        if !first.is_tiled() {
+            return Err(unsupported());
+        }
+        let description = first.ascii(IMAGE_DESCRIPTION);
+        let aperio = description
+            .as_deref()
+            .is_some_and(|description| description.starts_with("Aperio"));
+
+        let mut levels: Vec<TiffImage> = Vec::new();
+        let mut associated = Vec::new();
+        for (index, directory) in directories.iter().enumerate() {
+            let image = match directory.image() {
+                Some(image) => image,
+                // Level 0 must be readable, other images are skipped.
+                None if index == 0 => return Err(unsupported()),
+                None => continue,
+            };
+            if directory.is_tiled() {
+                // Levels have to get smaller, anything else (e.g. a tiled macro image) is skipped.
+                let smaller = levels
+                    .last()
+                    .is_none_or(|last| image.width < last.width && image.height < last.height);
+                if smaller {
+                    levels.push(image);
+                }
+            } else if aperio {
+                // The second image of an SVS is the thumbnail, the label and macro images say
+                // what they are on the second line of their description.
+                let kind = directory
+                    .ascii(IMAGE_DESCRIPTION)
+                    .and_then(|description| description.lines().nth(1).map(str::to_string));
+                let name = match kind {
+                    Some(kind) if kind.starts_with("label") => "label",
+                    Some(kind) if kind.starts_with("macro") => "macro",
+                    _ if index == 1 => "thumbnail",
+                    _ => continue,
+                };
+                associated.push((name.to_string(), image));
+            }
+        }
+
+        let properties = tiff_properties(first, aperio, &levels);
+        Ok(TiffFile {
+            path: path.to_path_buf(),
+            file,
+            levels,
+            associated,
+            properties,
+            decoded_tiles: DecodedTiles::new(),
+        })
+    }
+
+    fn level(&self, level: u32) -> Result<&TiffImage, OpenSlideError> {
+        self.levels
+            .get(level as usize)
+            .ok_or(OpenSlideError::LevelOutOfRange {
+                level,
+                level_count: self.levels.len() as u32,
+            })
+    }
+
+    /// The bytes of a tile as stored in the file.
+    fn read_tile_data(&self, image: &TiffImage, index: u64) -> Result<Vec<u8>, OpenSlideError> {
+        let offset = image.offsets[index as usize];
+        let length = image.byte_counts[index as usize];
+        if length > MAX_VALUE_SIZE {
+            return Err(OpenSlideError::Read(format!(
+                "{}: tile of {} bytes",
+                self.path.display(),
+                length
+            )));
+        }
+        let mut data = vec![0; length as usize];
+        read_exact_at(&self.file, &mut data, offset).map_err(|err| read_error(&self.path, err))?;
+        Ok(data)
+    }
+
+    /// The pixels of a tile, padded to a full tile.
+    fn decode_tile(&self, image: &TiffImage, index: u64) -> Result<RgbaImage, OpenSlideError> {
+        let data = self.read_tile_data(image, index)?;
+        let decode_error = |msg: String| {
+            OpenSlideError::Read(format!("{}: tile {}: {}", self.path.display(), index, msg))
+        };
+        let (width, height) = (image.tile_width as u32, image.tile_height as u32);
+        if image.compression == COMPRESSION_JPEG {
+            let jpeg = complete_jpeg(
+                image.jpeg_tables.as_deref(),
+                &data,
+                image.photometric == RGB,
+            );
+            let decoded = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg)
+                .map_err(|err| decode_error(err.to_string()))?;
+            return Ok(decoded.into_rgba8());
+        }
+
+        let mut samples = match image.compression {
+            COMPRESSION_NONE => data,
+            COMPRESSION_LZW => {
+                weezl::decode::Decoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+                    .decode(&data)
+                    .map_err(|err| decode_error(format!("LZW: {}", err)))?
+            }
+            _ => {
+                let mut samples = Vec::new();
+                ZlibDecoder::new(data.as_slice())
+                    .read_to_end(&mut samples)
+                    .map_err(|err| decode_error(format!("Deflate: {}", err)))?;
+                samples
+            }
+        };
+        let channels = image.samples_per_pixel as usize;
+        let row_size = width as usize * channels;
+        // The last strip of an image is usually shorter than the others.
+        samples.resize(row_size * height as usize, 0);
+        if image.predictor == 2 {
+            // Horizontal differencing.
+            for row in samples.chunks_exact_mut(row_size) {
+                for i in channels..row_size {
+                    row[i] = row[i].wrapping_add(row[i - channels]);
+                }
+            }
+        }
+        let mut tile = RgbaImage::new(width, height);
+        for (x, y, pixel) in tile.enumerate_pixels_mut() {
+            let offset = y as usize * row_size + x as usize * channels;
+            let sample = &samples[offset..offset + channels];
+            *pixel = match (image.photometric, channels) {
+                (WHITE_IS_ZERO, _) => {
+                    Rgba([255 - sample[0], 255 - sample[0], 255 - sample[0], 255])
+                }
+                (BLACK_IS_ZERO, _) => Rgba([sample[0], sample[0], sample[0], 255]),
+                (_, 3) => Rgba([sample[0], sample[1], sample[2], 255]),
+                _ => Rgba([sample[0], sample[1], sample[2], sample[3]]),
+            };
+        }
+        Ok(tile)
+    }
+}
+
+impl SlideBackend for TiffFile {
+    fn level_count(&self) -> Result<u32, OpenSlideError> {
+        Ok(self.levels.len() as u32)
+    }
+
+    fn level_dimensions(&self, level: u32) -> Result<(u64, u64), OpenSlideError> {
+        let image = self.level(level)?;
+        Ok((image.width, image.height))
+    }
+
+    fn level_downsample(&self, level: u32) -> Result<f64, OpenSlideError> {
+        // Like OpenSlide, the average of the downsamples of the width and height.
+        let image = self.level(level)?;
+        let (l0_width, l0_height) = (self.levels[0].width, self.levels[0].height);
+        Ok((l0_width as f64 / image.width as f64 + l0_height as f64 / image.height as f64) / 2.0)
+    }
+
+    fn read_region(
+        &self,
+        (x, y): (u64, u64),
+        level: u32,
+        (width, height): (u64, u64),
+    ) -> Result<RgbaImage, OpenSlideError> {
+        let image = self.level(level)?;
+        let downsample = self.level_downsample(level)?;
+        let location = (
+            (x as f64 / downsample) as u64,
+            (y as f64 / downsample) as u64,
+        );
+        let (cols, _) = image.tiles();
+        read_tiled_region(
+            (image.width, image.height),
+            (image.tile_width, image.tile_height),
+            location,
+            (width, height),
+            |col, row| {
+                let index = row * cols + col;
+                self.decoded_tiles
+                    .get_or_decode((level, index), || self.decode_tile(image, index))
+                    .map(Some)
+            },
+        )
+    }
+
+    fn read_jpeg_tile(
+        &self,
+        level: u32,
+        (x, y): (u64, u64),
+        (width, height): (u64, u64),
+    ) -> Result<Option<Vec<u8>>, OpenSlideError> {
+        let image = self.level(level)?;
+        let aligned = x % image.tile_width == 0 && y % image.tile_height == 0;
+        // Tiles on the right and bottom edges are padded, so they are larger than the region.
+        let inside = x + width <= image.width && y + height <= image.height;
+        if image.compression != COMPRESSION_JPEG
+            || (width, height) != (image.tile_width, image.tile_height)
+            || !aligned
+            || !inside
+        {
+            return Ok(None);
+        }
+        let index = (y / image.tile_height) * image.tiles().0 + x / image.tile_width;
+        let data = self.read_tile_data(image, index)?;
+        Ok(Some(complete_jpeg(
+            image.jpeg_tables.as_deref(),
+            &data,
+            image.photometric == RGB,
+        )))
+    }
+
+    fn vendor(&self) -> Option<String> {
+        self.properties.get("openslide.vendor").cloned()
+    }
+
+    fn objective_power(&self) -> Option<u32> {
+        self.properties
+            .get("openslide.objective-power")
+            .and_then(|power| power.parse().ok())
+    }
+
+    fn mpp(&self) -> Option<(f64, f64)> {
+        let mpp = |key: &str| self.properties.get(key).and_then(|mpp| mpp.parse().ok());
+        match (mpp("openslide.mpp-x"), mpp("openslide.mpp-y")) {
+            (Some(x), Some(y)) if x > 0.0 && y > 0.0 => Some((x, y)),
+            _ => None,
+        }
+    }
+
+    fn properties(&self) -> Result<HashMap<String, String>, OpenSlideError> {
+        Ok(self.properties.clone())
+    }
+
+    fn associated_image_names(&self) -> Result<Vec<String>, OpenSlideError> {
+        Ok(self
+            .associated
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    fn read_associated_image(&self, name: &str) -> Result<RgbaImage, OpenSlideError> {
+        let (_, image) = self
+            .associated
+            .iter()
+            .find(|(associated, _)| associated == name)
+            .ok_or_else(|| OpenSlideError::AssociatedImageNotFound(name.to_string()))?;
+        let (cols, _) = image.tiles();
+        read_tiled_region(
+            (image.width, image.height),
+            (image.tile_width, image.tile_height),
+            (0, 0),
+            (image.width, image.height),
+            |col, row| Ok(Some(Arc::new(self.decode_tile(image, row * cols + col)?))),
+        )
+    }
+}
+
+fn read_error(path: &Path, err: io::Error) -> OpenSlideError {
+    OpenSlideError::Read(format!("{}: {}", path.display(), err))
+}
+
+/// Whether a TIFF header is big endian, or `None` if it is no TIFF header.
+fn parse_byte_order(header: &[u8; 4]) -> Option<(bool, bool)> {
+    let big_endian = match &header[..2] {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let version = if big_endian {
+        u16::from_be_bytes([header[2], header[3]])
+    } else {
+        u16::from_le_bytes([header[2], header[3]])
+    };
+    match version {
+        42 => Some((big_endian, false)),
+        43 => Some((big_endian, true)),
+        _ => None,
+    }
+}
+
+/// An entry of an image file directory, with its values read.
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    bytes: Vec<u8>,
+}
+
+/// An image file directory, with the byte order of the file.
+struct Directory {
+    big_endian: bool,
+    entries: Vec<Entry>,
+}
+
+impl Directory {
+    fn get(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    fn is_tiled(&self) -> bool {
+        self.get(TILE_WIDTH).is_some()
+    }
+
+    /// The values of an integer tag.
+    fn uints(&self, tag: u16) -> Option<Vec<u64>> {
+        let entry = self.get(tag)?;
+        let read = |bytes: &[u8]| {
+            let mut value = [0; 8];
+            if self.big_endian {
+                value[8 - bytes.len()..].copy_from_slice(bytes);
+                u64::from_be_bytes(value)
+            } else {
+                value[..bytes.len()].copy_from_slice(bytes);
+                u64::from_le_bytes(value)
+            }
+        };
+        let size = match entry.field_type {
+            // BYTE, UNDEFINED
+            1 | 7 => 1,
+            // SHORT
+            3 => 2,
+            // LONG, IFD
+            4 | 13 => 4,
+            // LONG8, IFD8
+            16 | 18 => 8,
+            _ => return None,
+        };
+        Some(entry.bytes.chunks_exact(size).map(read).collect())
+    }
+
+    fn uint(&self, tag: u16) -> Option<u64> {
+        self.uints(tag)?.first().copied()
+    }
+
+    fn ascii(&self, tag: u16) -> Option<String> {
+        let entry = self.get(tag)?;
+        let text = String::from_utf8_lossy(&entry.bytes);
+        Some(text.trim_end_matches('\0').to_string())
+    }
+
+    fn rational(&self, tag: u16) -> Option<f64> {
+        let entry = self.get(tag)?;
+        if entry.field_type != 5 || entry.bytes.len() < 8 {
+            return None;
+        }
+        let part = |bytes: &[u8]| {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            if self.big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        let denominator = part(&entry.bytes[4..8]);
+        (denominator != 0).then(|| part(&entry.bytes[..4]) as f64 / denominator as f64)
+    }
+
+    /// The image described by this directory, if it can be decoded.
+    fn image(&self) -> Option<TiffImage> {
+        let width = self.uint(IMAGE_WIDTH)?;
+        let height = self.uint(IMAGE_LENGTH)?;
+        let compression = self.uint(COMPRESSION).unwrap_or(1) as u16;
+        let photometric = self.uint(PHOTOMETRIC_INTERPRETATION)? as u16;
+        let samples_per_pixel = self.uint(SAMPLES_PER_PIXEL).unwrap_or(1) as u16;
+        let predictor = self.uint(PREDICTOR).unwrap_or(1) as u16;
+        let bits = self.uints(BITS_PER_SAMPLE).unwrap_or_else(|| vec![1]);
+        let planar = self.uint(PLANAR_CONFIGURATION).unwrap_or(1);
+
+        let supported_compression = matches!(
+            compression,
+            COMPRESSION_NONE
+                | COMPRESSION_LZW
+                | COMPRESSION_JPEG
+                | COMPRESSION_DEFLATE
+                | COMPRESSION_ADOBE_DEFLATE
+        );
+        let supported_samples = match photometric {
+            WHITE_IS_ZERO | BLACK_IS_ZERO => samples_per_pixel == 1,
+            RGB => samples_per_pixel == 3 || samples_per_pixel == 4,
+            YCBCR => compression == COMPRESSION_JPEG && samples_per_pixel == 3,
+            _ => false,
+        };
+        if !supported_compression
+            || !supported_samples
+            || bits.iter().any(|bits| *bits != 8)
+            || planar != 1
+            || !(predictor == 1 || predictor == 2)
+            || width == 0
+            || height == 0
+        {
+            return None;
+        }
+
+        let (tile_width, tile_height, offsets, byte_counts) = if self.is_tiled() {
+            (
+                self.uint(TILE_WIDTH)?,
+                self.uint(TILE_LENGTH)?,
+                self.uints(TILE_OFFSETS)?,
+                self.uints(TILE_BYTE_COUNTS)?,
+            )
+        } else {
+            (
+                width,
+                self.uint(ROWS_PER_STRIP).unwrap_or(height).min(height),
+                self.uints(STRIP_OFFSETS)?,
+                self.uints(STRIP_BYTE_COUNTS)?,
+            )
+        };
+        if tile_width == 0
+            || tile_height == 0
+            || tile_width.checked_mul(tile_height)? > MAX_TILE_PIXELS
+        {
+            return None;
+        }
+        let image = TiffImage {
+            width,
+            height,
+            tile_width,
+            tile_height,
+            offsets,
+            byte_counts,
+            compression,
+            photometric,
+            samples_per_pixel,
+            predictor,
+            jpeg_tables: self.get(JPEG_TABLES).map(|entry| entry.bytes.clone()),
+        };
+        let (cols, rows) = image.tiles();
+        let complete = image.offsets.len() as u64 >= cols * rows
+            && image.byte_counts.len() as u64 >= cols * rows;
+        complete.then_some(image)
+    }
+}
+
+/// Read all image file directories of a TIFF, or `None` if it is not a TIFF.
+fn read_directories(file: &mut File) -> io::Result<Option<Vec<Directory>>> {
+    let mut header = [0; 16];
+    let length = file.read(&mut header)?;
+    if length < 8 {
+        return Ok(None);
+    }
+    let (big_endian, bigtiff) =
+        match parse_byte_order(&[header[0], header[1], header[2], header[3]]) {
+            Some(order) => order,
+            None => return Ok(None),
+        };
+    let uint = |bytes: &[u8]| {
+        let mut value = [0; 8];
+        if big_endian {
+            value[8 - bytes.len()..].copy_from_slice(bytes);
+            u64::from_be_bytes(value)
+        } else {
+            value[..bytes.len()].copy_from_slice(bytes);
+            u64::from_le_bytes(value)
+        }
+    };
+    // Sizes of the entry count, an entry and the value or offset in an entry.
+    let (count_size, entry_size, value_size) = if bigtiff { (8, 20, 8) } else { (2, 12, 4) };
+    let mut next = if bigtiff {
+        uint(&header[8..16])
+    } else {
+        uint(&header[4..8])
+    };
+
+    let mut directories = Vec::new();
+    let mut seen = Vec::new();
+    // A corrupt file could have a loop of directories.
+    while next != 0 && !seen.contains(&next) {
+        seen.push(next);
+        file.seek(SeekFrom::Start(next))?;
+        let mut count = vec![0; count_size];
+        file.read_exact(&mut count)?;
+        let count = uint(&count);
+        if count > MAX_DIRECTORY_ENTRIES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("image file directory has {} entries", count),
+            ));
+        }
+        let mut table = vec![0; (count * entry_size + value_size) as usize];
+        file.read_exact(&mut table)?;
+
+        let mut entries = Vec::new();
+        for raw in table.chunks_exact(entry_size as usize) {
+            let tag = uint(&raw[..2]) as u16;
+            let field_type = uint(&raw[2..4]) as u16;
+            let (count, value) = raw[4..].split_at(value_size as usize);
+            let count = uint(count);
+            let type_size = match field_type {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 | 13 => 4,
+                5 | 10 | 12 | 16 | 17 | 18 => 8,
+                _ => continue,
+            };
+            let size = count.saturating_mul(type_size);
+            if size > MAX_VALUE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("tag {} has {} bytes of values", tag, size),
+                ));
+            }
+            let bytes = if size <= value_size {
+                value[..size as usize].to_vec()
+            } else {
+                let position = file.stream_position()?;
+                let mut bytes = vec![0; size as usize];
+                file.seek(SeekFrom::Start(uint(value)))?;
+                file.read_exact(&mut bytes)?;
+                file.seek(SeekFrom::Start(position))?;
+                bytes
+            };
+            entries.push(Entry {
+                tag,
+                field_type,
+                bytes,
+            });
+        }
+        next = uint(&table[table.len() - value_size as usize..]);
+        directories.push(Directory {
+            big_endian,
+            entries,
+        });
+    }
+    Ok(Some(directories))
+}
+
+/// OpenSlide style properties of a TIFF, from its first directory and levels.
+fn tiff_properties(
+    first: &Directory,
+    aperio: bool,
+    levels: &[TiffImage],
+) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    let vendor = if aperio { "aperio" } else { "generic-tiff" };
+    properties.insert("openslide.vendor".to_string(), vendor.to_string());
+    properties.insert(
+        "openslide.level-count".to_string(),
+        levels.len().to_string(),
+    );
+    for (level, image) in levels.iter().enumerate() {
+        let downsample = (levels[0].width as f64 / image.width as f64
+            + levels[0].height as f64 / image.height as f64)
+            / 2.0;
+        for (key, value) in [
+            ("width", image.width.to_string()),
+            ("height", image.height.to_string()),
+            ("downsample", downsample.to_string()),
+            ("tile-width", image.tile_width.to_string()),
+            ("tile-height", image.tile_height.to_string()),
+        ] {
+            properties.insert(format!("openslide.level[{}].{}", level, key), value);
+        }
+    }
+
+    for (tag, name) in [
+        (IMAGE_DESCRIPTION, "ImageDescription"),
+        (MAKE, "Make"),
+        (MODEL, "Model"),
+        (SOFTWARE, "Software"),
+        (DATE_TIME, "DateTime"),
+    ] {
+        if let Some(value) = first.ascii(tag) {
+            properties.insert(format!("tiff.{}", name), value);
+        }
+    }
+    let resolution_unit = first.uint(RESOLUTION_UNIT).unwrap_or(2);
+    let resolution = (first.rational(X_RESOLUTION), first.rational(Y_RESOLUTION));
+    if let (Some(x), Some(y)) = resolution {
+        properties.insert("tiff.XResolution".to_string(), x.to_string());
+        properties.insert("tiff.YResolution".to_string(), y.to_string());
+        let unit = match resolution_unit {
+            1 => "none",
+            3 => "centimeter",
+            _ => "inch",
+        };
+        properties.insert("tiff.ResolutionUnit".to_string(), unit.to_string());
+        // Like OpenSlide, only a resolution in centimeters is trusted.
+        if resolution_unit == 3 && x > 0.0 && y > 0.0 {
+            properties.insert("openslide.mpp-x".to_string(), (10_000.0 / x).to_string());
+            properties.insert("openslide.mpp-y".to_string(), (10_000.0 / y).to_string());
+        }
+    }
+
+    if let Some(description) = first.ascii(IMAGE_DESCRIPTION) {
+        properties.insert("openslide.comment".to_string(), description.clone());
+        if aperio {
+            // "Aperio Image Library v10.0.50\r\n46000x32914 ... |AppMag = 20|MPP = 0.4990"
+            for field in description.split('|').skip(1) {
+                if let Some((key, value)) = field.split_once(" = ") {
+                    properties.insert(format!("aperio.{}", key.trim()), value.trim().to_string());
+                }
+            }
+            if let Some(mpp) = properties
+                .get("aperio.MPP")
+                .and_then(|mpp| mpp.parse::<f64>().ok())
+            {
+                properties.insert("openslide.mpp-x".to_string(), mpp.to_string());
+                properties.insert("openslide.mpp-y".to_string(), mpp.to_string());
+            }
+            if let Some(power) = properties
+                .get("aperio.AppMag")
+                .and_then(|power| power.parse::<u32>().ok())
+            {
+                properties.insert("openslide.objective-power".to_string(), power.to_string());
+            }
+        }
+    }
+    properties
+}
+
+#[test]
+fn test_tiff_file_roundtrip() {
+    use crate::backend::ImageFile;
+    use crate::export::tiff::{TiffCompression, TiffExport};
+    use crate::generator::resample::ResampleFilter;
+
+    let image = RgbaImage::from_fn(700, 300, |x, y| Rgba([(x % 256) as u8, y as u8, 7, 255]));
+    let path = std::env::temp_dir().join(format!("slidestream-{}.tif", std::process::id()));
+    let export = TiffExport {
+        tile_size: 256,
+        compression: TiffCompression::Deflate(1),
+        threads: 2,
+    };
+    export
+        .run(
+            &ImageFile::from_image(image.clone(), ResampleFilter::Area),
+            None,
+            &path,
+            &|_, _| {},
+        )
+        .unwrap();
+    let tiff = TiffFile::open(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    let tiff = tiff.unwrap();
+    assert_eq!(tiff.vendor().as_deref(), Some("generic-tiff"));
+    assert_eq!(tiff.level_count().unwrap(), 3);
+    assert_eq!(tiff.level_dimensions(1).unwrap(), (350, 150));
+    assert_eq!(tiff.mpp(), None);
+    let region = tiff.read_region((250, 100), 0, (300, 250)).unwrap();
+    assert_eq!(region.get_pixel(0, 0).0, [250, 100, 7, 255]);
+    assert_eq!(region.get_pixel(10, 10).0, [4, 110, 7, 255]);
+    assert_eq!(region.get_pixel(299, 199).0, [37, 43, 7, 255]);
+    // Outside the image.
+    assert_eq!(region.get_pixel(0, 200).0, [0, 0, 0, 0]);
+    // Deflate tiles are not passed through.
+    assert_eq!(tiff.read_jpeg_tile(0, (256, 0), (256, 256)).unwrap(), None);
+}
+
+#[test]
+fn test_tiff_file_jpeg_tables() {
+    use image::{DynamicImage, ImageOutputFormat};
+
+    // Split a JPEG into its tables and an abbreviated stream without them, as TIFF stores them.
+    let color = Rgba([200, 100, 50, 255]);
+    let mut jpeg = Vec::new();
+    DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 16, color))
+        .write_to(&mut jpeg, ImageOutputFormat::Jpeg(95))
+        .unwrap();
+    let (mut tables, mut tile) = (vec![0xff, 0xd8], vec![0xff, 0xd8]);
+    let mut position = 2;
+    while jpeg[position + 1] != 0xda {
+        let length = u16::from_be_bytes([jpeg[position + 2], jpeg[position + 3]]) as usize;
+        let segment = &jpeg[position..position + 2 + length];
+        match jpeg[position + 1] {
+            // Quantization and Huffman tables.
+            0xdb | 0xc4 => tables.extend_from_slice(segment),
+            // JFIF and other application segments.
+            0xe0..=0xef => {}
+            _ => tile.extend_from_slice(segment),
+        }
+        position += 2 + length;
+    }
+    tables.extend_from_slice(&[0xff, 0xd9]);
+    tile.extend_from_slice(&jpeg[position..]);
+
+    // A single 16 x 16 tile, with the tables and tile data after the directory.
+    let tiff = |photometric: u16| {
+        let data_offset = 8 + 2 + 11 * 12 + 4;
+        let bits_offset = data_offset;
+        let tables_offset = bits_offset + 6;
+        let tile_offset = tables_offset + tables.len() as u32;
+        let mut file = b"II".to_vec();
+        file.extend(42u16.to_le_bytes());
+        file.extend(8u32.to_le_bytes());
+        file.extend(11u16.to_le_bytes());
+        for (tag, field_type, count, value) in [
+            (IMAGE_WIDTH, 3, 1, 16),
+            (IMAGE_LENGTH, 3, 1, 16),
+            (BITS_PER_SAMPLE, 3, 3, bits_offset),
+            (COMPRESSION, 3, 1, COMPRESSION_JPEG as u32),
+            (PHOTOMETRIC_INTERPRETATION, 3, 1, photometric as u32),
+            (SAMPLES_PER_PIXEL, 3, 1, 3),
+            (TILE_WIDTH, 3, 1, 16),
+            (TILE_LENGTH, 3, 1, 16),
+            (TILE_OFFSETS, 4, 1, tile_offset),
+            (TILE_BYTE_COUNTS, 4, 1, tile.len() as u32),
+            (JPEG_TABLES, 7, tables.len() as u32, tables_offset),
+        ] {
+            file.extend(tag.to_le_bytes());
+            file.extend((field_type as u16).to_le_bytes());
+            file.extend(count.to_le_bytes());
+            file.extend(value.to_le_bytes());
+        }
+        file.extend(0u32.to_le_bytes());
+        for bits in [8u16; 3] {
+            file.extend(bits.to_le_bytes());
+        }
+        file.extend_from_slice(&tables);
+        file.extend_from_slice(&tile);
+        file
+    };
+
+    // The components are YCbCr as encoded, and are taken as RGB (124, 86, 182) when the TIFF
+    // says they are RGB.
+    for (photometric, expected) in [(YCBCR, [200, 100, 50]), (RGB, [124, 86, 182])] {
+        let path = std::env::temp_dir().join(format!(
+            "slidestream-jpeg-{}-{}.tif",
+            photometric,
+            std::process::id()
+        ));
+        std::fs::write(&path, tiff(photometric)).unwrap();
+        let opened = TiffFile::open(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let tiff = opened.unwrap();
+        let close = |pixel: &Rgba<u8>| {
+            (0..3).all(|channel| (pixel[channel] as i16 - expected[channel] as i16).abs() <= 2)
+                && pixel[3] == 255
+        };
+        let region = tiff.read_region((0, 0), 0, (16, 16)).unwrap();
+        assert!(
+            close(region.get_pixel(8, 8)),
+            "{:?}",
+            region.get_pixel(8, 8)
+        );
+        let stored = tiff.read_jpeg_tile(0, (0, 0), (16, 16)).unwrap().unwrap();
+        let decoded = image::load_from_memory_with_format(&stored, ImageFormat::Jpeg)
+            .unwrap()
+            .to_rgba8();
+        assert!(
+            close(decoded.get_pixel(3, 3)),
+            "{:?}",
+            decoded.get_pixel(3, 3)
+        );
+    }
+}
+
+#[test]
+fn test_tiff_file_tile_size() {
+    // An uncompressed grayscale image of the given size, described with the given tile (or strip)
+    // tags.
+    let tiff = |size: u32, tags: &[(u16, u32)]| {
+        let entries = 6 + tags.len() as u32;
+        let data_offset = 8 + 2 + entries * 12 + 4;
+        let mut file = b"II".to_vec();
+        file.extend(42u16.to_le_bytes());
+        file.extend(8u32.to_le_bytes());
+        file.extend((entries as u16).to_le_bytes());
+        for (tag, value) in [
+            (IMAGE_WIDTH, size),
+            (IMAGE_LENGTH, size),
+            (BITS_PER_SAMPLE, 8),
+            (COMPRESSION, COMPRESSION_NONE as u32),
+            (PHOTOMETRIC_INTERPRETATION, BLACK_IS_ZERO as u32),
+        ]
+        .iter()
+        .chain(tags)
+        .chain(&[(SAMPLES_PER_PIXEL, 1)])
+        {
+            file.extend(tag.to_le_bytes());
+            file.extend(4u16.to_le_bytes());
+            file.extend(1u32.to_le_bytes());
+            match *tag {
+                STRIP_OFFSETS | TILE_OFFSETS => file.extend(data_offset.to_le_bytes()),
+                _ => file.extend(value.to_le_bytes()),
+            }
+        }
+        file.extend(0u32.to_le_bytes());
+        file.extend([0u8; 16 * 16]);
+        file
+    };
+
+    let strips = |size, rows_per_strip| {
+        tiff(
+            size,
+            &[
+                (STRIP_OFFSETS, 0),
+                (ROWS_PER_STRIP, rows_per_strip),
+                (STRIP_BYTE_COUNTS, 256),
+            ],
+        )
+    };
+    let tiles = |tile_width, tile_length| {
+        tiff(
+            16,
+            &[
+                (TILE_WIDTH, tile_width),
+                (TILE_LENGTH, tile_length),
+                (TILE_OFFSETS, 0),
+                (TILE_BYTE_COUNTS, 256),
+            ],
+        )
+    };
+    let cases = [
+        (strips(16, 0), false),
+        (strips(16, 16), true),
+        // A single strip of a large image is too large to be decoded as one tile.
+        (strips(1 << 13, 1 << 13), false),
+        (tiles(0, 16), false),
+        (tiles(16, 0), false),
+        (tiles(16, 16), true),
+    ];
+    for (index, (file, readable)) in cases.iter().enumerate() {
+        let path = std::env::temp_dir().join(format!(
+            "slidestream-tile-size-{}-{}.tif",
+            index,
+            std::process::id()
+        ));
+        std::fs::write(&path, file).unwrap();
+        let directories = read_directories(&mut File::open(&path).unwrap());
+        let opened = TiffFile::open(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let directories = directories.unwrap().unwrap();
+        assert_eq!(
+            directories[0].image().is_some(),
+            *readable,
+            "case {}",
+            index
+        );
+        if directories[0].is_tiled() && !readable {
+            assert!(matches!(opened, Err(OpenSlideError::Unsupported(_))));
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! In-memory cache of encoded tiles.
+//!
+//! Producing a tile (reading the region from the slide, resizing and encoding it) is expensive,
+//! while popular tiles are requested over and over, e.g. when several people look at the same
+//! slide. The cache keeps the most recently used encoded tiles within a budget of bytes. Slide
+//! thumbnails are kept in the same cache.
+
+use crate::generator::TileFormat;
+use bytes::Bytes;
+use lru::LruCache;
+use serde::Serialize;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Identifies an encoded tile.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    slide: String,
+    image: CachedImage,
+    format: TileFormat,
+    quality: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum CachedImage {
+    Tile {
+        geometry: String,
+        level: u64,
+        col: u64,
+        row: u64,
+    },
+    Thumbnail {
+        max_size: u32,
+    },
+}
+
+impl TileKey {
+    pub fn new(
+        slide: &str,
+        geometry: &str,
+        level: u64,
+        col: u64,
+        row: u64,
+        format: TileFormat,
+        quality: u8,
+    ) -> TileKey {
+        TileKey::with_image(
+            slide,
+            CachedImage::Tile {
+                geometry: geometry.to_string(),
+                level,
+                col,
+                row,
+            },
+            format,
+            quality,
+        )
+    }
+
+    /// Identifies the thumbnail of a whole slide that is at most `max_size` wide and high.
+    pub fn thumbnail(slide: &str, max_size: u32, format: TileFormat, quality: u8) -> TileKey {
+        TileKey::with_image(slide, CachedImage::Thumbnail { max_size }, format, quality)
+    }
+
+    fn with_image(slide: &str, image: CachedImage, format: TileFormat, quality: u8) -> TileKey {
+        TileKey {
+            slide: slide.to_string(),
+            image,
+            format,
+            // The quality does not affect lossless formats, so don't cache those twice.
+            quality: match format {
+                TileFormat::Png => 0,
+                _ => quality,
+            },
+        }
+    }
+
+    /// Approximate number of bytes the key takes up in the cache.
+    fn size(&self) -> usize {
+        let geometry = match &self.image {
+            CachedImage::Tile { geometry, .. } => geometry.len(),
+            CachedImage::Thumbnail { .. } => 0,
+        };
+        size_of::<TileKey>() + self.slide.len() + geometry
+    }
+}
+
+/// Snapshot of the cache counters.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// Bytes currently in use.
+    pub size: usize,
+    /// Maximum number of bytes in use.
+    pub capacity: usize,
+}
+
+struct Entries {
+    tiles: LruCache<TileKey, Bytes>,
+    size: usize,
+}
+
+/// A least recently used cache of encoded tiles, bounded by the total size of the tiles.
+///
+/// The cache can be shared between threads.
+pub struct TileCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TileCache {
+    /// Create a cache that holds at most `capacity` bytes. A capacity of 0 disables caching.
+    pub fn new(capacity: usize) -> TileCache {
+        TileCache {
+            entries: Mutex::new(Entries {
+                tiles: LruCache::unbounded(),
+                size: 0,
+            }),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Look up a tile, marking it as most recently used.
+    pub fn get(&self, key: &TileKey) -> Option<Bytes> {
+        let tile = self.lock().tiles.get(key).cloned();
+        match tile {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        tile
+    }
+
+    /// Add a tile, evicting the least recently used tiles to stay within the capacity.
+    ///
+    /// Tiles larger than the whole capacity are not cached.
+    pub fn insert(&self, key: TileKey, tile: Bytes) {
+        let size = key.size() + tile.len();
+        if size > self.capacity {
+            return;
+        }
+        let mut entries = self.lock();
+        if let Some(old) = entries.tiles.pop(&key) {
+            entries.size -= key.size() + old.len();
+        }
+        while entries.size + size > self.capacity {
+            match entries.tiles.pop_lru() {
+                Some((old_key, old)) => entries.size -= old_key.size() + old.len(),
+                None => break,
+            }
+        }
+        entries.tiles.put(key, tile);
+        entries.size += size;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.tiles.len(),
+            size: entries.size,
+            capacity: self.capacity,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // A panic while holding the lock can not leave the entries inconsistent in a way that
+        // matters for a cache, so recover from poisoning.
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[test]
+fn test_tile_cache_eviction() {
+    let key = |col| TileKey::new("slide", "default", 10, col, 0, TileFormat::Jpeg, 80);
+    let tile_size = key(0).size() + 100;
+    let cache = TileCache::new(2 * tile_size);
+
+    cache.insert(key(0), Bytes::from(vec![0; 100]));
+    cache.insert(key(1), Bytes::from(vec![1; 100]));
+    assert!(cache.get(&key(0)).is_some());
+    // Evicts tile 1, the least recently used.
+    cache.insert(key(2), Bytes::from(vec![2; 100]));
+    assert!(cache.get(&key(1)).is_none());
+    assert_eq!(cache.get(&key(2)).unwrap()[0], 2);
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+    assert_eq!((stats.entries, stats.size), (2, 2 * tile_size));
+
+    // Too large to cache at all.
+    cache.insert(key(3), Bytes::from(vec![3; 2 * tile_size]));
+    assert!(cache.get(&key(3)).is_none());
+    assert_eq!(cache.stats().entries, 2);
+}
+
+#[test]
+fn test_tile_key_ignores_png_quality() {
+    assert_eq!(
+        TileKey::new("slide", "default", 1, 2, 3, TileFormat::Png, 80),
+        TileKey::new("slide", "default", 1, 2, 3, TileFormat::Png, 95)
+    );
+    assert_ne!(
+        TileKey::new("slide", "default", 1, 2, 3, TileFormat::Jpeg, 80),
+        TileKey::new("slide", "default", 1, 2, 3, TileFormat::Jpeg, 95)
+    );
+    assert_eq!(
+        TileKey::thumbnail("slide", 512, TileFormat::Png, 80),
+        TileKey::thumbnail("slide", 512, TileFormat::Png, 95)
+    );
+}
@@ -0,0 +1,257 @@
+//! Server configuration.
+//!
+//! Settings are read from an optional TOML file, which may be overridden by command line flags.
+//! Every setting has a default, so an empty file (or no file at all) is a valid configuration as
+//! long as at least one slide root is given. For example:
+//!
+//! ```toml
+//! bind = "0.0.0.0"
+//! port = 8080
+//! slides = ["/data/slides"]
+//!
+//! [cache]
+//! tile_cache_mb = 1024
+//! max_open_slides = 16
+//!
+//! [tiles]
+//! format = "webp"
+//! quality = 90
+//! resample = "nearest"
+//! ```
+
+use crate::generator::resample::ResampleFilter;
+use crate::generator::TileFormat;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Complete configuration of the server.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Host name or IP address to listen on.
+    pub bind: String,
+    pub port: u16,
+    /// Number of HTTP worker threads. Defaults to the number of CPUs.
+    pub workers: Option<usize>,
+    /// Directory containing the viewer (`index.html`) and its assets (`static/`).
+    pub static_dir: PathBuf,
+    /// Slide files, or directories that are searched for slides.
+    pub slides: Vec<PathBuf>,
+    pub cache: CacheConfig,
+    pub tiles: TileConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "localhost".to_string(),
+            port: 8080,
+            workers: None,
+            static_dir: PathBuf::from("./public"),
+            slides: Vec::new(),
+            cache: CacheConfig::default(),
+            tiles: TileConfig::default(),
+        }
+    }
+}
+
+/// Limits on what is kept in memory.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Size of the cache of encoded tiles, in MiB. 0 disables the cache.
+    pub tile_cache_mb: usize,
+    /// Maximum number of slides that are open at the same time.
+    pub max_open_slides: usize,
+    /// Slides that have not been used for this many seconds are closed.
+    pub slide_idle_timeout_secs: u64,
+}
+
+impl CacheConfig {
+    /// Size of the cache of encoded tiles in bytes, or `None` if it does not fit in a `usize`.
+    pub fn tile_cache_bytes(&self) -> Option<usize> {
+        self.tile_cache_mb.checked_mul(1024 * 1024)
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            tile_cache_mb: 256,
+            max_open_slides: 64,
+            slide_idle_timeout_secs: 10 * 60,
+        }
+    }
+}
+
+/// How tiles are produced by default.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TileConfig {
+    /// Format advertised in the DZI.
+    pub format: TileFormat,
+    /// Quality (1-100) of lossy encoded tiles, unless a request overrides it.
+    pub quality: u8,
+    /// Filter used to scale tiles between the levels stored in the slide.
+    pub resample: ResampleFilter,
+    /// Number of threads producing tiles. Defaults to the number of CPUs.
+    pub threads: Option<usize>,
+    /// Number of tiles that can wait for a thread before requests are refused.
+    pub queue_size: usize,
+}
+
+impl Default for TileConfig {
+    fn default() -> Self {
+        TileConfig {
+            format: TileFormat::Jpeg,
+            quality: 80,
+            resample: ResampleFilter::Area,
+            threads: None,
+            queue_size: 1024,
+        }
+    }
+}
+
+/// Reasons a configuration could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    Read(PathBuf, io::Error),
+    /// The config file is not valid TOML, or contains unknown or mistyped settings.
+    Parse(PathBuf, String),
+    /// A setting has a value that is not allowed.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => {
+                write!(f, "Could not read config file {}: {}", path.display(), err)
+            }
+            ConfigError::Parse(path, msg) => {
+                write!(f, "Invalid config file {}: {}", path.display(), msg)
+            }
+            ConfigError::Invalid(msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Read the configuration from a TOML file. Settings missing from the file get their default.
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.into(), err))?;
+        Config::from_toml(&text).map_err(|msg| ConfigError::Parse(path.into(), msg))
+    }
+
+    /// Parse the configuration from TOML text.
+    pub fn from_toml(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    /// Check that the settings make sense together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+        if self.slides.is_empty() {
+            return invalid("no slides given, pass a slide file or directory");
+        }
+        if self.bind.is_empty() {
+            return invalid("bind address must not be empty");
+        }
+        if self.workers == Some(0) {
+            return invalid("workers must be at least 1");
+        }
+        if self.cache.tile_cache_bytes().is_none() {
+            return invalid("tile_cache_mb is too large");
+        }
+        if self.cache.max_open_slides == 0 {
+            return invalid("max_open_slides must be at least 1");
+        }
+        if self.cache.slide_idle_timeout_secs == 0 {
+            return invalid("slide_idle_timeout_secs must be at least 1");
+        }
+        if !(1..=100).contains(&self.tiles.quality) {
+            return invalid("tile quality must be between 1 and 100");
+        }
+        if self.tiles.threads == Some(0) {
+            return invalid("tile threads must be at least 1");
+        }
+        if self.tiles.queue_size == 0 {
+            return invalid("tile queue_size must be at least 1");
+        }
+        if !self.static_dir.is_dir() {
+            return Err(ConfigError::Invalid(format!(
+                "static_dir {} is not a directory",
+                self.static_dir.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    assert_eq!(Config::from_toml("").unwrap(), Config::default());
+
+    let config = Config::from_toml(
+        r#"
+        port = 9000
+        slides = ["/data/a", "/data/b.svs"]
+
+        [cache]
+        max_open_slides = 4
+
+        [tiles]
+        format = "webp"
+        quality = 95
+        resample = "box"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.port, 9000);
+    assert_eq!(config.bind, "localhost");
+    assert_eq!(config.slides.len(), 2);
+    assert_eq!(config.cache.max_open_slides, 4);
+    assert_eq!(config.cache.tile_cache_mb, 256);
+    assert_eq!(config.tiles.format, TileFormat::WebP);
+    assert_eq!(config.tiles.quality, 95);
+    assert_eq!(config.tiles.resample, ResampleFilter::Area);
+
+    assert!(Config::from_toml("prot = 9000").is_err());
+    assert!(Config::from_toml("port = \"high\"").is_err());
+    assert!(Config::from_toml("[tiles]\nformat = \"gif\"").is_err());
+    assert!(Config::from_toml("[tiles]\nresample = \"cubic\"").is_err());
+}
+
+#[test]
+fn test_validate_config() {
+    assert!(matches!(
+        Config::default().validate(),
+        Err(ConfigError::Invalid(_))
+    ));
+
+    let mut config = Config {
+        slides: vec![PathBuf::from("slides")],
+        static_dir: std::env::temp_dir(),
+        ..Config::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.tiles.quality = 0;
+    assert!(config.validate().is_err());
+    config.tiles.quality = 80;
+    config.cache.max_open_slides = 0;
+    assert!(config.validate().is_err());
+    config.cache.max_open_slides = 1;
+    config.cache.tile_cache_mb = usize::MAX;
+    assert!(config.validate().is_err());
+    config.cache.tile_cache_mb = 256;
+    assert_eq!(config.cache.tile_cache_bytes(), Some(256 << 20));
+    config.static_dir = PathBuf::from("/nonexistent/public");
+    assert!(config.validate().is_err());
+}
@@ -0,0 +1,205 @@
+//! Offline export of slides to static files.
+//!
+//! Exports run on the calling thread plus a number of worker threads, and report their progress
+//! through a callback so the caller decides how (and how often) to show it.
+
+pub mod deepzoom;
+pub mod tiff;
+pub mod zarr;
+
+use crate::backend::SlideBackend;
+use crate::generator::openslide::OpenSlideError;
+use crate::generator::GeneratorError;
+use crate::region::{flatten, read_scaled_region, Region};
+use image::RgbImage;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Called with the number of finished and total units of work (e.g. tiles) of an export. It may be
+/// called from several threads at once.
+pub type Progress<'a> = &'a (dyn Fn(u64, u64) + Sync);
+
+/// Reasons an export failed.
+#[derive(Debug)]
+pub enum ExportError {
+    /// The export options are not valid.
+    Invalid(String),
+    /// An output file or directory could not be written.
+    Write(PathBuf, io::Error),
+    /// The slide could not be read, or a tile could not be encoded.
+    Generator(GeneratorError),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Invalid(msg) => write!(f, "Invalid export: {}", msg),
+            ExportError::Write(path, err) => {
+                write!(f, "Could not write {}: {}", path.display(), err)
+            }
+            ExportError::Generator(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExportError::Write(_, err) => Some(err),
+            ExportError::Generator(err) => Some(err),
+            ExportError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<GeneratorError> for ExportError {
+    fn from(err: GeneratorError) -> Self {
+        ExportError::Generator(err)
+    }
+}
+
+impl From<OpenSlideError> for ExportError {
+    fn from(err: OpenSlideError) -> Self {
+        ExportError::Generator(err.into())
+    }
+}
+
+/// Run `work` for the indices `0..total` on `threads` threads (including the calling one).
+///
+/// Stops at the first error, which is returned.
+fn run_parallel<F>(
+    total: u64,
+    threads: usize,
+    progress: Progress,
+    work: F,
+) -> Result<(), ExportError>
+where
+    F: Fn(u64) -> Result<(), ExportError> + Sync,
+{
+    if threads == 0 {
+        return Err(ExportError::Invalid(
+            "threads must be at least 1".to_string(),
+        ));
+    }
+    let next = AtomicU64::new(0);
+    let done = AtomicU64::new(0);
+    let failed = AtomicBool::new(false);
+    let error = Mutex::new(None);
+    let worker = || {
+        while !failed.load(Ordering::Relaxed) {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            if index >= total {
+                return;
+            }
+            if let Err(err) = work(index) {
+                failed.store(true, Ordering::Relaxed);
+                error.lock().unwrap().get_or_insert(err);
+                return;
+            }
+            progress(done.fetch_add(1, Ordering::Relaxed) + 1, total);
+        }
+    };
+    std::thread::scope(|scope| {
+        for _ in 1..threads {
+            scope.spawn(worker);
+        }
+        worker();
+    });
+    match error.into_inner().unwrap() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// The (level, col, row) of the index-th cell of a pyramid of grids, given the (columns, rows) of
+/// every level, counting level by level and row by row.
+fn grid_position(grids: &[(u64, u64)], mut index: u64) -> Option<(usize, u64, u64)> {
+    for (level, (cols, rows)) in grids.iter().enumerate() {
+        let count = cols * rows;
+        if index < count {
+            return Some((level, index % cols, index / cols));
+        }
+        index -= count;
+    }
+    None
+}
+
+/// The (width, height) of a level 0 image of `(width, height)` pixels downsampled by powers of
+/// two, until it fits in `fit` x `fit` pixels.
+fn power_of_two_levels((width, height): (u64, u64), fit: u64) -> Vec<(u64, u64)> {
+    let mut dimensions = vec![(width.max(1), height.max(1))];
+    let mut downsample = 1.0;
+    while let Some(&(w, h)) = dimensions.last() {
+        if w <= fit && h <= fit {
+            break;
+        }
+        downsample *= 2.0;
+        dimensions.push((
+            ((width as f64 / downsample).round() as u64).max(1),
+            ((height as f64 / downsample).round() as u64).max(1),
+        ));
+    }
+    dimensions
+}
+
+/// Read the `size` pixels at `origin` of a level of `region`, where the whole region is scaled to
+/// `level_size`. The pixels are flattened onto white.
+fn read_level_tile(
+    wsi: &dyn SlideBackend,
+    region: Region,
+    level_size: (u64, u64),
+    (x, y): (u64, u64),
+    (width, height): (u64, u64),
+) -> Result<RgbImage, ExportError> {
+    let scale = (
+        region.width as f64 / level_size.0 as f64,
+        region.height as f64 / level_size.1 as f64,
+    );
+    let l0_x = (x as f64 * scale.0).round() as u64;
+    let l0_y = (y as f64 * scale.1).round() as u64;
+    let l0_region = Region::new(
+        region.x + l0_x,
+        region.y + l0_y,
+        (((x + width) as f64 * scale.0).round() as u64 - l0_x).max(1),
+        (((y + height) as f64 * scale.1).round() as u64 - l0_y).max(1),
+    );
+    let image = read_scaled_region(wsi, l0_region, (width as u32, height as u32))?;
+    Ok(flatten(&image))
+}
+
+/// Create a directory and its parents, keeping the path in the error.
+fn create_dir(path: &Path) -> Result<(), ExportError> {
+    std::fs::create_dir_all(path).map_err(|err| ExportError::Write(path.into(), err))
+}
+
+/// Write a file, keeping the path in the error.
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), ExportError> {
+    std::fs::write(path, contents).map_err(|err| ExportError::Write(path.into(), err))
+}
+
+#[test]
+fn test_grid_position() {
+    let grids = [(1, 1), (2, 1), (3, 2)];
+    assert_eq!(grid_position(&grids, 0), Some((0, 0, 0)));
+    assert_eq!(grid_position(&grids, 2), Some((1, 1, 0)));
+    assert_eq!(grid_position(&grids, 3), Some((2, 0, 0)));
+    assert_eq!(grid_position(&grids, 7), Some((2, 1, 1)));
+    assert_eq!(grid_position(&grids, 8), Some((2, 2, 1)));
+    assert_eq!(grid_position(&grids, 9), None);
+}
+
+#[test]
+fn test_power_of_two_levels() {
+    assert_eq!(
+        power_of_two_levels((2_000, 1_000), 512),
+        vec![(2_000, 1_000), (1_000, 500), (500, 250)]
+    );
+    assert_eq!(power_of_two_levels((100, 100), 512), vec![(100, 100)]);
+    assert_eq!(
+        power_of_two_levels((1_025, 3), 512),
+        vec![(1_025, 3), (513, 2), (256, 1)]
+    );
+}
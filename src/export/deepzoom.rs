@@ -0,0 +1,83 @@
+//! Export of a complete Deep Zoom pyramid, like OpenSlide's `deepzoom_tile.py`.
+//!
+//! The pyramid is written as `{name}.dzi` and `{name}_files/{level}/{col}_{row}.{format}`, which
+//! any static web server can serve to OpenSeadragon. JPEG tiles stored in the slide with exactly
+//! the pixels of a Deep Zoom tile are copied as they are.
+
+use super::{create_dir, grid_position, run_parallel, write_file, ExportError, Progress};
+use crate::generator::{DeepZoomGenerator, TileFormat};
+use std::path::Path;
+
+/// How the tiles of a Deep Zoom export are produced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeepZoomExport {
+    pub format: TileFormat,
+    /// Quality (1-100) of lossy encoded tiles.
+    pub quality: u8,
+    /// Number of threads reading and encoding tiles.
+    pub threads: usize,
+}
+
+impl Default for DeepZoomExport {
+    fn default() -> Self {
+        DeepZoomExport {
+            format: TileFormat::Jpeg,
+            quality: 80,
+            threads: 4,
+        }
+    }
+}
+
+impl DeepZoomExport {
+    /// Write the pyramid of `generator` to `dir`, as `{name}.dzi` and `{name}_files/`.
+    ///
+    /// Existing files are overwritten. The export stops at the first tile that fails.
+    pub fn run(
+        &self,
+        generator: &DeepZoomGenerator,
+        dir: &Path,
+        name: &str,
+        progress: Progress,
+    ) -> Result<(), ExportError> {
+        if !(1..=100).contains(&self.quality) {
+            return Err(ExportError::Invalid(
+                "quality must be between 1 and 100".to_string(),
+            ));
+        }
+        let tiles_dir = dir.join(format!("{}_files", name));
+        for level in 0..generator.level_count() {
+            create_dir(&tiles_dir.join(level.to_string()))?;
+        }
+
+        let level_tiles = generator.level_tiles();
+        run_parallel(generator.tile_count(), self.threads, progress, |index| {
+            let (level, col, row) =
+                grid_position(level_tiles, index).expect("index within tile count");
+            let level = level as u64;
+            let stored = match self.format {
+                TileFormat::Jpeg => generator.get_jpeg_tile(level, col, row)?,
+                _ => None,
+            };
+            let buffer = match stored {
+                Some(buffer) => buffer,
+                None => {
+                    let tile = generator.get_tile(level, col, row)?;
+                    self.format.encode(&tile, self.quality)?
+                }
+            };
+            let path = tiles_dir.join(level.to_string()).join(format!(
+                "{}_{}.{}",
+                col,
+                row,
+                self.format.extension()
+            ));
+            write_file(&path, &buffer)
+        })?;
+
+        // The descriptor is written last, so a viewer never sees an incomplete pyramid.
+        write_file(
+            &dir.join(format!("{}.dzi", name)),
+            generator.get_dzi(self.format).as_bytes(),
+        )
+    }
+}
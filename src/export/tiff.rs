@@ -0,0 +1,379 @@
+//! Export of a slide (or a region of it) to a tiled, pyramidal BigTIFF.
+//!
+//! The file holds one tiled RGB image per resolution, from the full resolution down to a single
+//! tile, each downsampled by two from the one before. This is the layout OpenSlide reads as a
+//! generic tiled TIFF, and QuPath as a pyramidal TIFF. The resolution tags are set from the microns
+//! per pixel of the slide.
+
+use super::{
+    grid_position, power_of_two_levels, read_level_tile, run_parallel, ExportError, Progress,
+};
+use crate::backend::SlideBackend;
+use crate::region::Region;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::{Rgb, RgbImage};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+// TIFF tags written.
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const X_RESOLUTION: u16 = 282;
+const Y_RESOLUTION: u16 = 283;
+const PLANAR_CONFIGURATION: u16 = 284;
+const RESOLUTION_UNIT: u16 = 296;
+const SOFTWARE: u16 = 305;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+
+// TIFF field types.
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const LONG8: u16 = 16;
+
+/// How the tiles are compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TiffCompression {
+    None,
+    /// Deflate (zlib) with a level from 0 to 9.
+    Deflate(u32),
+}
+
+impl TiffCompression {
+    fn tag_value(&self) -> u16 {
+        match self {
+            TiffCompression::None => 1,
+            TiffCompression::Deflate(_) => 8,
+        }
+    }
+}
+
+/// How a slide is written to a pyramidal TIFF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TiffExport {
+    /// Width and height of the tiles, a multiple of 16.
+    pub tile_size: u32,
+    pub compression: TiffCompression,
+    /// Number of threads reading and compressing tiles.
+    pub threads: usize,
+}
+
+impl Default for TiffExport {
+    fn default() -> Self {
+        TiffExport {
+            tile_size: 512,
+            compression: TiffCompression::Deflate(6),
+            threads: 4,
+        }
+    }
+}
+
+/// The tiles of one resolution, in the order they were written.
+struct LevelTiles {
+    offsets: Vec<u64>,
+    byte_counts: Vec<u64>,
+}
+
+/// The output file, shared by the threads writing tiles.
+struct Output {
+    file: BufWriter<File>,
+    position: u64,
+    levels: Vec<LevelTiles>,
+}
+
+impl TiffExport {
+    /// Write a level 0 `region` of the slide (the whole slide if `None`) to a BigTIFF at `path`.
+    pub fn run(
+        &self,
+        wsi: &dyn SlideBackend,
+        region: Option<Region>,
+        path: &Path,
+        progress: Progress,
+    ) -> Result<(), ExportError> {
+        if self.tile_size == 0 || !self.tile_size.is_multiple_of(16) {
+            return Err(ExportError::Invalid(
+                "tile size must be a positive multiple of 16".to_string(),
+            ));
+        }
+        if let TiffCompression::Deflate(level) = self.compression {
+            if level > 9 {
+                return Err(ExportError::Invalid(
+                    "compression level must be between 0 and 9".to_string(),
+                ));
+            }
+        }
+        let dimensions = wsi.level0_dimensions()?;
+        let region = match region {
+            Some(region) => region.clip(dimensions).ok_or_else(|| {
+                ExportError::Invalid(format!("{:?} lies outside the slide", region))
+            })?,
+            None => Region::new(0, 0, dimensions.0, dimensions.1),
+        };
+        let levels = power_of_two_levels((region.width, region.height), self.tile_size as u64);
+        self.write_pyramid(path, &levels, wsi.mpp(), progress, |level, origin, size| {
+            read_level_tile(wsi, region, levels[level], origin, size)
+        })
+    }
+
+    /// Write a pyramid with the given (width, height) of its levels, the first being the full
+    /// resolution of `mpp` microns per pixel. `read_tile(level, origin, size)` gives the pixels of
+    /// a tile.
+    fn write_pyramid<F>(
+        &self,
+        path: &Path,
+        levels: &[(u64, u64)],
+        mpp: Option<(f64, f64)>,
+        progress: Progress,
+        read_tile: F,
+    ) -> Result<(), ExportError>
+    where
+        F: Fn(usize, (u64, u64), (u64, u64)) -> Result<RgbImage, ExportError> + Sync,
+    {
+        let tile = self.tile_size as u64;
+        let grids: Vec<(u64, u64)> = levels
+            .iter()
+            .map(|(width, height)| (width.div_ceil(tile), height.div_ceil(tile)))
+            .collect();
+
+        let write_error = |err| ExportError::Write(path.into(), err);
+        let mut file = BufWriter::new(File::create(path).map_err(write_error)?);
+        // The offset of the first IFD is filled in when the tiles are written.
+        file.write_all(&bigtiff_header(0)).map_err(write_error)?;
+        let output = Mutex::new(Output {
+            file,
+            position: 16,
+            levels: grids
+                .iter()
+                .map(|(cols, rows)| LevelTiles {
+                    offsets: vec![0; (cols * rows) as usize],
+                    byte_counts: vec![0; (cols * rows) as usize],
+                })
+                .collect(),
+        });
+
+        let total = grids.iter().map(|(cols, rows)| cols * rows).sum();
+        run_parallel(total, self.threads, progress, |index| {
+            let (level, col, row) = grid_position(&grids, index).expect("index within tiles");
+            let (width, height) = levels[level];
+            let (x, y) = (col * tile, row * tile);
+            let size = (tile.min(width - x), tile.min(height - y));
+            let image = read_tile(level, (x, y), size)?;
+            let data = self
+                .compress(&pad_tile(&image, self.tile_size))
+                .map_err(write_error)?;
+
+            let mut output = output.lock().unwrap();
+            output.file.write_all(&data).map_err(write_error)?;
+            let offset = output.position;
+            output.position += data.len() as u64;
+            let tiles = &mut output.levels[level];
+            let index = (row * grids[level].0 + col) as usize;
+            tiles.offsets[index] = offset;
+            tiles.byte_counts[index] = data.len() as u64;
+            Ok(())
+        })?;
+
+        let Output {
+            mut file,
+            mut position,
+            levels: level_tiles,
+        } = output.into_inner().unwrap();
+        let first_ifd = position + position % 2;
+        for (level, tiles) in level_tiles.iter().enumerate() {
+            // IFDs start on a word boundary.
+            if position % 2 == 1 {
+                file.write_all(&[0]).map_err(write_error)?;
+                position += 1;
+            }
+            let (width, height) = levels[level];
+            let mut ifd = Ifd::new();
+            ifd.add_long(NEW_SUBFILE_TYPE, if level == 0 { 0 } else { 1 });
+            ifd.add_long(IMAGE_WIDTH, width as u32);
+            ifd.add_long(IMAGE_LENGTH, height as u32);
+            ifd.add_shorts(BITS_PER_SAMPLE, &[8, 8, 8]);
+            ifd.add_shorts(COMPRESSION, &[self.compression.tag_value()]);
+            // RGB
+            ifd.add_shorts(PHOTOMETRIC_INTERPRETATION, &[2]);
+            ifd.add_shorts(SAMPLES_PER_PIXEL, &[3]);
+            // Chunky (RGBRGB...)
+            ifd.add_shorts(PLANAR_CONFIGURATION, &[1]);
+            ifd.add_ascii(SOFTWARE, concat!("slidestream ", env!("CARGO_PKG_VERSION")));
+            ifd.add_long(TILE_WIDTH, self.tile_size);
+            ifd.add_long(TILE_LENGTH, self.tile_size);
+            ifd.add_long8s(TILE_OFFSETS, &tiles.offsets);
+            ifd.add_long8s(TILE_BYTE_COUNTS, &tiles.byte_counts);
+            if let Some((mpp_x, mpp_y)) = mpp {
+                let downsample = (
+                    levels[0].0 as f64 / width as f64,
+                    levels[0].1 as f64 / height as f64,
+                );
+                ifd.add_rational(X_RESOLUTION, pixels_per_cm(mpp_x * downsample.0));
+                ifd.add_rational(Y_RESOLUTION, pixels_per_cm(mpp_y * downsample.1));
+                // Centimeter
+                ifd.add_shorts(RESOLUTION_UNIT, &[3]);
+            }
+            let last = level + 1 == level_tiles.len();
+            let encoded = ifd.encode(position, last);
+            file.write_all(&encoded).map_err(write_error)?;
+            position += encoded.len() as u64;
+        }
+
+        file.seek(SeekFrom::Start(0)).map_err(write_error)?;
+        file.write_all(&bigtiff_header(first_ifd))
+            .map_err(write_error)?;
+        file.flush().map_err(write_error)
+    }
+
+    fn compress(&self, tile: &RgbImage) -> io::Result<Vec<u8>> {
+        match self.compression {
+            TiffCompression::None => Ok(tile.as_raw().clone()),
+            TiffCompression::Deflate(level) => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(tile.as_raw())?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// A little endian BigTIFF header pointing at the first IFD.
+fn bigtiff_header(first_ifd: u64) -> [u8; 16] {
+    let mut header = [0; 16];
+    header[..8].copy_from_slice(&[b'I', b'I', 43, 0, 8, 0, 0, 0]);
+    header[8..].copy_from_slice(&first_ifd.to_le_bytes());
+    header
+}
+
+/// Pad a tile on the right and bottom to a full tile with white pixels.
+fn pad_tile(image: &RgbImage, tile_size: u32) -> RgbImage {
+    if image.dimensions() == (tile_size, tile_size) {
+        return image.clone();
+    }
+    let mut tile = RgbImage::from_pixel(tile_size, tile_size, Rgb([255, 255, 255]));
+    image::imageops::replace(&mut tile, image, 0, 0);
+    tile
+}
+
+/// A resolution of `mpp` microns per pixel in pixels per centimeter, as a TIFF rational.
+fn pixels_per_cm(mpp: f64) -> (u32, u32) {
+    let denominator = 1000;
+    (
+        (10_000.0 / mpp * denominator as f64).round() as u32,
+        denominator,
+    )
+}
+
+/// A BigTIFF image file directory.
+struct Ifd {
+    /// Tag, field type, count and value bytes of every entry.
+    entries: Vec<(u16, u16, u64, Vec<u8>)>,
+}
+
+impl Ifd {
+    fn new() -> Ifd {
+        Ifd {
+            entries: Vec::new(),
+        }
+    }
+
+    fn add_shorts(&mut self, tag: u16, values: &[u16]) {
+        let bytes = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.entries.push((tag, SHORT, values.len() as u64, bytes));
+    }
+
+    fn add_long(&mut self, tag: u16, value: u32) {
+        self.entries
+            .push((tag, LONG, 1, value.to_le_bytes().to_vec()));
+    }
+
+    fn add_long8s(&mut self, tag: u16, values: &[u64]) {
+        let bytes = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.entries.push((tag, LONG8, values.len() as u64, bytes));
+    }
+
+    fn add_rational(&mut self, tag: u16, (numerator, denominator): (u32, u32)) {
+        let mut bytes = numerator.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&denominator.to_le_bytes());
+        self.entries.push((tag, RATIONAL, 1, bytes));
+    }
+
+    fn add_ascii(&mut self, tag: u16, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.entries.push((tag, ASCII, bytes.len() as u64, bytes));
+    }
+
+    /// Encode the directory to be written at `offset`, followed by the values that do not fit in
+    /// their entry. Unless this is the `last` directory, the next one is expected right after it.
+    fn encode(mut self, offset: u64, last: bool) -> Vec<u8> {
+        self.entries.sort_by_key(|entry| entry.0);
+        let table_size = 8 + 20 * self.entries.len() as u64 + 8;
+        let mut table = Vec::with_capacity(table_size as usize);
+        let mut values = Vec::new();
+        table.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        for (tag, field_type, count, bytes) in &self.entries {
+            table.extend_from_slice(&tag.to_le_bytes());
+            table.extend_from_slice(&field_type.to_le_bytes());
+            table.extend_from_slice(&count.to_le_bytes());
+            if bytes.len() <= 8 {
+                let mut inline = [0; 8];
+                inline[..bytes.len()].copy_from_slice(bytes);
+                table.extend_from_slice(&inline);
+            } else {
+                let value_offset = offset + table_size + values.len() as u64;
+                table.extend_from_slice(&value_offset.to_le_bytes());
+                values.extend_from_slice(bytes);
+                // Values start on a word boundary.
+                if values.len() % 2 == 1 {
+                    values.push(0);
+                }
+            }
+        }
+        let next = if last {
+            0
+        } else {
+            offset + table_size + values.len() as u64
+        };
+        table.extend_from_slice(&next.to_le_bytes());
+        table.extend_from_slice(&values);
+        table
+    }
+}
+
+#[test]
+fn test_encode_ifd() {
+    let mut ifd = Ifd::new();
+    ifd.add_long8s(TILE_OFFSETS, &[16, 32]);
+    ifd.add_long(IMAGE_WIDTH, 1000);
+    let encoded = ifd.encode(100, false);
+    // Count, two entries, next offset and the 16 bytes of tile offsets.
+    assert_eq!(encoded.len(), 8 + 2 * 20 + 8 + 16);
+    assert_eq!(encoded[..8], 2u64.to_le_bytes());
+    // Entries are sorted by tag.
+    assert_eq!(encoded[8..10], IMAGE_WIDTH.to_le_bytes());
+    assert_eq!(encoded[20..24], 1000u32.to_le_bytes());
+    assert_eq!(encoded[28..30], TILE_OFFSETS.to_le_bytes());
+    // The tile offsets follow the table, then the next directory.
+    assert_eq!(encoded[40..48], (100u64 + 56).to_le_bytes());
+    assert_eq!(encoded[48..56], (100u64 + 56 + 16).to_le_bytes());
+    assert_eq!(encoded[56..64], 16u64.to_le_bytes());
+    assert_eq!(Ifd::new().encode(0, true)[8..], [0; 8]);
+}
+
+#[test]
+fn test_pixels_per_cm() {
+    assert_eq!(pixels_per_cm(0.25), (40_000_000, 1000));
+    assert_eq!(pixels_per_cm(0.5), (20_000_000, 1000));
+}
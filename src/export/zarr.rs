@@ -0,0 +1,331 @@
+//! Export of a slide (or a region of it) to an OME-Zarr multiscale image.
+//!
+//! The image is written as a Zarr v2 group following OME-NGFF 0.4
+//! (<https://ngff.openmicroscopy.org/0.4/>), which napari, dask and other analysis tools read.
+//! Every resolution is a `(c, y, x)` array of RGB values, chunked by `chunk_size` pixels and
+//! compressed with zlib. The physical pixel size is taken from the microns per pixel of the slide.
+
+use super::{
+    create_dir, grid_position, power_of_two_levels, read_level_tile, run_parallel, write_file,
+    ExportError, Progress,
+};
+use crate::backend::SlideBackend;
+use crate::region::Region;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::Path;
+
+/// Value of parts of a chunk outside the image, and of missing chunks. Slides are scanned on a
+/// white background.
+const FILL_VALUE: u8 = 255;
+
+/// Which resolutions are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZarrLevels {
+    /// The levels stored in the slide.
+    Slide,
+    /// Levels downsampled by powers of two, down to a single chunk.
+    PowerOfTwo,
+}
+
+/// How a slide is written to OME-Zarr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZarrExport {
+    pub levels: ZarrLevels,
+    /// Width and height of the chunks.
+    pub chunk_size: u32,
+    /// zlib compression level (0-9).
+    pub compression: u32,
+    /// Number of threads reading and writing chunks.
+    pub threads: usize,
+}
+
+impl Default for ZarrExport {
+    fn default() -> Self {
+        ZarrExport {
+            levels: ZarrLevels::Slide,
+            chunk_size: 512,
+            compression: 6,
+            threads: 4,
+        }
+    }
+}
+
+impl ZarrExport {
+    /// Write a level 0 `region` of the slide (the whole slide if `None`) as an OME-Zarr group at
+    /// `path`, named `name` in the metadata.
+    pub fn run(
+        &self,
+        wsi: &dyn SlideBackend,
+        region: Option<Region>,
+        path: &Path,
+        name: &str,
+        progress: Progress,
+    ) -> Result<(), ExportError> {
+        if self.chunk_size == 0 {
+            return Err(ExportError::Invalid(
+                "chunk size must be at least 1".to_string(),
+            ));
+        }
+        if self.compression > 9 {
+            return Err(ExportError::Invalid(
+                "compression level must be between 0 and 9".to_string(),
+            ));
+        }
+        let dimensions = wsi.level0_dimensions()?;
+        let region = match region {
+            Some(region) => region.clip(dimensions).ok_or_else(|| {
+                ExportError::Invalid(format!("{:?} lies outside the slide", region))
+            })?,
+            None => Region::new(0, 0, dimensions.0, dimensions.1),
+        };
+        let mut downsamples = Vec::new();
+        for level in 0..wsi.level_count()? {
+            downsamples.push(wsi.level_downsample(level)?);
+        }
+        let levels = plan_levels(
+            self.levels,
+            (region.width, region.height),
+            &downsamples,
+            self.chunk_size as u64,
+        );
+
+        // Metadata first, and the directories of every row of chunks.
+        create_dir(path)?;
+        write_json(&path.join(".zgroup"), &json!({ "zarr_format": 2 }))?;
+        write_json(
+            &path.join(".zattrs"),
+            &multiscales(name, region, &levels, wsi.mpp()),
+        )?;
+        let chunk = self.chunk_size as u64;
+        let mut chunk_counts = Vec::new();
+        for (level, (width, height)) in levels.iter().enumerate() {
+            let level_dir = path.join(level.to_string());
+            create_dir(&level_dir)?;
+            write_json(
+                &level_dir.join(".zarray"),
+                &json!({
+                    "zarr_format": 2,
+                    "shape": [3, height, width],
+                    "chunks": [3, chunk, chunk],
+                    "dtype": "|u1",
+                    "compressor": { "id": "zlib", "level": self.compression },
+                    "fill_value": FILL_VALUE,
+                    "order": "C",
+                    "filters": null,
+                    "dimension_separator": "/",
+                }),
+            )?;
+            let counts = (width.div_ceil(chunk), height.div_ceil(chunk));
+            for row in 0..counts.1 {
+                create_dir(&level_dir.join("0").join(row.to_string()))?;
+            }
+            chunk_counts.push(counts);
+        }
+
+        let total = chunk_counts.iter().map(|(cols, rows)| cols * rows).sum();
+        run_parallel(total, self.threads, progress, |index| {
+            let (level, col, row) =
+                grid_position(&chunk_counts, index).expect("index within chunks");
+            let (width, height) = levels[level];
+            let (x, y) = (col * chunk, row * chunk);
+            let size = (chunk.min(width - x), chunk.min(height - y));
+            let image = read_level_tile(wsi, region, levels[level], (x, y), size)?;
+            let data = planar_chunk(&image, self.chunk_size);
+
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(self.compression));
+            let chunk_path = path
+                .join(level.to_string())
+                .join("0")
+                .join(row.to_string())
+                .join(col.to_string());
+            let compressed = encoder
+                .write_all(&data)
+                .and_then(|_| encoder.finish())
+                .map_err(|err| ExportError::Write(chunk_path.clone(), err))?;
+            write_file(&chunk_path, &compressed)
+        })
+    }
+}
+
+fn write_json(path: &Path, value: &Value) -> Result<(), ExportError> {
+    write_file(path, value.to_string().as_bytes())
+}
+
+/// The (width, height) of every resolution of a region of `(width, height)` level 0 pixels.
+///
+/// `downsamples` are those of the slide levels. Power of two levels continue until the image fits
+/// in one chunk.
+fn plan_levels(
+    levels: ZarrLevels,
+    (width, height): (u64, u64),
+    downsamples: &[f64],
+    chunk_size: u64,
+) -> Vec<(u64, u64)> {
+    let scaled = |downsample: f64| {
+        (
+            ((width as f64 / downsample).round() as u64).max(1),
+            ((height as f64 / downsample).round() as u64).max(1),
+        )
+    };
+    match levels {
+        ZarrLevels::Slide => {
+            let mut dimensions: Vec<(u64, u64)> = Vec::new();
+            for downsample in downsamples {
+                let size = scaled(*downsample);
+                // Tiny regions can round to the same size at several levels.
+                if dimensions.last() != Some(&size) {
+                    dimensions.push(size);
+                }
+            }
+            dimensions
+        }
+        ZarrLevels::PowerOfTwo => power_of_two_levels((width, height), chunk_size),
+    }
+}
+
+/// The NGFF `multiscales` and `omero` metadata of the image.
+///
+/// Without a known pixel size, the scales are in level 0 pixels.
+fn multiscales(
+    name: &str,
+    region: Region,
+    levels: &[(u64, u64)],
+    mpp: Option<(f64, f64)>,
+) -> Value {
+    let (pixel_x, pixel_y) = mpp.unwrap_or((1.0, 1.0));
+    let space_axis = |axis: &str| match mpp {
+        Some(_) => json!({ "name": axis, "type": "space", "unit": "micrometer" }),
+        None => json!({ "name": axis, "type": "space" }),
+    };
+    let datasets: Vec<Value> = levels
+        .iter()
+        .enumerate()
+        .map(|(level, (width, height))| {
+            let scale = json!({
+                "type": "scale",
+                "scale": [
+                    1.0,
+                    pixel_y * region.height as f64 / *height as f64,
+                    pixel_x * region.width as f64 / *width as f64,
+                ],
+            });
+            let transformations = if region.x == 0 && region.y == 0 {
+                vec![scale]
+            } else {
+                let translation = json!({
+                    "type": "translation",
+                    "translation": [0.0, pixel_y * region.y as f64, pixel_x * region.x as f64],
+                });
+                vec![scale, translation]
+            };
+            json!({ "path": level.to_string(), "coordinateTransformations": transformations })
+        })
+        .collect();
+    let channel = |label: &str, color: &str| {
+        json!({
+            "label": label,
+            "color": color,
+            "active": true,
+            "window": { "min": 0, "max": 255, "start": 0, "end": 255 },
+        })
+    };
+    json!({
+        "multiscales": [{
+            "version": "0.4",
+            "name": name,
+            "axes": [{ "name": "c", "type": "channel" }, space_axis("y"), space_axis("x")],
+            "datasets": datasets,
+        }],
+        "omero": {
+            "name": name,
+            "version": "0.4",
+            "channels": [
+                channel("red", "FF0000"),
+                channel("green", "00FF00"),
+                channel("blue", "0000FF"),
+            ],
+            "rdefs": { "model": "color" },
+        },
+    })
+}
+
+/// An RGB image as a full `(3, chunk_size, chunk_size)` chunk in C order, padded with
+/// `FILL_VALUE`.
+fn planar_chunk(image: &image::RgbImage, chunk_size: u32) -> Vec<u8> {
+    let plane = (chunk_size * chunk_size) as usize;
+    let mut data = vec![FILL_VALUE; 3 * plane];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let offset = (y * chunk_size + x) as usize;
+        for (channel, value) in pixel.0.iter().enumerate() {
+            data[channel * plane + offset] = *value;
+        }
+    }
+    data
+}
+
+#[test]
+fn test_plan_levels() {
+    let downsamples = [1.0, 4.0, 16.000_3];
+    assert_eq!(
+        plan_levels(ZarrLevels::Slide, (10_000, 5_000), &downsamples, 512),
+        vec![(10_000, 5_000), (2_500, 1_250), (625, 312)]
+    );
+    assert_eq!(
+        plan_levels(ZarrLevels::Slide, (2, 1), &downsamples, 512),
+        vec![(2, 1), (1, 1)]
+    );
+    assert_eq!(
+        plan_levels(ZarrLevels::PowerOfTwo, (2_000, 1_000), &downsamples, 512),
+        vec![(2_000, 1_000), (1_000, 500), (500, 250)]
+    );
+    assert_eq!(
+        plan_levels(ZarrLevels::PowerOfTwo, (100, 100), &downsamples, 512),
+        vec![(100, 100)]
+    );
+}
+
+#[test]
+fn test_multiscales() {
+    let region = Region::new(1_000, 0, 2_000, 1_000);
+    let value = multiscales(
+        "slide",
+        region,
+        &[(2_000, 1_000), (500, 250)],
+        Some((0.25, 0.5)),
+    );
+    let datasets = &value["multiscales"][0]["datasets"];
+    assert_eq!(datasets[1]["path"], "1");
+    assert_eq!(
+        datasets[1]["coordinateTransformations"][0]["scale"],
+        json!([1.0, 2.0, 1.0])
+    );
+    assert_eq!(
+        datasets[0]["coordinateTransformations"][1]["translation"],
+        json!([0.0, 0.0, 250.0])
+    );
+    assert_eq!(value["multiscales"][0]["axes"][2]["unit"], "micrometer");
+
+    let value = multiscales("slide", Region::new(0, 0, 10, 10), &[(10, 10)], None);
+    let dataset = &value["multiscales"][0]["datasets"][0];
+    assert_eq!(
+        dataset["coordinateTransformations"]
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(value["multiscales"][0]["axes"][1].get("unit").is_none());
+}
+
+#[test]
+fn test_planar_chunk() {
+    let image = image::RgbImage::from_fn(2, 1, |x, _| image::Rgb([x as u8, 10, 20]));
+    let data = planar_chunk(&image, 2);
+    assert_eq!(
+        data,
+        vec![0, 1, 255, 255, 10, 10, 255, 255, 20, 20, 255, 255]
+    );
+}
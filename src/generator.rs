@@ -1,11 +1,17 @@
 extern crate image;
 
-mod openslide;
-
-use image::DynamicImage;
+pub mod openslide;
+pub mod resample;
+
+use crate::backend::{self, SlideBackend};
+use image::{DynamicImage, ImageOutputFormat};
+use openslide::OpenSlideError;
+use resample::ResampleFilter;
+use serde::Deserialize;
 use serde_json::json;
-use std::error::Error;
+use std::fmt;
 use std::ops::Div;
+use std::sync::Arc;
 use std::{path::Path, vec};
 
 #[derive(PartialEq, Debug)]
@@ -17,13 +23,238 @@ struct TileInfo {
 }
 
 type Tile = DynamicImage;
+
+/// XML namespace of Deep Zoom image descriptors.
+const DZI_NAMESPACE: &str = "http://schemas.microsoft.com/deepzoom/2008";
+
+/// Errors returned by a `DeepZoomGenerator`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GeneratorError {
+    /// The options do not describe a usable pyramid.
+    InvalidOptions(String),
+    /// The requested Deep Zoom level does not exist.
+    LevelOutOfRange { level: u64, level_count: u64 },
+    /// The requested tile does not exist in its Deep Zoom level.
+    TileOutOfRange {
+        level: u64,
+        location: (u64, u64),
+        tiles: (u64, u64),
+    },
+    /// The slide could not be opened or read.
+    Slide(OpenSlideError),
+    /// The tile could not be encoded.
+    Encode(String),
+}
+
+impl fmt::Display for GeneratorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeneratorError::InvalidOptions(msg) => write!(f, "Invalid options: {}", msg),
+            GeneratorError::LevelOutOfRange { level, level_count } => write!(
+                f,
+                "Deep Zoom level {} does not exist, the slide has {} levels",
+                level, level_count
+            ),
+            GeneratorError::TileOutOfRange {
+                level,
+                location,
+                tiles,
+            } => write!(
+                f,
+                "Tile {:?} does not exist, level {} has {:?} tiles",
+                location, level, tiles
+            ),
+            GeneratorError::Slide(err) => write!(f, "{}", err),
+            GeneratorError::Encode(msg) => write!(f, "Encoding failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for GeneratorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GeneratorError::Slide(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<OpenSlideError> for GeneratorError {
+    fn from(err: OpenSlideError) -> Self {
+        GeneratorError::Slide(err)
+    }
+}
+
+impl From<image::ImageError> for GeneratorError {
+    fn from(err: image::ImageError) -> Self {
+        GeneratorError::Encode(err.to_string())
+    }
+}
+
+/// Options for the Deep Zoom pyramid generated by a `DeepZoomGenerator`.
+///
+/// Built from the defaults (254 pixel tiles with a 1 pixel overlap, as OpenSlide's Python
+/// `DeepZoomGenerator` uses) with the builder methods:
+///
+/// ```
+/// use slidestream::generator::DeepZoomGeneratorOptions;
+///
+/// let options = DeepZoomGeneratorOptions::default().tile_size(224).overlap(0);
+/// assert!(options.validate().is_ok());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DeepZoomGeneratorOptions {
+    tile_size: u64,
+    overlap: u64,
+    limit_bounds: bool,
+    resample: ResampleFilter,
+}
+
+impl Default for DeepZoomGeneratorOptions {
+    fn default() -> Self {
+        DeepZoomGeneratorOptions {
+            tile_size: 254,
+            overlap: 1,
+            limit_bounds: false,
+            resample: ResampleFilter::Area,
+        }
+    }
+}
+
+impl DeepZoomGeneratorOptions {
+    /// Largest tile size accepted, including the overlap on both sides.
+    pub const MAX_TILE_SIZE: u64 = 4096;
+
+    /// Width and height of the tiles, excluding the overlap.
+    pub fn tile_size(mut self, tile_size: u64) -> Self {
+        self.tile_size = tile_size;
+        self
+    }
+
+    /// Number of extra pixels to add to each interior edge of a tile.
+    pub fn overlap(mut self, overlap: u64) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// Render only the non-empty area of the slide, as given by the `openslide.bounds-*`
+    /// properties, rather than the full level 0 dimensions.
+    ///
+    /// This avoids large empty margins for formats such as MIRAX and Hamamatsu. Slides without
+    /// bounds properties are rendered in full.
+    pub fn limit_bounds(mut self, limit_bounds: bool) -> Self {
+        self.limit_bounds = limit_bounds;
+        self
+    }
+
+    /// Filter used to scale tiles read from the slide to their size in the Deep Zoom level.
+    pub fn resample(mut self, resample: ResampleFilter) -> Self {
+        self.resample = resample;
+        self
+    }
+
+    pub fn get_tile_size(&self) -> u64 {
+        self.tile_size
+    }
+
+    pub fn get_overlap(&self) -> u64 {
+        self.overlap
+    }
+
+    pub fn get_limit_bounds(&self) -> bool {
+        self.limit_bounds
+    }
+
+    pub fn get_resample(&self) -> ResampleFilter {
+        self.resample
+    }
+
+    /// Check that the options describe a usable pyramid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tile_size == 0 {
+            return Err("tile_size must be at least 1".to_string());
+        }
+        if self.overlap >= self.tile_size {
+            return Err(format!(
+                "overlap ({}) must be smaller than tile_size ({})",
+                self.overlap, self.tile_size
+            ));
+        }
+        if self.tile_size + 2 * self.overlap > Self::MAX_TILE_SIZE {
+            return Err(format!(
+                "tile_size plus overlap on both sides ({}) exceeds the maximum of {}",
+                self.tile_size + 2 * self.overlap,
+                Self::MAX_TILE_SIZE
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The image formats a tile can be encoded in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileFormat {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl TileFormat {
+    /// Parse a file extension (e.g. `jpg` from `1_2.jpg`) into a format.
+    pub fn from_extension(extension: &str) -> Option<TileFormat> {
+        match extension {
+            "jpg" | "jpeg" => Some(TileFormat::Jpeg),
+            "png" => Some(TileFormat::Png),
+            "webp" => Some(TileFormat::WebP),
+            _ => None,
+        }
+    }
+
+    /// The file extension of the format, as advertised in the DZI.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TileFormat::Jpeg => "jpg",
+            TileFormat::Png => "png",
+            TileFormat::WebP => "webp",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            TileFormat::Jpeg => "image/jpeg",
+            TileFormat::Png => "image/png",
+            TileFormat::WebP => "image/webp",
+        }
+    }
+
+    /// Encode a tile in this format.
+    ///
+    /// The quality (1-100) applies to the lossy formats (JPEG and WebP). PNG is always lossless.
+    pub fn encode(&self, tile: &Tile, quality: u8) -> Result<Vec<u8>, GeneratorError> {
+        let mut buffer = Vec::new();
+        match self {
+            TileFormat::Jpeg => tile.write_to(&mut buffer, ImageOutputFormat::Jpeg(quality))?,
+            TileFormat::Png => tile.write_to(&mut buffer, ImageOutputFormat::Png)?,
+            TileFormat::WebP => {
+                let rgba = tile.to_rgba8();
+                let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                    .encode_simple(false, quality as f32)
+                    .map_err(|err| GeneratorError::Encode(format!("WebP: {:?}", err)))?;
+                buffer.extend_from_slice(&encoded);
+            }
+        }
+        Ok(buffer)
+    }
+}
 pub struct DeepZoomGenerator {
     // We have four coordinate planes:
     // - Row and column of the tile within the Deep Zoom level (t_)
     // - Pixel coordinates within the Deep Zoom level (z_)
     // - Pixel coordinates within the slide level (l_)
     // - Pixel coordinates within slide level 0 (l0_)
-    wsi: openslide::OpenSlide,
+    wsi: Arc<dyn SlideBackend>,
     l0_dimensions: (u64, u64),
     level_dimensions: Vec<(u64, u64)>,
     z_dimensions: Vec<(u64, u64)>,
@@ -32,28 +263,83 @@ pub struct DeepZoomGenerator {
     _l_z_downsamples: Vec<f64>,
     _l0_offset: (u64, u64),
     l0_l_downsamples: Vec<f64>,
+    /// The slide level with the same pixels as each Deep Zoom level, if there is one.
+    stored_levels: Vec<Option<u32>>,
     tile_size: u64,
     overlap: u64,
+    resample: ResampleFilter,
+}
+
+/// Restrict the slide level dimensions to the bounds (x, y, width, height) of the non-empty area
+/// of a slide, as given in level 0 coordinates by the `openslide.bounds-*` properties.
+///
+/// Returns the level 0 offset of the bounds and the dimensions of the bounds at every level. Bounds
+/// that are not known fall back to the full slide.
+fn limit_to_bounds(
+    level_dimensions: &[(u64, u64)],
+    bounds: (Option<u64>, Option<u64>, Option<u64>, Option<u64>),
+) -> ((u64, u64), Vec<(u64, u64)>) {
+    let (x, y, width, height) = bounds;
+    let l0_offset = (x.unwrap_or(0), y.unwrap_or(0));
+
+    // Scale factor from the full slide to the bounds, in each axis.
+    let (l0_w, l0_h) = level_dimensions[0];
+    let size_scale = (
+        width.unwrap_or(l0_w) as f64 / l0_w as f64,
+        height.unwrap_or(l0_h) as f64 / l0_h as f64,
+    );
+    let dimensions = level_dimensions
+        .iter()
+        .map(|(l_w, l_h)| {
+            (
+                (*l_w as f64 * size_scale.0).ceil() as u64,
+                (*l_h as f64 * size_scale.1).ceil() as u64,
+            )
+        })
+        .collect();
+    (l0_offset, dimensions)
 }
 
 impl DeepZoomGenerator {
-    pub fn new(wsi_path: &Path) -> Result<DeepZoomGenerator, Box<dyn Error>> {
-        // TODO: make these parameters configurable.
-        let tile_size: u64 = 254;
-        let overlap: u64 = 1;
-        let _l0_offset: (u64, u64) = (0, 0);
-
-        let wsi = openslide::OpenSlide::new(wsi_path)?;
-        let l0_dimensions = wsi.get_level0_dimensions()?; // (width, height)
-        let level_count = wsi.get_level_count()?;
+    /// Open the slide at `wsi_path` and generate a pyramid with the default options.
+    pub fn new(wsi_path: &Path) -> Result<DeepZoomGenerator, GeneratorError> {
+        let options = DeepZoomGeneratorOptions::default();
+        let wsi = backend::open(wsi_path, options.resample)?;
+        DeepZoomGenerator::from_slide(wsi, options)
+    }
+
+    /// Generate a pyramid for an already opened slide.
+    ///
+    /// Several generators (e.g. with different tile sizes) can share the same slide.
+    pub fn from_slide(
+        wsi: Arc<dyn SlideBackend>,
+        options: DeepZoomGeneratorOptions,
+    ) -> Result<DeepZoomGenerator, GeneratorError> {
+        options.validate().map_err(GeneratorError::InvalidOptions)?;
+        let tile_size = options.tile_size;
+        let overlap = options.overlap;
+
+        let level_count = wsi.level_count()?;
         let mut level_dimensions: Vec<(u64, u64)> = Vec::new();
         for lvl in 0..level_count {
-            level_dimensions.push(wsi.get_level_dimensions(lvl)?)
+            level_dimensions.push(wsi.level_dimensions(lvl)?)
+        }
+        if level_dimensions.is_empty() {
+            return Err(OpenSlideError::Read("Slide has no levels".to_string()).into());
         }
 
+        // Restrict the pyramid to the non-empty area of the slide, if requested.
+        let mut _l0_offset: (u64, u64) = (0, 0);
+        if options.limit_bounds {
+            let (offset, dimensions) = limit_to_bounds(&level_dimensions, wsi.bounds());
+            _l0_offset = offset;
+            level_dimensions = dimensions;
+        }
+        let l0_dimensions = level_dimensions[0]; // (width, height)
+
         let mut l0_l_downsamples: Vec<f64> = Vec::new();
         for lvl in 0..level_count {
-            l0_l_downsamples.push(wsi.get_level_downsample(lvl)?);
+            l0_l_downsamples.push(wsi.level_downsample(lvl)?);
         }
 
         // Derive all possible Deep Zoom levels.
@@ -90,7 +376,7 @@ impl DeepZoomGenerator {
         // Preferred slide levels for each Deep Zoom level
         let mut _slide_from_dz_level: Vec<u32> = Vec::new();
         for lvl in &l0_z_downsamples {
-            _slide_from_dz_level.push(wsi.get_best_level_for_downsample(*lvl)?);
+            _slide_from_dz_level.push(wsi.best_level_for_downsample(*lvl as f64)?);
         }
 
         // Piecewise downsamples
@@ -99,10 +385,24 @@ impl DeepZoomGenerator {
             // TODO: using array indexing; assert assumptions about array size
             let slide_level = _slide_from_dz_level[dz_level as usize];
             let ds = (l0_z_downsamples[dz_level as usize] as f64)
-                .div(wsi.get_level_downsample(slide_level)?);
+                .div(wsi.level_downsample(slide_level)?);
             _l_z_downsamples.push(ds);
         }
 
+        // Slide levels are not always exact halvings, so a level within a pixel of a Deep Zoom
+        // level counts as the same. The pixels only line up without an offset.
+        let stored_levels = z_dimensions
+            .iter()
+            .map(|(zw, zh)| {
+                let level = level_dimensions
+                    .iter()
+                    .position(|(lw, lh)| lw.abs_diff(*zw) <= 1 && lh.abs_diff(*zh) <= 1);
+                level
+                    .filter(|_| _l0_offset == (0, 0))
+                    .map(|level| level as u32)
+            })
+            .collect();
+
         Ok(DeepZoomGenerator {
             wsi,
             l0_dimensions,
@@ -113,17 +413,60 @@ impl DeepZoomGenerator {
             _l_z_downsamples,
             _l0_offset,
             l0_l_downsamples,
+            stored_levels,
             tile_size,
             overlap,
+            resample: options.resample,
         })
     }
 
-    pub fn get_dzi(&self) -> String {
+    /// The slide this generator produces tiles for.
+    pub fn slide(&self) -> &dyn SlideBackend {
+        self.wsi.as_ref()
+    }
+
+    /// Number of Deep Zoom levels in the pyramid.
+    pub fn level_count(&self) -> u64 {
+        self.z_dimensions.len() as u64
+    }
+
+    /// Number of tiles (columns, rows) in each Deep Zoom level.
+    pub fn level_tiles(&self) -> &[(u64, u64)] {
+        &self.t_dimensions
+    }
+
+    /// Total number of tiles in the pyramid.
+    pub fn tile_count(&self) -> u64 {
+        self.t_dimensions
+            .iter()
+            .map(|(cols, rows)| cols * rows)
+            .sum()
+    }
+
+    /// Get the DZI descriptor as XML, advertising tiles in the given format.
+    pub fn get_dzi(&self, format: TileFormat) -> String {
+        let (w, h) = self.l0_dimensions;
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <Image xmlns=\"{}\" Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\
+             <Size Width=\"{}\" Height=\"{}\"/>\
+             </Image>\n",
+            DZI_NAMESPACE,
+            format.extension(),
+            self.overlap,
+            self.tile_size,
+            w,
+            h
+        )
+    }
+
+    /// Get the DZI descriptor in the JSON form understood by OpenSeadragon.
+    pub fn get_dzi_json(&self, format: TileFormat) -> String {
         let (w, h) = self.l0_dimensions;
         let data = json!({
             "Image": {
-                "xmlns":    "http://schemas.microsoft.com/deepzoom/2008",
-                "Format":   "jpg",
+                "xmlns":    DZI_NAMESPACE,
+                "Format":   format.extension(),
                 "Overlap":  self.overlap,
                 "TileSize": self.tile_size,
                 "Size": {
@@ -135,20 +478,24 @@ impl DeepZoomGenerator {
         data.to_string()
     }
 
-    fn get_tile_info(&self, dz_level: u64, t_location: (u64, u64)) -> Result<TileInfo, String> {
+    fn get_tile_info(
+        &self,
+        dz_level: u64,
+        t_location: (u64, u64),
+    ) -> Result<TileInfo, GeneratorError> {
         if dz_level >= self.z_dimensions.len() as u64 {
-            return Err(format!(
-                "dz_level {} exceeds number of z-dimensions in slide ({})",
-                dz_level,
-                self.z_dimensions.len()
-            ));
+            return Err(GeneratorError::LevelOutOfRange {
+                level: dz_level,
+                level_count: self.z_dimensions.len() as u64,
+            });
         }
         let t_lim = self.t_dimensions[dz_level as usize];
         if t_location.0 >= t_lim.0 || t_location.1 >= t_lim.1 {
-            return Err(format!(
-                "t_location {:?} exceeds t_lim of slide ({:?})",
-                t_location, t_lim
-            ));
+            return Err(GeneratorError::TileOutOfRange {
+                level: dz_level,
+                location: t_location,
+                tiles: t_lim,
+            });
         }
 
         // Get preferred slide level
@@ -156,16 +503,8 @@ impl DeepZoomGenerator {
 
         // Calculate top/left and bottom/right overlap
         let z_overlap_tl = (
-            if t_location.0 != 0 {
-                self.overlap
-            } else {
-                0
-            },
-            if t_location.1 != 0 {
-                self.overlap
-            } else {
-                0
-            },
+            if t_location.0 != 0 { self.overlap } else { 0 },
+            if t_location.1 != 0 { self.overlap } else { 0 },
         );
         let z_overlap_br = (
             if t_location.0 != (t_lim.0 - 1) {
@@ -228,30 +567,51 @@ impl DeepZoomGenerator {
         })
     }
 
-    pub fn get_tile(&self, level: u64, col: u64, row: u64) -> Result<Tile, Box<dyn Error>> {
+    pub fn get_tile(&self, level: u64, col: u64, row: u64) -> Result<Tile, GeneratorError> {
         let tile_info = self.get_tile_info(level, (col, row))?;
 
-        // Note that the rust openslide bindings read_region expects (row,col) and not (col,row) like the C & python impl.
         let tile = self.wsi.read_region(
-            tile_info.l0_location.1,
-            tile_info.l0_location.0,
-            tile_info.slide_level,
-            // Note that the rust openslide bindings
-            // read_region expects (height, width) and not (width,height) like the C & python impl.
-            tile_info.l_size.1,
-            tile_info.l_size.0,
+            tile_info.l0_location,
+            tile_info.slide_level as u32,
+            tile_info.l_size,
         )?;
 
         // Scale the tile to the correct size
         let (desired_w, desired_h) = tile_info.z_size;
         let (w, h) = tile.dimensions();
         if (desired_w as u32) != w || (desired_h as u32) != h {
-            // TODO: revist interpolation method used. May be able to speed this up?
-            let resized = image::imageops::thumbnail(&tile, desired_w as u32, desired_h as u32);
+            let resized =
+                resample::resize(&tile, desired_w as u32, desired_h as u32, self.resample);
             return Ok(DynamicImage::ImageRgba8(resized));
         }
         Ok(DynamicImage::ImageRgba8(tile))
     }
+
+    /// The tile as a JPEG stored in the slide, if there is one with exactly its pixels.
+    ///
+    /// This is the case for tiles without overlap in Deep Zoom levels that are also slide levels,
+    /// when the tile size is that of the slide. Other tiles return `None`, and have to be rendered
+    /// with `get_tile`.
+    pub fn get_jpeg_tile(
+        &self,
+        level: u64,
+        col: u64,
+        row: u64,
+    ) -> Result<Option<Vec<u8>>, GeneratorError> {
+        let tile_info = self.get_tile_info(level, (col, row))?;
+        let slide_level = match self.stored_levels[level as usize] {
+            Some(slide_level) => slide_level,
+            None => return Ok(None),
+        };
+        let overlap = |index: u64| if index != 0 { self.overlap } else { 0 };
+        let location = (
+            self.tile_size * col - overlap(col),
+            self.tile_size * row - overlap(row),
+        );
+        Ok(self
+            .wsi
+            .read_jpeg_tile(slide_level, location, tile_info.z_size)?)
+    }
 }
 
 #[test]
@@ -382,10 +742,166 @@ fn test_get_tile_out_of_bounds() {
     let filename = Path::new("demodata/example.svs");
     let g = DeepZoomGenerator::new(filename).unwrap();
     let res = g.get_tile(12, 5, 11);
-    match res {
-        Ok(_val) => panic!("Should error out"),
-        Err(_e) => {
-            // TODO: use a more sensible error type and check the message contained within.
+    assert_eq!(
+        res.err(),
+        Some(GeneratorError::TileOutOfRange {
+            level: 12,
+            location: (5, 11),
+            tiles: (14, 11),
+        })
+    );
+    assert!(matches!(
+        g.get_tile(18, 0, 0),
+        Err(GeneratorError::LevelOutOfRange { level: 18, .. })
+    ));
+}
+
+#[test]
+fn test_image_pyramid() {
+    use crate::backend::ImageFile;
+    use image::GenericImageView;
+    let image = image::RgbaImage::from_fn(1000, 600, |x, y| {
+        image::Rgba([(x % 256) as u8, (y % 256) as u8, 0, 255])
+    });
+    let g = DeepZoomGenerator::from_slide(
+        Arc::new(ImageFile::from_image(image, ResampleFilter::Area)),
+        DeepZoomGeneratorOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(g.level_count(), 11);
+    assert_eq!(g.level_tiles()[0], (1, 1));
+    assert_eq!(g.level_tiles()[10], (4, 3));
+    assert_eq!(g.tile_count(), 25);
+
+    // Interior tiles have the overlap on both sides, edge tiles are smaller.
+    let tile = g.get_tile(10, 1, 0).unwrap();
+    assert_eq!(tile.dimensions(), (256, 255));
+    assert_eq!(tile.get_pixel(0, 0).0, [253, 0, 0, 255]);
+    assert_eq!(g.get_tile(10, 3, 2).unwrap().dimensions(), (239, 93));
+    // Read from the first downsampled level of the image.
+    assert_eq!(g.get_tile(9, 0, 0).unwrap().dimensions(), (255, 255));
+    assert_eq!(g.get_tile(0, 0, 0).unwrap().dimensions(), (1, 1));
+    assert!(matches!(
+        g.get_tile(10, 4, 0),
+        Err(GeneratorError::TileOutOfRange { .. })
+    ));
+}
+
+#[test]
+fn test_tile_format_encode() {
+    let tile = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+        8,
+        4,
+        image::Rgba([200, 100, 50, 255]),
+    ));
+    for format in [TileFormat::Jpeg, TileFormat::Png, TileFormat::WebP] {
+        assert_eq!(TileFormat::from_extension(format.extension()), Some(format));
+        let encoded = format.encode(&tile, 80).unwrap();
+        let decoded = match format {
+            // The image crate can not decode lossy WebP, so only check the RIFF container.
+            TileFormat::WebP => {
+                assert_eq!(&encoded[0..4], b"RIFF");
+                assert_eq!(&encoded[8..12], b"WEBP");
+                continue;
+            }
+            _ => image::load_from_memory(&encoded).unwrap(),
+        };
+        assert_eq!(decoded.to_rgba8().dimensions(), (8, 4));
+    }
+    assert_eq!(TileFormat::from_extension("gif"), None);
+}
+
+#[test]
+fn test_options_validation() {
+    let options = DeepZoomGeneratorOptions::default();
+    assert_eq!((options.get_tile_size(), options.get_overlap()), (254, 1));
+    assert!(options.validate().is_ok());
+    assert!(options.tile_size(510).validate().is_ok());
+    assert!(options.tile_size(224).overlap(0).validate().is_ok());
+    assert!(options.tile_size(0).overlap(0).validate().is_err());
+    assert!(options.tile_size(4).overlap(4).validate().is_err());
+    assert!(options.tile_size(4096).validate().is_err());
+}
+
+#[test]
+fn test_limit_to_bounds() {
+    let level_dimensions = vec![(1000, 800), (250, 200)];
+
+    let (offset, dimensions) = limit_to_bounds(&level_dimensions, (None, None, None, None));
+    assert_eq!(offset, (0, 0));
+    assert_eq!(dimensions, level_dimensions);
+
+    let bounds = (Some(100), Some(50), Some(500), Some(401));
+    let (offset, dimensions) = limit_to_bounds(&level_dimensions, bounds);
+    assert_eq!(offset, (100, 50));
+    assert_eq!(dimensions, vec![(500, 401), (125, 101)]);
+}
+
+#[test]
+fn test_jpeg_tile() {
+    use crate::backend::ImageFile;
+
+    /// Stores 256 pixel JPEG tiles, "encoded" as their location.
+    struct StoredTiles(ImageFile);
+
+    impl SlideBackend for StoredTiles {
+        fn level_count(&self) -> Result<u32, OpenSlideError> {
+            self.0.level_count()
+        }
+
+        fn level_dimensions(&self, level: u32) -> Result<(u64, u64), OpenSlideError> {
+            self.0.level_dimensions(level)
+        }
+
+        fn level_downsample(&self, level: u32) -> Result<f64, OpenSlideError> {
+            self.0.level_downsample(level)
+        }
+
+        fn read_region(
+            &self,
+            location: (u64, u64),
+            level: u32,
+            size: (u64, u64),
+        ) -> Result<image::RgbaImage, OpenSlideError> {
+            self.0.read_region(location, level, size)
+        }
+
+        fn read_jpeg_tile(
+            &self,
+            level: u32,
+            (x, y): (u64, u64),
+            size: (u64, u64),
+        ) -> Result<Option<Vec<u8>>, OpenSlideError> {
+            let aligned = x % 256 == 0 && y % 256 == 0 && size == (256, 256);
+            Ok(aligned.then(|| format!("{} {} {}", level, x, y).into_bytes()))
         }
     }
+
+    let slide = || {
+        Arc::new(StoredTiles(ImageFile::from_image(
+            image::RgbaImage::new(1500, 1100),
+            ResampleFilter::Area,
+        )))
+    };
+    let options = DeepZoomGeneratorOptions::default()
+        .tile_size(256)
+        .overlap(0);
+    let g = DeepZoomGenerator::from_slide(slide(), options).unwrap();
+    assert_eq!(g.level_count(), 12);
+    assert_eq!(
+        g.get_jpeg_tile(11, 1, 2).unwrap(),
+        Some(b"0 256 512".to_vec())
+    );
+    // Level 1 (750 x 550) of the slide is Deep Zoom level 10.
+    assert_eq!(
+        g.get_jpeg_tile(10, 1, 0).unwrap(),
+        Some(b"1 256 0".to_vec())
+    );
+    // Smaller levels are not stored.
+    assert_eq!(g.get_jpeg_tile(8, 0, 0).unwrap(), None);
+    assert!(g.get_jpeg_tile(11, 6, 0).is_err());
+
+    // With overlap, tiles are not aligned.
+    let g = DeepZoomGenerator::from_slide(slide(), DeepZoomGeneratorOptions::default()).unwrap();
+    assert_eq!(g.get_jpeg_tile(11, 1, 1).unwrap(), None);
 }
@@ -6,29 +6,100 @@
 #![allow(dead_code)]
 
 mod bindings;
-mod properties;
+pub mod properties;
 mod utils;
 
+pub use self::utils::{decode_buffer, decode_buffer_into, WordRepresentation};
+
 use std::cmp::PartialOrd;
 use std::collections::HashMap;
-use std::fmt::{Debug, Display};
-use std::path::Path;
+use std::fmt::{self, Debug, Display};
+use std::path::{Path, PathBuf};
 
-use failure::{format_err, Error};
 use image::RgbaImage;
+use log::debug;
 use num::zero;
 use num::{Integer, Num, ToPrimitive, Unsigned};
 
+/// Errors returned by the OpenSlide wrapper.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OpenSlideError {
+    /// The slide file does not exist.
+    NotFound(PathBuf),
+    /// The file exists, but OpenSlide can not open it.
+    Unsupported(PathBuf),
+    /// The requested level does not exist.
+    LevelOutOfRange { level: u32, level_count: u32 },
+    /// The slide has no associated image with the requested name.
+    AssociatedImageNotFound(String),
+    /// An argument that can not be passed on to OpenSlide.
+    InvalidArgument(String),
+    /// The path of the slide can not be passed on to OpenSlide, e.g. because it is not UTF-8.
+    InvalidPath(PathBuf),
+    /// OpenSlide failed to read from the slide.
+    Read(String),
+    /// The slide handle is in an error state, e.g. after an I/O error, and returns no more data.
+    /// It has to be reopened.
+    Failed(String),
+}
+
+impl fmt::Display for OpenSlideError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenSlideError::NotFound(path) => write!(f, "Nonexisting path: {}", path.display()),
+            OpenSlideError::Unsupported(path) => {
+                write!(f, "Not a slide OpenSlide can open: {}", path.display())
+            }
+            OpenSlideError::LevelOutOfRange { level, level_count } => write!(
+                f,
+                "Level {} does not exist, the slide has {} levels",
+                level, level_count
+            ),
+            OpenSlideError::AssociatedImageNotFound(name) => {
+                write!(f, "Slide has no associated image named {}", name)
+            }
+            OpenSlideError::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            OpenSlideError::InvalidPath(path) => {
+                write!(f, "Path can not be passed to OpenSlide: {}", path.display())
+            }
+            OpenSlideError::Read(msg) => write!(f, "OpenSlide error: {}", msg),
+            OpenSlideError::Failed(msg) => write!(f, "Slide failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for OpenSlideError {}
+
+impl From<std::ffi::NulError> for OpenSlideError {
+    fn from(err: std::ffi::NulError) -> Self {
+        OpenSlideError::InvalidArgument(err.to_string())
+    }
+}
+
+/// The path of a slide as OpenSlide takes it: UTF-8, without NUL characters.
+fn path_str(path: &Path) -> Result<&str, OpenSlideError> {
+    path.to_str()
+        .filter(|path| !path.contains('\0'))
+        .ok_or_else(|| OpenSlideError::InvalidPath(path.to_path_buf()))
+}
+
 /// A convenient OpenSlide object with the ordinary OpenSlide functions as methods
 ///
 /// This wraps the bindings found in the bindings module, but has a more (in my opinion) convenient
 /// API for rust. It also contains some other convenience methods.
-#[derive(Clone)]
+///
+/// The object is not `Clone`, as that would close the underlying handle twice. Share it between
+/// threads with an `Arc` instead.
 pub struct OpenSlide {
     osr: *const bindings::OpenSlideT,
     pub properties: properties::Properties,
 }
 
+// The OpenSlide C library is thread-safe: a handle may be used from several threads at once. The
+// handle is only closed on drop, when no other references to it exist.
+unsafe impl Send for OpenSlide {}
+unsafe impl Sync for OpenSlide {}
+
 impl Drop for OpenSlide {
     /// This method is called when the object in dropped, and tries to close the slide.
     fn drop(&mut self) {
@@ -42,19 +113,20 @@ impl OpenSlide {
     /// This function can be expensive; avoid calling it unnecessarily. For example, a tile server
     /// should not create a new object on every tile request. Instead, it should maintain a cache
     /// of OpenSlide objects and reuse them when possible.
-    pub fn new(filename: &Path) -> Result<OpenSlide, Error> {
+    pub fn new(filename: &Path) -> Result<OpenSlide, OpenSlideError> {
         if !filename.exists() {
-            return Err(format_err!(
-                "Error: Nonexisting path: {}",
-                filename.display()
-            ));
+            return Err(OpenSlideError::NotFound(filename.to_path_buf()));
         }
 
-        let osr = bindings::open(
-            filename
-                .to_str()
-                .ok_or_else(|| format_err!("Error: Path to &str"))?,
-        )?;
+        let osr = bindings::open(path_str(filename)?)?;
+        if osr.is_null() {
+            return Err(OpenSlideError::Unsupported(filename.to_path_buf()));
+        }
+        // A file that is recognized but can not be read is opened in the error state.
+        if let Some(msg) = unsafe { bindings::get_error(osr) } {
+            unsafe { bindings::close(osr) };
+            return Err(OpenSlideError::Failed(msg));
+        }
 
         let mut property_map = HashMap::<String, String>::new();
         for name in unsafe { bindings::get_property_names(osr)? } {
@@ -67,23 +139,48 @@ impl OpenSlide {
         Ok(OpenSlide { osr, properties })
     }
 
+    /// Quickly determine whether a whole slide image is recognized by OpenSlide.
+    ///
+    /// Returns the name of the vendor, or `None` if the file is not a slide OpenSlide can open.
+    /// This is much cheaper than opening the slide.
+    pub fn detect_vendor(filename: &Path) -> Result<Option<String>, OpenSlideError> {
+        bindings::detect_vendor(path_str(filename)?)
+    }
+
+    /// Get the error message of the slide, if it is in the error state.
+    ///
+    /// Once an error occurred, every further read fails (or returns transparent pixels), so the
+    /// slide should be reopened.
+    pub fn get_error(&self) -> Option<String> {
+        unsafe { bindings::get_error(self.osr) }
+    }
+
+    /// Fail if the slide is in the error state.
+    fn check_error(&self) -> Result<(), OpenSlideError> {
+        match self.get_error() {
+            Some(msg) => Err(OpenSlideError::Failed(msg)),
+            None => Ok(()),
+        }
+    }
+
     /// Get the number of levels in the whole slide image.
-    pub fn get_level_count(&self) -> Result<u32, Error> {
+    pub fn get_level_count(&self) -> Result<u32, OpenSlideError> {
         let num_levels = unsafe { bindings::get_level_count(self.osr)? };
 
         #[allow(clippy::comparison_chain)]
         if num_levels < -1 {
-            Err(format_err!(
+            Err(OpenSlideError::Read(format!(
                 "Error: Number of levels is {}, this is an unknown error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
                  See OpenSlide C API documentation.",
                 num_levels
-            ))
+            )))
         } else if num_levels == -1 {
-            Err(format_err!(
+            Err(OpenSlideError::Read(
                 "Error: Number of levels is -1, this is a known error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
-                 See OpenSlide C API documentation.",
+                 See OpenSlide C API documentation."
+                    .to_string(),
             ))
         } else {
             Ok(num_levels as u32)
@@ -95,38 +192,40 @@ impl OpenSlide {
     /// This method returns the (width, height) number of pixels of the level 0 whole slide image.
     ///
     /// This is the same as calling get_level_dimensions(level) with level=0.
-    pub fn get_level0_dimensions(&self) -> Result<(u64, u64), Error> {
+    pub fn get_level0_dimensions(&self) -> Result<(u64, u64), OpenSlideError> {
         let (width, height) = unsafe { bindings::get_level0_dimensions(self.osr)? };
 
         #[allow(clippy::comparison_chain)]
         if width < -1 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(format!(
                 "Error: Width is {}, this is an unknown error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
                  See OpenSlide C API documentation.",
                 width
-            ));
+            )));
         } else if width == -1 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(
                 "Error: Width is -1, this is a known error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
-                 See OpenSlide C API documentation.",
+                 See OpenSlide C API documentation."
+                    .to_string(),
             ));
         }
 
         #[allow(clippy::comparison_chain)]
         if height < -1 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(format!(
                 "Error: Height is {}, this is an unknown error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
                  See OpenSlide C API documentation.",
                 width
-            ));
+            )));
         } else if height == -1 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(
                 "Error: Height is -1, this is a known error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
-                 See OpenSlide C API documentation.",
+                 See OpenSlide C API documentation."
+                    .to_string(),
             ));
         }
 
@@ -140,43 +239,45 @@ impl OpenSlide {
     pub fn get_level_dimensions<T: Integer + ToPrimitive + Debug + Display + Clone + Copy>(
         &self,
         level: T,
-    ) -> Result<(u64, u64), Error> {
+    ) -> Result<(u64, u64), OpenSlideError> {
         self.assert_level_validity(level)?;
-        let level = level
-            .to_i32()
-            .ok_or_else(|| format_err!("Conversion to primitive error"))?;
+        let level = level.to_i32().ok_or_else(|| {
+            OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+        })?;
 
         let (width, height) = unsafe { bindings::get_level_dimensions(self.osr, level)? };
 
         #[allow(clippy::comparison_chain)]
         if width < -1 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(format!(
                 "Error: Width is {}, this is an unknown error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
                  See OpenSlide C API documentation.",
                 width
-            ));
+            )));
         } else if width == -1 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(
                 "Error: Width is -1, this is a known error from openslide. \
                  OpenSlide returns -1 if an error occured. \
-                 See OpenSlide C API documentation.",
+                 See OpenSlide C API documentation."
+                    .to_string(),
             ));
         }
 
         #[allow(clippy::comparison_chain)]
         if height < -1 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(format!(
                 "Error: Height is {}, this is an unknown error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
                  See OpenSlide C API documentation.",
                 width
-            ));
+            )));
         } else if height == -1 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(
                 "Error: Height is -1, this is a known error from openslide. \
                  OpenSlide returns -1 if an error occured. \
-                 See OpenSlide C API documentation.",
+                 See OpenSlide C API documentation."
+                    .to_string(),
             ));
         }
 
@@ -187,22 +288,21 @@ impl OpenSlide {
     pub fn get_level_downsample<T: Integer + ToPrimitive + Debug + Display + Clone + Copy>(
         &self,
         level: T,
-    ) -> Result<f64, Error> {
+    ) -> Result<f64, OpenSlideError> {
         self.assert_level_validity(level)?;
-        let level = level
-            .to_i32()
-            .ok_or_else(|| format_err!("Conversion to primitive error"))?;
+        let level = level.to_i32().ok_or_else(|| {
+            OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+        })?;
         let downsample_factor = unsafe { bindings::get_level_downsample(self.osr, level)? };
 
         if downsample_factor < 0.0 {
-            return Err(format_err!(
+            return Err(OpenSlideError::Read(format!(
                 "Error: When trying to get a downsample factor for level {},\
                  OpenSlide returned a downsample factor {}, this is an error from \
                  OpenSlide. OpenSlide returns -1.0 if an error occured. \
                  See OpenSlide C API documentation.",
-                level,
-                downsample_factor
-            ));
+                level, downsample_factor
+            )));
         }
 
         Ok(downsample_factor)
@@ -214,37 +314,38 @@ impl OpenSlide {
     >(
         &self,
         downsample_factor: T,
-    ) -> Result<u32, Error> {
+    ) -> Result<u32, OpenSlideError> {
         if downsample_factor < zero() {
-            return Err(format_err!(
+            return Err(OpenSlideError::InvalidArgument(format!(
                 "Error: Only non-negative downsample factor is allowed. \
                  You specified {}. ",
                 downsample_factor
-            ));
+            )));
         }
 
         let level = unsafe {
             bindings::get_best_level_for_downsample(
                 self.osr,
-                downsample_factor
-                    .to_f64()
-                    .ok_or_else(|| format_err!("Conversion to primitive error"))?,
+                downsample_factor.to_f64().ok_or_else(|| {
+                    OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+                })?,
             )?
         };
 
         #[allow(clippy::comparison_chain)]
         if level < -1 {
-            Err(format_err!(
+            Err(OpenSlideError::Read(format!(
                 "Error: Returned level is {}, this is an unknown error from OpenSlide. \
                  OpenSlide returns -1 if an error occured. \
                  See OpenSlide C API documentation.",
                 level
-            ))
+            )))
         } else if level == -1 {
-            Err(format_err!(
+            Err(OpenSlideError::Read(
                 "Error: Returned level is -1, this is a known error from openslide. \
                  OpenSlide returns -1 if an error occured. \
-                 See OpenSlide C API documentation.",
+                 See OpenSlide C API documentation."
+                    .to_string(),
             ))
         } else {
             Ok(level as u32)
@@ -267,63 +368,63 @@ impl OpenSlide {
         level: T,
         height: T,
         width: T,
-    ) -> Result<(u64, u64), Error> {
+    ) -> Result<(u64, u64), OpenSlideError> {
         let (max_width, max_height) = self.get_level_dimensions(level)?;
         let downsample_factor = self.get_level_downsample(level)?;
 
-        let tl_row_this_lvl = top_left_lvl0_row
-            .to_f64()
-            .ok_or_else(|| format_err!("Conversion to primitive error"))?
-            / downsample_factor;
-        let tl_col_this_lvl = top_left_lvl0_col
-            .to_f64()
-            .ok_or_else(|| format_err!("Conversion to primitive error"))?
-            / downsample_factor;
+        let tl_row_this_lvl = top_left_lvl0_row.to_f64().ok_or_else(|| {
+            OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+        })? / downsample_factor;
+        let tl_col_this_lvl = top_left_lvl0_col.to_f64().ok_or_else(|| {
+            OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+        })? / downsample_factor;
 
         let new_height = height
             .to_u64()
-            .ok_or_else(|| format_err!("Conversion to primitive error"))?
-            .min(max_height - tl_row_this_lvl.round() as u64);
+            .ok_or_else(|| {
+                OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+            })?
+            .min(max_height.saturating_sub(tl_row_this_lvl.round() as u64));
         let new_width = width
             .to_u64()
-            .ok_or_else(|| format_err!("Conversion to primitive error"))?
-            .min(max_width - tl_col_this_lvl.round() as u64);
+            .ok_or_else(|| {
+                OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+            })?
+            .min(max_width.saturating_sub(tl_col_this_lvl.round() as u64));
 
         if new_height
-            < height
-                .to_u64()
-                .ok_or_else(|| format_err!("Conversion to primitive error"))?
+            < height.to_u64().ok_or_else(|| {
+                OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+            })?
         {
-            println!(
-                "WARNING: Requested region height is changed from {} to {} in order to fit",
+            debug!(
+                "Requested region height is changed from {} to {} in order to fit",
                 height, new_height
             );
         }
         if new_width
-            < width
-                .to_u64()
-                .ok_or_else(|| format_err!("conversion to primitive error"))?
+            < width.to_u64().ok_or_else(|| {
+                OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+            })?
         {
-            println!(
-                "WARNING: Requested region width is changed from {} to {} in order to fit",
+            debug!(
+                "Requested region width is changed from {} to {} in order to fit",
                 width, new_width
             );
         }
 
         if new_height > max_height {
-            return Err(format_err!(
+            return Err(OpenSlideError::InvalidArgument(format!(
                 "Requested height {} exceeds maximum {}",
-                height,
-                max_height
-            ));
+                height, max_height
+            )));
         }
 
         if new_width > max_width {
-            return Err(format_err!(
+            return Err(OpenSlideError::InvalidArgument(format!(
                 "Requested width {} exceeds maximum {}",
-                width,
-                max_width
-            ));
+                width, max_width
+            )));
         }
 
         Ok((new_height, new_width))
@@ -347,7 +448,7 @@ impl OpenSlide {
         level: u64,
         height: u64,
         width: u64,
-    ) -> Result<RgbaImage, Error> {
+    ) -> Result<RgbaImage, OpenSlideError> {
         let (height, width) = self.get_feasible_dimensions(
             top_left_lvl0_row,
             top_left_lvl0_col,
@@ -366,8 +467,17 @@ impl OpenSlide {
                 height as i64,
             )?
         };
-        let word_repr = utils::WordRepresentation::BigEndian;
-        utils::decode_buffer(&buffer, height as u32, width as u32, word_repr)
+        // A failed read leaves the buffer transparent rather than returning an error.
+        self.check_error()?;
+        let mut region = RgbaImage::new(width as u32, height as u32);
+        decode_buffer_into(
+            &buffer,
+            height as u32,
+            width as u32,
+            WordRepresentation::BigEndian,
+            &mut region,
+        )?;
+        Ok(region)
     }
 
     /// Get a dictionary of properties associated with the current slide
@@ -375,7 +485,8 @@ impl OpenSlide {
     /// There are some standard properties to every slide, but also a lot of vendor-specific
     /// properties. This method returns a HashMap with all key-value pairs of the properties
     /// associated with the slide.
-    pub fn get_properties(&self) -> Result<HashMap<String, String>, Error> {
+    pub fn get_properties(&self) -> Result<HashMap<String, String>, OpenSlideError> {
+        self.check_error()?;
         let mut properties = HashMap::<String, String>::new();
         for name in unsafe { bindings::get_property_names(self.osr)? } {
             properties.insert(name.clone(), unsafe {
@@ -385,18 +496,66 @@ impl OpenSlide {
         Ok(properties)
     }
 
+    /// Get the names of the associated images (e.g. `label`, `macro` or `thumbnail`) of the slide.
+    pub fn get_associated_image_names(&self) -> Result<Vec<String>, OpenSlideError> {
+        unsafe { bindings::get_associated_image_names(self.osr) }
+    }
+
+    /// Get the (width, height) of the associated image with the given name.
+    pub fn get_associated_image_dimensions(
+        &self,
+        name: &str,
+    ) -> Result<(u64, u64), OpenSlideError> {
+        self.assert_associated_image_validity(name)?;
+        let (width, height) = unsafe { bindings::get_associated_image_dimensions(self.osr, name)? };
+        if width < 0 || height < 0 {
+            return Err(OpenSlideError::Read(format!(
+                "Error: Dimensions of associated image {} are ({}, {}). \
+                 OpenSlide returns -1 if an error occured. \
+                 See OpenSlide C API documentation.",
+                name, width, height
+            )));
+        }
+        Ok((width as u64, height as u64))
+    }
+
+    /// Read the associated image with the given name into an RGBA image.
+    pub fn read_associated_image(&self, name: &str) -> Result<RgbaImage, OpenSlideError> {
+        let (width, height) = self.get_associated_image_dimensions(name)?;
+        let buffer = unsafe {
+            bindings::read_associated_image(self.osr, name, width as i64, height as i64)?
+        };
+        self.check_error()?;
+        decode_buffer(
+            &buffer,
+            height as u32,
+            width as u32,
+            WordRepresentation::BigEndian,
+        )
+    }
+
+    /// Check if the slide has an associated image with the given name
+    fn assert_associated_image_validity(&self, name: &str) -> Result<(), OpenSlideError> {
+        if !self.get_associated_image_names()?.iter().any(|n| n == name) {
+            return Err(OpenSlideError::AssociatedImageNotFound(name.to_string()));
+        }
+        Ok(())
+    }
+
     /// Check if the given level is valid
-    fn assert_level_validity<T: Integer + ToPrimitive>(&self, level: T) -> Result<(), Error> {
+    fn assert_level_validity<T: Integer + ToPrimitive>(
+        &self,
+        level: T,
+    ) -> Result<(), OpenSlideError> {
         let max_num_levels = self.get_level_count()?;
-        let level = level
-            .to_u32()
-            .ok_or_else(|| format_err!("Conversion to primitive error"))?;
+        let level = level.to_u32().ok_or_else(|| {
+            OpenSlideError::InvalidArgument("Conversion to primitive error".to_string())
+        })?;
         if level >= max_num_levels {
-            return Err(format_err!(
-                "Error: Specified level {} is larger than the max slide level {}",
+            return Err(OpenSlideError::LevelOutOfRange {
                 level,
-                max_num_levels - 1,
-            ));
+                level_count: max_num_levels,
+            });
         }
         Ok(())
     }
@@ -6,7 +6,7 @@
 //! For a more rust convenient api, use the OpenSlide struct.
 //!
 
-use failure::Error;
+use super::OpenSlideError;
 
 use std::{self, ffi, str};
 
@@ -62,9 +62,7 @@ extern "C" {
     // Error handling
     // ---------------
 
-    // fn openslide_get_error(
-    //     osr: *const OpenSlideT
-    // ) -> *const libc::c_char;
+    fn openslide_get_error(osr: *const OpenSlideT) -> *const libc::c_char;
 
     // ---------------
     // Properties
@@ -76,6 +74,25 @@ extern "C" {
         osr: *const OpenSlideT,
         name: *const libc::c_char,
     ) -> *const libc::c_char;
+
+    // ---------------
+    // Associated images
+    // ---------------
+
+    fn openslide_get_associated_image_names(osr: *const OpenSlideT) -> *const *const libc::c_char;
+
+    fn openslide_get_associated_image_dimensions(
+        osr: *const OpenSlideT,
+        name: *const libc::c_char,
+        w: *mut i64,
+        h: *mut i64,
+    ) -> libc::c_void;
+
+    fn openslide_read_associated_image(
+        osr: *const OpenSlideT,
+        name: *const libc::c_char,
+        dest: *mut u32,
+    ) -> libc::c_void;
 }
 
 // ---------------
@@ -83,17 +100,23 @@ extern "C" {
 // ---------------
 
 /// Quickly determine whether a whole slide image is recognized.
-pub fn detect_vendor(filename: &str) -> Result<String, Error> {
+///
+/// Returns `None` if OpenSlide does not recognize the file.
+pub fn detect_vendor(filename: &str) -> Result<Option<String>, OpenSlideError> {
     let c_filename = ffi::CString::new(filename)?;
     let vendor = unsafe {
         let c_vendor = openslide_detect_vendor(c_filename.as_ptr());
-        ffi::CStr::from_ptr(c_vendor).to_string_lossy().into_owned()
+        if c_vendor.is_null() {
+            None
+        } else {
+            Some(ffi::CStr::from_ptr(c_vendor).to_string_lossy().into_owned())
+        }
     };
     Ok(vendor)
 }
 
 /// Open a whole slide image.
-pub fn open(filename: &str) -> Result<*const OpenSlideT, Error> {
+pub fn open(filename: &str) -> Result<*const OpenSlideT, OpenSlideError> {
     let c_filename = ffi::CString::new(filename)?;
     let slide = unsafe { openslide_open(c_filename.as_ptr()) };
     Ok(slide)
@@ -105,13 +128,13 @@ pub unsafe fn close(osr: *const OpenSlideT) {
 }
 
 /// Get the number of levels in the whole slide image.
-pub unsafe fn get_level_count(osr: *const OpenSlideT) -> Result<i32, Error> {
+pub unsafe fn get_level_count(osr: *const OpenSlideT) -> Result<i32, OpenSlideError> {
     let num_levels = openslide_get_level_count(osr); // This is unsafe
     Ok(num_levels)
 }
 
 /// Get the dimensions of level 0 (the largest level).
-pub unsafe fn get_level0_dimensions(osr: *const OpenSlideT) -> Result<(i64, i64), Error> {
+pub unsafe fn get_level0_dimensions(osr: *const OpenSlideT) -> Result<(i64, i64), OpenSlideError> {
     let mut width: i64 = 0;
     let mut height: i64 = 0;
     openslide_get_level0_dimensions(osr, &mut width, &mut height); // This is unsafe
@@ -122,7 +145,7 @@ pub unsafe fn get_level0_dimensions(osr: *const OpenSlideT) -> Result<(i64, i64)
 pub unsafe fn get_level_dimensions(
     osr: *const OpenSlideT,
     level: i32,
-) -> Result<(i64, i64), Error> {
+) -> Result<(i64, i64), OpenSlideError> {
     let mut width: i64 = 0;
     let mut height: i64 = 0;
     openslide_get_level_dimensions(osr, level, &mut width, &mut height); // This is unsafe
@@ -130,7 +153,10 @@ pub unsafe fn get_level_dimensions(
 }
 
 /// Get the downsampling factor of a given level.
-pub unsafe fn get_level_downsample(osr: *const OpenSlideT, level: i32) -> Result<f64, Error> {
+pub unsafe fn get_level_downsample(
+    osr: *const OpenSlideT,
+    level: i32,
+) -> Result<f64, OpenSlideError> {
     let downsampling_factor = openslide_get_level_downsample(osr, level); // This is unsafe
     Ok(downsampling_factor)
 }
@@ -139,7 +165,7 @@ pub unsafe fn get_level_downsample(osr: *const OpenSlideT, level: i32) -> Result
 pub unsafe fn get_best_level_for_downsample(
     osr: *const OpenSlideT,
     downsample: f64,
-) -> Result<i32, Error> {
+) -> Result<i32, OpenSlideError> {
     let level = openslide_get_best_level_for_downsample(osr, downsample); // This is unsafe
     Ok(level)
 }
@@ -152,7 +178,7 @@ pub unsafe fn read_region(
     level: i32,
     w: i64,
     h: i64,
-) -> Result<Vec<u32>, Error> {
+) -> Result<Vec<u32>, OpenSlideError> {
     let mut buffer: Vec<u32> = Vec::with_capacity((h * w) as usize);
     let p_buffer = buffer.as_mut_ptr();
     openslide_read_region(osr, p_buffer, x, y, level, w, h); // This is unsafe
@@ -164,27 +190,34 @@ pub unsafe fn read_region(
 // Error handling
 // ---------------
 
-/* FIXME Keep commented as long as it is not working. Gets segmentation fault core dumped
 /// Get the current error string.
-pub fn get_error(
-    osr: *const OpenSlideT
-) -> Result<String, Error> {
-    let msg = unsafe {
-        let c_msg = openslide_get_error(osr);
-        ffi::CStr::from_ptr(c_msg).to_string_lossy().into_owned()
-    };
-    Ok(msg)
+///
+/// Returns `None` if the slide is not in an error state. Once an error occurred, the handle stays
+/// in the error state and every read fails; the slide has to be closed and reopened.
+pub unsafe fn get_error(osr: *const OpenSlideT) -> Option<String> {
+    // OpenSlide returns NULL when there is no error, which must not be passed to CStr.
+    let c_msg = openslide_get_error(osr); // This is unsafe
+    if c_msg.is_null() {
+        None
+    } else {
+        Some(ffi::CStr::from_ptr(c_msg).to_string_lossy().into_owned())
+    }
 }
-*/
 
 // ---------------
 // Properties
 // ---------------
 
 /// Get the NULL-terminated array of property names.
-pub unsafe fn get_property_names(osr: *const OpenSlideT) -> Result<Vec<String>, Error> {
+pub unsafe fn get_property_names(osr: *const OpenSlideT) -> Result<Vec<String>, OpenSlideError> {
+    read_string_array(openslide_get_property_names(osr))
+}
+
+/// Copy a NULL-terminated array of strings, as returned by OpenSlide, into a vector.
+unsafe fn read_string_array(
+    null_terminated_array_ptr: *const *const libc::c_char,
+) -> Result<Vec<String>, OpenSlideError> {
     let string_values = {
-        let null_terminated_array_ptr = openslide_get_property_names(osr);
         let mut counter = 0;
         let mut loc = null_terminated_array_ptr;
         while !(*loc).is_null() {
@@ -205,11 +238,59 @@ pub unsafe fn get_property_names(osr: *const OpenSlideT) -> Result<Vec<String>,
 }
 
 /// Get the value of a single property.
-pub unsafe fn get_property_value(osr: *const OpenSlideT, name: &str) -> Result<String, Error> {
+pub unsafe fn get_property_value(
+    osr: *const OpenSlideT,
+    name: &str,
+) -> Result<String, OpenSlideError> {
     let c_name = ffi::CString::new(name)?;
-    let value = {
-        let c_value = openslide_get_property_value(osr, c_name.as_ptr());
-        ffi::CStr::from_ptr(c_value).to_string_lossy().into_owned()
-    };
-    Ok(value)
+    let c_value = openslide_get_property_value(osr, c_name.as_ptr());
+    // NULL if the property does not exist or the slide is in the error state.
+    if c_value.is_null() {
+        return Err(OpenSlideError::Read(format!(
+            "No value for property {}",
+            name
+        )));
+    }
+    Ok(ffi::CStr::from_ptr(c_value).to_string_lossy().into_owned())
+}
+
+// ---------------
+// Associated images
+// ---------------
+
+/// Get the NULL-terminated array of associated image names.
+pub unsafe fn get_associated_image_names(
+    osr: *const OpenSlideT,
+) -> Result<Vec<String>, OpenSlideError> {
+    read_string_array(openslide_get_associated_image_names(osr))
+}
+
+/// Get the dimensions of an associated image.
+pub unsafe fn get_associated_image_dimensions(
+    osr: *const OpenSlideT,
+    name: &str,
+) -> Result<(i64, i64), OpenSlideError> {
+    let c_name = ffi::CString::new(name)?;
+    let mut width: i64 = 0;
+    let mut height: i64 = 0;
+    // This is unsafe
+    openslide_get_associated_image_dimensions(osr, c_name.as_ptr(), &mut width, &mut height);
+    Ok((width, height))
+}
+
+/// Copy pre-multiplied ARGB data from an associated image.
+///
+/// The dimensions `w` and `h` must be those returned by `get_associated_image_dimensions()`.
+pub unsafe fn read_associated_image(
+    osr: *const OpenSlideT,
+    name: &str,
+    w: i64,
+    h: i64,
+) -> Result<Vec<u32>, OpenSlideError> {
+    let c_name = ffi::CString::new(name)?;
+    let mut buffer: Vec<u32> = Vec::with_capacity((h * w) as usize);
+    let p_buffer = buffer.as_mut_ptr();
+    openslide_read_associated_image(osr, c_name.as_ptr(), p_buffer); // This is unsafe
+    buffer.set_len((h * w) as usize);
+    Ok(buffer)
 }
@@ -2,9 +2,10 @@
 //!
 
 use num::Num;
-use std::{f32, u32};
+use serde::Serialize;
+use std::f32;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct Aperio {
     pub filename: Option<String>,
     pub title: Option<String>,
@@ -49,41 +50,25 @@ impl Aperio {
             "aperio.User" => self.user = Some(String::from(value)),
             "aperio.ICC Profile" => self.icc_profile = Some(String::from(value)),
             "aperio.Parmset" => self.parmset = Some(String::from(value)),
-            "aperio.OriginalHeight" => {
-                self.original_height = Some(value.parse().unwrap())
-            }
-            "aperio.OriginalWidth" => {
-                self.original_width = Some(value.parse().unwrap())
-            }
-            "aperio.Top" => self.top = Some(f32::from_str_radix(value, 10).unwrap()),
-            "aperio.Left" => self.left = Some(f32::from_str_radix(value, 10).unwrap()),
-            "aperio.MPP" => self.mpp = Some(f32::from_str_radix(value, 10).unwrap()),
-            "aperio.LineCameraSkew" => {
-                self.line_camera_skew = Some(f32::from_str_radix(value, 10).unwrap())
-            }
+            "aperio.OriginalHeight" => self.original_height = value.parse().ok(),
+            "aperio.OriginalWidth" => self.original_width = value.parse().ok(),
+            "aperio.Top" => self.top = f32::from_str_radix(value, 10).ok(),
+            "aperio.Left" => self.left = f32::from_str_radix(value, 10).ok(),
+            "aperio.MPP" => self.mpp = f32::from_str_radix(value, 10).ok(),
+            "aperio.LineCameraSkew" => self.line_camera_skew = f32::from_str_radix(value, 10).ok(),
             "aperio.LineAreaXOffset" => {
-                self.line_area_x_offset = Some(f32::from_str_radix(value, 10).unwrap())
+                self.line_area_x_offset = f32::from_str_radix(value, 10).ok()
             }
             "aperio.LineAreaYOffset" => {
-                self.line_area_y_offset = Some(f32::from_str_radix(value, 10).unwrap())
-            }
-            "aperio.Focus Offset" => {
-                self.focus_offset = Some(f32::from_str_radix(value, 10).unwrap())
-            }
-            "aperio.AppMag" => self.app_mag = Some(value.parse().unwrap()),
-            "aperio.StripeWidth" => {
-                self.stripe_width = Some(value.parse().unwrap())
-            }
-            "aperio.Filtered" => self.filtered = Some(value.parse().unwrap()),
-            "aperio.DisplayColor" => {
-                self.display_color = Some(value.parse().unwrap())
-            }
-            "aperio.Exposure Time" => {
-                self.exposure_time = Some(value.parse().unwrap())
-            }
-            "aperio.Exposure Scale" => {
-                self.exposure_scale = Some(f32::from_str_radix(value, 10).unwrap())
+                self.line_area_y_offset = f32::from_str_radix(value, 10).ok()
             }
+            "aperio.Focus Offset" => self.focus_offset = f32::from_str_radix(value, 10).ok(),
+            "aperio.AppMag" => self.app_mag = value.parse().ok(),
+            "aperio.StripeWidth" => self.stripe_width = value.parse().ok(),
+            "aperio.Filtered" => self.filtered = value.parse().ok(),
+            "aperio.DisplayColor" => self.display_color = value.parse().ok(),
+            "aperio.Exposure Time" => self.exposure_time = value.parse().ok(),
+            "aperio.Exposure Scale" => self.exposure_scale = f32::from_str_radix(value, 10).ok(),
             "aperio.SessonMode" => self.sesson_mode = Some(String::from(value)),
             //_ => println!("Could not parse property name {} and value {}", name, value),
             _ => {}
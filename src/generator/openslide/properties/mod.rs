@@ -6,6 +6,7 @@ mod openslide;
 mod tiff;
 //mod hamamatsu;
 
+use serde::Serialize;
 use std::collections::HashMap;
 
 use self::openslide::LevelProperties;
@@ -32,10 +33,15 @@ use self::openslide::LevelProperties;
 /// print the result of the `OpenSlide::get_properties()` method, or use the
 /// `Properties::print_available()` method (recommended).
 ///
-#[derive(Clone, Debug)]
+/// The properties serialize (e.g. to JSON) grouped per property namespace, with `null` for
+/// properties the slide does not have.
+#[derive(Clone, Debug, Serialize)]
 pub struct Properties {
+    #[serde(rename = "openslide")]
     openslide_properties: openslide::OpenSlide,
+    #[serde(rename = "tiff")]
     tiff_properties: tiff::Tiff,
+    #[serde(rename = "aperio")]
     aperio_properties: aperio::Aperio,
 }
 
@@ -113,6 +119,26 @@ impl Properties {
         self.openslide_properties.comment.clone()
     }
 
+    /// Horizontal level 0 coordinate of the top left corner of the non-empty region of the slide
+    pub fn bounds_x(&self) -> Option<u64> {
+        self.openslide_properties.bounds_x
+    }
+
+    /// Vertical level 0 coordinate of the top left corner of the non-empty region of the slide
+    pub fn bounds_y(&self) -> Option<u64> {
+        self.openslide_properties.bounds_y
+    }
+
+    /// Level 0 width of the non-empty region of the slide
+    pub fn bounds_width(&self) -> Option<u64> {
+        self.openslide_properties.bounds_width
+    }
+
+    /// Level 0 height of the non-empty region of the slide
+    pub fn bounds_height(&self) -> Option<u64> {
+        self.openslide_properties.bounds_height
+    }
+
     /// Number of zoom levels
     pub fn level_count(&self) -> Option<u32> {
         self.openslide_properties.level_count
@@ -234,7 +260,7 @@ impl Properties {
 
     /// Slide width
     pub fn original_width(&self) -> Option<u32> {
-        self.aperio_properties.original_height
+        self.aperio_properties.original_width
     }
 
     pub fn top(&self) -> Option<f32> {
@@ -299,3 +325,47 @@ impl Properties {
         self.aperio_properties.sesson_mode.clone()
     }
 }
+
+#[test]
+fn test_serialize_properties() {
+    let property_map: HashMap<String, String> = [
+        ("openslide.vendor", "aperio"),
+        ("openslide.mpp-x", "0.499"),
+        ("openslide.level-count", "1"),
+        ("openslide.bounds-x", "1024"),
+        ("openslide.level[0].width", "2220"),
+        ("tiff.ResolutionUnit", "inch"),
+        ("aperio.AppMag", "20"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    let value = serde_json::to_value(Properties::new(&property_map)).unwrap();
+
+    assert_eq!(value["openslide"]["vendor"], "aperio");
+    assert_eq!(value["openslide"]["mpp_y"], serde_json::Value::Null);
+    assert_eq!(value["openslide"]["levels"][0]["width"], 2220);
+    assert_eq!(value["openslide"]["bounds_x"], 1024);
+    assert_eq!(value["tiff"]["resolution_unit"], "inch");
+    assert_eq!(value["aperio"]["app_mag"], 20);
+}
+
+#[test]
+fn test_malformed_properties() {
+    let property_map: HashMap<String, String> = [
+        ("openslide.objective-power", "20x"),
+        ("openslide.level-count", "1"),
+        ("openslide.level[0].width", "wide"),
+        ("openslide.level[3].height", "512"),
+        ("openslide.level[x].height", "512"),
+        ("aperio.AppMag", "20.5"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    let properties = Properties::new(&property_map);
+
+    assert_eq!(properties.objective_power(), None);
+    assert_eq!(properties.app_mag(), None);
+    assert_eq!(properties.levels().unwrap()[0].width(), None);
+}
@@ -2,12 +2,12 @@
 //!
 
 use num::Num;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::f32;
-use std::u32;
 
 /// Properties defined for every level
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct LevelProperties {
     downsample: Option<f32>,
     height: Option<u32>,
@@ -66,7 +66,7 @@ impl LevelProperties {
 
 /// Common properties that are available under the name `openslide.<property>` in the HashMap
 /// returned from the `OpenSlide::get_properties()` method.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct OpenSlide {
     pub vendor: Option<String>,
     pub quickhash_1: Option<String>,
@@ -74,6 +74,10 @@ pub struct OpenSlide {
     pub mpp_y: Option<f32>,
     pub objective_power: Option<u32>,
     pub comment: Option<String>,
+    pub bounds_x: Option<u64>,
+    pub bounds_y: Option<u64>,
+    pub bounds_width: Option<u64>,
+    pub bounds_height: Option<u64>,
     pub level_count: Option<u32>,
     pub levels: Option<Vec<LevelProperties>>,
 }
@@ -87,10 +91,7 @@ impl OpenSlide {
         let computed_level_count = find_max_level(property_map);
         let level_count = match property_map.get("openslide.level-count") {
             Some(val) => {
-                let level_count = match val.parse() {
-                    Ok(val) => Some(val),
-                    Err(_) => None,
-                };
+                let level_count = val.parse().ok();
                 if level_count != computed_level_count {
                     println!("WARNING: Computed level count is different from stated property");
                 }
@@ -119,6 +120,10 @@ impl OpenSlide {
             mpp_y: None,
             objective_power: None,
             comment: None,
+            bounds_x: None,
+            bounds_y: None,
+            bounds_width: None,
+            bounds_height: None,
             level_count,
             levels,
         }
@@ -128,52 +133,43 @@ impl OpenSlide {
         match name {
             "openslide.vendor" => self.vendor = Some(String::from(value)),
             "openslide.quickhash-1" => self.quickhash_1 = Some(String::from(value)),
-            "openslide.mpp-x" => self.mpp_x = Some(f32::from_str_radix(value, 10).unwrap()),
-            "openslide.mpp-y" => self.mpp_y = Some(f32::from_str_radix(value, 10).unwrap()),
-            "openslide.objective-power" => {
-                self.objective_power = Some(value.parse().unwrap())
-            }
+            "openslide.mpp-x" => self.mpp_x = f32::from_str_radix(value, 10).ok(),
+            "openslide.mpp-y" => self.mpp_y = f32::from_str_radix(value, 10).ok(),
+            "openslide.objective-power" => self.objective_power = value.parse().ok(),
             "openslide.comment" => self.comment = Some(String::from(value)),
-            "openslide.level-count" => {
-                self.level_count = Some(value.parse().unwrap())
-            }
+            "openslide.bounds-x" => self.bounds_x = value.parse().ok(),
+            "openslide.bounds-y" => self.bounds_y = value.parse().ok(),
+            "openslide.bounds-width" => self.bounds_width = value.parse().ok(),
+            "openslide.bounds-height" => self.bounds_height = value.parse().ok(),
+            "openslide.level-count" => self.level_count = value.parse().ok(),
             _ => {
                 if name.contains("level[") {
                     let level = {
                         let starts_with_number = name.split("level[").last().unwrap();
                         let number_as_string = starts_with_number.split(']').next().unwrap();
-                        number_as_string.parse::<usize>().unwrap()
+                        match number_as_string.parse::<usize>() {
+                            Ok(level) => level,
+                            Err(_) => return,
+                        }
                     };
                     match self.levels {
-                        Some(ref mut vector) => {
+                        Some(ref mut vector) if level < vector.len() => {
                             let last_part = name
                                 .split(&format!("openslide.level[{}].", level))
                                 .last()
                                 .unwrap();
                             match last_part {
                                 "downsample" => {
-                                    vector[level].downsample =
-                                        Some(f32::from_str_radix(value, 10).unwrap())
-                                }
-                                "height" => {
-                                    vector[level].height =
-                                        Some(value.parse().unwrap())
-                                }
-                                "width" => {
-                                    vector[level].width =
-                                        Some(value.parse().unwrap())
-                                }
-                                "tile-height" => {
-                                    vector[level].tile_height =
-                                        Some(value.parse().unwrap())
-                                }
-                                "tile-width" => {
-                                    vector[level].tile_width =
-                                        Some(value.parse().unwrap())
+                                    vector[level].downsample = f32::from_str_radix(value, 10).ok()
                                 }
+                                "height" => vector[level].height = value.parse().ok(),
+                                "width" => vector[level].width = value.parse().ok(),
+                                "tile-height" => vector[level].tile_height = value.parse().ok(),
+                                "tile-width" => vector[level].tile_width = value.parse().ok(),
                                 _ => {}
                             }
                         }
+                        Some(_) => println!("Level {} is beyond the level count", level),
                         None => println!("self.levels is unexpectedly None"),
                     }
                 }
@@ -201,6 +197,18 @@ impl OpenSlide {
         if let Some(ref val) = self.comment {
             println!("Comment: {}", val)
         }
+        if let Some(ref val) = self.bounds_x {
+            println!("Bounds x: {}", val)
+        }
+        if let Some(ref val) = self.bounds_y {
+            println!("Bounds y: {}", val)
+        }
+        if let Some(ref val) = self.bounds_width {
+            println!("Bounds width: {}", val)
+        }
+        if let Some(ref val) = self.bounds_height {
+            println!("Bounds height: {}", val)
+        }
         if let Some(ref val) = self.level_count {
             println!("Number of levels: {}", val)
         }
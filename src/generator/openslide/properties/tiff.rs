@@ -2,9 +2,10 @@
 //!
 
 use num::Num;
+use serde::Serialize;
 use std::f32;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct Tiff {
     pub image_description: Option<String>,
     pub software: Option<String>,
@@ -24,8 +25,8 @@ impl Tiff {
             "tiff.Model" => self.model = Some(String::from(value)),
             "tiff.DateTime" => self.date_time = Some(String::from(value)),
             "tiff.Make" => self.make = Some(String::from(value)),
-            "tiff.XResolution" => self.x_resolution = Some(f32::from_str_radix(value, 10).unwrap()),
-            "tiff.YResolution" => self.y_resolution = Some(f32::from_str_radix(value, 10).unwrap()),
+            "tiff.XResolution" => self.x_resolution = f32::from_str_radix(value, 10).ok(),
+            "tiff.YResolution" => self.y_resolution = f32::from_str_radix(value, 10).ok(),
             "tiff.ResolutionUnit" => self.resolution_unit = Some(String::from(value)),
             //_ => println!("Could not parse property name {} and value {}", name, value),
             _ => {}
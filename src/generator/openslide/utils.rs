@@ -1,10 +1,9 @@
 //! Misc utility definitions
 
-use byteorder::ByteOrder;
-use failure::{Error};
-use image::{Rgba, RgbaImage};
+use super::OpenSlideError;
+use image::RgbaImage;
 
-use std::fmt::{Debug};
+use std::fmt::Debug;
 
 /// A list of supported formats
 ///
@@ -76,6 +75,28 @@ pub enum WordRepresentation {
     LittleEndian,
 }
 
+/// `UNPREMULTIPLY[alpha][value]` is the color value premultiplied with `alpha` divided by it again,
+/// rounded to the nearest. Fully transparent and fully opaque values are kept as they are.
+static UNPREMULTIPLY: [[u8; 256]; 256] = unpremultiply_table();
+
+const fn unpremultiply_table() -> [[u8; 256]; 256] {
+    let mut table = [[0; 256]; 256];
+    let mut alpha = 0;
+    while alpha < 256 {
+        let mut value = 0;
+        while value < 256 {
+            table[alpha][value] = match (value * 255 + alpha / 2).checked_div(alpha) {
+                None => value as u8,
+                Some(unpremultiplied) if unpremultiplied > 255 => 255,
+                Some(unpremultiplied) => unpremultiplied as u8,
+            };
+            value += 1;
+        }
+        alpha += 1;
+    }
+    table
+}
+
 /// This function takes a buffer, as the one obtained from openslide::read_region, and decodes into
 /// an Rgba image buffer.
 pub fn decode_buffer(
@@ -83,37 +104,92 @@ pub fn decode_buffer(
     height: u32,
     width: u32,
     word_representation: WordRepresentation,
-) -> Result<RgbaImage, Error> {
+) -> Result<RgbaImage, OpenSlideError> {
     let mut rgba_image = RgbaImage::new(width, height);
+    decode_buffer_into(buffer, height, width, word_representation, &mut rgba_image)?;
+    Ok(rgba_image)
+}
 
-    for (col, row, pixel) in rgba_image.enumerate_pixels_mut() {
-        let curr_pos = row * width + col;
-        let value = buffer[curr_pos as usize];
-
-        let mut buf = [0; 4];
+/// Like `decode_buffer`, but writes the RGBA values into `output`, which holds `4 * height * width`
+/// bytes (e.g. an `RgbaImage` that is reused between reads).
+pub fn decode_buffer_into(
+    buffer: &[u32],
+    height: u32,
+    width: u32,
+    word_representation: WordRepresentation,
+    output: &mut [u8],
+) -> Result<(), OpenSlideError> {
+    let pixels = width as usize * height as usize;
+    if buffer.len() < pixels || output.len() != 4 * pixels {
+        return Err(OpenSlideError::InvalidArgument(format!(
+            "Buffers of {} words and {} bytes do not fit a {}x{} image",
+            buffer.len(),
+            output.len(),
+            width,
+            height
+        )));
+    }
+    if pixels == 0 {
+        return Ok(());
+    }
+    let rows = buffer[..pixels]
+        .chunks_exact(width as usize)
+        .zip(output.chunks_exact_mut(4 * width as usize));
+    for (words, rgba) in rows {
         match word_representation {
-            WordRepresentation::BigEndian => byteorder::BigEndian::write_u32(&mut buf, value),
-            WordRepresentation::LittleEndian => byteorder::BigEndian::write_u32(&mut buf, value),
-        };
-        let [alpha, mut red, mut green, mut blue] = buf;
-
-        if alpha != 0 && alpha != 255 {
-            red = (red as f32 * (255.0 / alpha as f32))
-                .round()
-                .max(0.0)
-                .min(255.0) as u8;
-            green = (green as f32 * (255.0 / alpha as f32))
-                .round()
-                .max(0.0)
-                .min(255.0) as u8;
-            blue = (blue as f32 * (255.0 / alpha as f32))
-                .round()
-                .max(0.0)
-                .min(255.0) as u8;
+            WordRepresentation::BigEndian => decode_row(words, rgba, u32::to_be_bytes),
+            WordRepresentation::LittleEndian => decode_row(words, rgba, u32::to_le_bytes),
         }
+    }
+    Ok(())
+}
 
-        *pixel = Rgba([red, green, blue, alpha]);
+/// Decode a row of premultiplied words, which `to_bytes` splits into `[alpha, red, green, blue]`.
+#[inline(always)]
+fn decode_row(words: &[u32], rgba: &mut [u8], to_bytes: impl Fn(u32) -> [u8; 4]) {
+    for (word, pixel) in words.iter().zip(rgba.chunks_exact_mut(4)) {
+        let [alpha, red, green, blue] = to_bytes(*word);
+        let table = &UNPREMULTIPLY[alpha as usize];
+        pixel.copy_from_slice(&[
+            table[red as usize],
+            table[green as usize],
+            table[blue as usize],
+            alpha,
+        ]);
     }
+}
 
-    Ok(rgba_image)
+#[test]
+fn test_decode_buffer() {
+    let words = [0xff102030, 0x80402010, 0x00aabbcc, 0x40ff0000];
+    let image = decode_buffer(&words, 2, 2, WordRepresentation::BigEndian).unwrap();
+    assert_eq!(image.get_pixel(0, 0).0, [0x10, 0x20, 0x30, 0xff]);
+    // 0x40 * 255 / 0x80 = 127.5, rounded up.
+    assert_eq!(image.get_pixel(1, 0).0, [128, 64, 32, 0x80]);
+    assert_eq!(image.get_pixel(0, 1).0, [0xaa, 0xbb, 0xcc, 0]);
+    // Values larger than alpha are clamped.
+    assert_eq!(image.get_pixel(1, 1).0, [255, 0, 0, 0x40]);
+
+    let swapped: Vec<u32> = words.iter().map(|word| word.swap_bytes()).collect();
+    let little = decode_buffer(&swapped, 2, 2, WordRepresentation::LittleEndian).unwrap();
+    assert_eq!(little, image);
+
+    let mut output = vec![0; 12];
+    assert!(matches!(
+        decode_buffer_into(&words, 2, 2, WordRepresentation::BigEndian, &mut output),
+        Err(OpenSlideError::InvalidArgument(_))
+    ));
+}
+
+#[test]
+fn test_unpremultiply_table() {
+    // The table matches rounding the exact quotient.
+    for alpha in 1..=255u32 {
+        for value in 0..=alpha {
+            let expected = (value as f64 * 255.0 / alpha as f64).round() as u8;
+            assert_eq!(UNPREMULTIPLY[alpha as usize][value as usize], expected);
+        }
+    }
+    assert_eq!(UNPREMULTIPLY[0][17], 17);
+    assert_eq!(UNPREMULTIPLY[255][17], 17);
 }
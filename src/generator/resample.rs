@@ -0,0 +1,139 @@
+//! Resampling of tiles read from the slide to their size in the Deep Zoom level.
+//!
+//! A Deep Zoom level between two slide levels is read from the larger one and downscaled, by up to
+//! about 2x. Which filter is best depends on the slide: area averaging keeps the texture of stained
+//! tissue, while label masks must keep their exact values and need nearest neighbour sampling.
+
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+use serde::Deserialize;
+
+/// The filters tiles can be resampled with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResampleFilter {
+    /// The nearest pixel, without mixing values.
+    Nearest,
+    /// The average of the pixels covered (box filter). Exact 2x downscales take a fast path.
+    #[default]
+    #[serde(alias = "box")]
+    Area,
+    /// Linear interpolation between the nearest pixels.
+    Bilinear,
+    /// Lanczos with a window of 3, the sharpest and slowest.
+    Lanczos3,
+}
+
+impl ResampleFilter {
+    /// Parse a filter name, e.g. from a command line flag.
+    pub fn from_name(name: &str) -> Option<ResampleFilter> {
+        match name {
+            "nearest" => Some(ResampleFilter::Nearest),
+            "area" | "box" => Some(ResampleFilter::Area),
+            "bilinear" => Some(ResampleFilter::Bilinear),
+            "lanczos3" => Some(ResampleFilter::Lanczos3),
+            _ => None,
+        }
+    }
+}
+
+/// Resample `image` to `width` by `height` pixels with `filter`.
+pub fn resize(image: &RgbaImage, width: u32, height: u32, filter: ResampleFilter) -> RgbaImage {
+    match filter {
+        ResampleFilter::Area if image.dimensions() == (width * 2, height * 2) => {
+            downscale_2x(image)
+        }
+        ResampleFilter::Area => imageops::thumbnail(image, width, height),
+        ResampleFilter::Nearest => nearest(image, width, height),
+        ResampleFilter::Bilinear => imageops::resize(image, width, height, FilterType::Triangle),
+        ResampleFilter::Lanczos3 => imageops::resize(image, width, height, FilterType::Lanczos3),
+    }
+}
+
+/// Sample the pixel nearest to the center of every output pixel.
+///
+/// This is much faster than `imageops::resize` with `FilterType::Nearest`, which runs the generic
+/// filter machinery for a single tap.
+fn nearest(image: &RgbaImage, width: u32, height: u32) -> RgbaImage {
+    let source = |position: u32, size: u32, source_size: u32| {
+        ((2 * position as u64 + 1) * source_size as u64 / (2 * size as u64)) as u32
+    };
+    let columns: Vec<u32> = (0..width)
+        .map(|x| source(x, width, image.width()))
+        .collect();
+    let mut resized = RgbaImage::new(width, height);
+    for (y, row) in resized.enumerate_rows_mut() {
+        let source_y = source(y, height, image.height());
+        for ((_, _, pixel), source_x) in row.zip(&columns) {
+            *pixel = *image.get_pixel(*source_x, source_y);
+        }
+    }
+    resized
+}
+
+/// Halve the width and height of `image`, averaging every 2x2 block of pixels. A trailing odd row
+/// or column is dropped.
+///
+/// The loops run over plain slices of channel values without branches, so that the compiler can
+/// vectorize them.
+pub fn downscale_2x(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = (image.width() / 2, image.height() / 2);
+    let stride = image.width() as usize * 4;
+    let mut pixels = vec![0; width as usize * height as usize * 4];
+    if pixels.is_empty() {
+        return RgbaImage::new(width, height);
+    }
+    let raw = image.as_raw();
+    // Sums of each channel value with the one below it.
+    let mut sums = vec![0u16; width as usize * 8];
+    for (y, out) in pixels.chunks_exact_mut(width as usize * 4).enumerate() {
+        let top = &raw[2 * y * stride..][..sums.len()];
+        let bottom = &raw[(2 * y + 1) * stride..][..sums.len()];
+        for ((sum, top), bottom) in sums.iter_mut().zip(top).zip(bottom) {
+            *sum = *top as u16 + *bottom as u16;
+        }
+        for (value, sum) in out.chunks_exact_mut(4).zip(sums.chunks_exact(8)) {
+            for channel in 0..4 {
+                value[channel] = ((sum[channel] + sum[channel + 4] + 2) >> 2) as u8;
+            }
+        }
+    }
+    RgbaImage::from_raw(width, height, pixels).expect("buffer has the size of the image")
+}
+
+#[test]
+fn test_downscale_2x() {
+    let image = RgbaImage::from_fn(5, 4, |x, y| image::Rgba([x as u8 * 10, y as u8, 255, 255]));
+    let half = downscale_2x(&image);
+    assert_eq!(half.dimensions(), (2, 2));
+    // Rounded to the nearest: (0 + 10 + 0 + 10) / 4 and (0 + 0 + 1 + 1) / 4.
+    assert_eq!(half.get_pixel(0, 0).0, [5, 1, 255, 255]);
+    assert_eq!(half.get_pixel(1, 1).0, [25, 3, 255, 255]);
+    assert_eq!(downscale_2x(&RgbaImage::new(1, 7)).dimensions(), (0, 3));
+}
+
+#[test]
+fn test_resize() {
+    // A checkerboard of label values, which only nearest neighbour sampling keeps as they are.
+    let image = RgbaImage::from_fn(8, 8, |x, y| {
+        image::Rgba([if (x + y) % 2 == 0 { 3 } else { 7 }, 0, 0, 255])
+    });
+    let nearest = resize(&image, 3, 3, ResampleFilter::Nearest);
+    assert!(nearest.pixels().all(|pixel| [3, 7].contains(&pixel[0])));
+    // Sampled at source pixels 1, 4 and 6.
+    assert_eq!(nearest.get_pixel(1, 0)[0], 7);
+    assert_eq!(nearest.get_pixel(1, 1)[0], 3);
+    let upscaled = resize(&image, 16, 16, ResampleFilter::Nearest);
+    assert_eq!(upscaled.get_pixel(15, 14)[0], 3);
+    let area = resize(&image, 4, 4, ResampleFilter::Area);
+    assert!(area.pixels().all(|pixel| pixel[0] == 5));
+    for filter in [
+        ResampleFilter::Area,
+        ResampleFilter::Bilinear,
+        ResampleFilter::Lanczos3,
+    ] {
+        assert_eq!(resize(&image, 3, 5, filter).dimensions(), (3, 5));
+    }
+    assert_eq!(ResampleFilter::from_name("box"), Some(ResampleFilter::Area));
+    assert_eq!(ResampleFilter::from_name("cubic"), None);
+}
@@ -0,0 +1,482 @@
+//! IIIF Image API 3.0.
+//!
+//! Images are requested as `{region}/{size}/{rotation}/{quality}.{format}` relative to the base
+//! URI of a slide, see <https://iiif.io/api/image/3.0/>. All features of compliance level 1 are
+//! supported, as well as the extra features listed in `EXTRA_FEATURES`.
+
+use crate::backend::SlideBackend;
+use crate::generator::openslide::OpenSlideError;
+use crate::generator::TileFormat;
+use crate::region::{read_scaled_region, Region};
+use image::{imageops, DynamicImage, GrayImage, Luma};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Largest width and height of a returned image.
+pub const MAX_SIZE: u64 = 4096;
+
+/// Width and height of the tiles advertised in `info.json`.
+pub const TILE_SIZE: u64 = 512;
+
+/// Characters that are percent-encoded in a slide ID within a URI: all but the unreserved ones.
+const ID_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Features beyond compliance level 1 that are supported.
+const EXTRA_FEATURES: &[&str] = &[
+    "mirroring",
+    "regionByPct",
+    "regionSquare",
+    "rotationBy90s",
+    "sizeByConfinedWh",
+    "sizeByPct",
+    "sizeUpscaling",
+];
+
+/// Errors of IIIF image requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IiifError {
+    /// The request is malformed or asks for something that is not supported.
+    Invalid(String),
+    /// The slide could not be read.
+    Slide(OpenSlideError),
+}
+
+impl fmt::Display for IiifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IiifError::Invalid(msg) => write!(f, "Invalid IIIF request: {}", msg),
+            IiifError::Slide(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for IiifError {}
+
+impl From<OpenSlideError> for IiifError {
+    fn from(err: OpenSlideError) -> Self {
+        IiifError::Slide(err)
+    }
+}
+
+fn invalid<T>(msg: String) -> Result<T, IiifError> {
+    Err(IiifError::Invalid(msg))
+}
+
+/// The `{region}` parameter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegionParam {
+    Full,
+    Square,
+    Pixels(u64, u64, u64, u64),
+    Percent(f64, f64, f64, f64),
+}
+
+/// The `{size}` parameter. `upscale` allows the result to be larger than the region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeParam {
+    upscale: bool,
+    kind: SizeKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SizeKind {
+    Max,
+    Width(u64),
+    Height(u64),
+    Percent(f64),
+    Exact(u64, u64),
+    Confined(u64, u64),
+}
+
+/// The `{rotation}` parameter: mirroring followed by a clockwise rotation in degrees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RotationParam {
+    mirror: bool,
+    degrees: u16,
+}
+
+/// The `{quality}` parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    Default,
+    Color,
+    Gray,
+    Bitonal,
+}
+
+/// A parsed image request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageRequest {
+    pub region: RegionParam,
+    pub size: SizeParam,
+    pub rotation: RotationParam,
+    pub quality: Quality,
+    pub format: TileFormat,
+}
+
+impl ImageRequest {
+    /// Parse the parameters of an image request, e.g. `("full", "max", "0", "default", "jpg")`.
+    pub fn parse(
+        region: &str,
+        size: &str,
+        rotation: &str,
+        quality: &str,
+        format: &str,
+    ) -> Result<ImageRequest, IiifError> {
+        Ok(ImageRequest {
+            region: parse_region(region)?,
+            size: parse_size(size)?,
+            rotation: parse_rotation(rotation)?,
+            quality: parse_quality(quality)?,
+            format: match TileFormat::from_extension(format) {
+                Some(format) => format,
+                None => return invalid(format!("unsupported format {}", format)),
+            },
+        })
+    }
+}
+
+fn parse_numbers<T: std::str::FromStr>(text: &str, count: usize) -> Option<Vec<T>> {
+    let numbers = text
+        .split(',')
+        .map(|n| n.parse().ok())
+        .collect::<Option<Vec<T>>>()?;
+    if numbers.len() == count {
+        Some(numbers)
+    } else {
+        None
+    }
+}
+
+fn parse_region(region: &str) -> Result<RegionParam, IiifError> {
+    let parsed = match region {
+        "full" => Some(RegionParam::Full),
+        "square" => Some(RegionParam::Square),
+        _ => match region.strip_prefix("pct:") {
+            Some(pct) => parse_numbers::<f64>(pct, 4)
+                .filter(|n| n.iter().all(|n| n.is_finite() && *n >= 0.0))
+                .map(|n| RegionParam::Percent(n[0], n[1], n[2], n[3])),
+            None => {
+                parse_numbers::<u64>(region, 4).map(|n| RegionParam::Pixels(n[0], n[1], n[2], n[3]))
+            }
+        },
+    };
+    match parsed {
+        Some(parsed) => Ok(parsed),
+        None => invalid(format!("invalid region {}", region)),
+    }
+}
+
+fn parse_size(size: &str) -> Result<SizeParam, IiifError> {
+    let (upscale, rest) = match size.strip_prefix('^') {
+        Some(rest) => (true, rest),
+        None => (false, size),
+    };
+    let kind = if rest == "max" {
+        Some(SizeKind::Max)
+    } else if let Some(pct) = rest.strip_prefix("pct:") {
+        pct.parse::<f64>()
+            .ok()
+            .filter(|pct| pct.is_finite() && *pct > 0.0)
+            .map(SizeKind::Percent)
+    } else if let Some(wh) = rest.strip_prefix('!') {
+        parse_numbers::<u64>(wh, 2).map(|n| SizeKind::Confined(n[0], n[1]))
+    } else if let Some(w) = rest.strip_suffix(',') {
+        w.parse().ok().map(SizeKind::Width)
+    } else if let Some(h) = rest.strip_prefix(',') {
+        h.parse().ok().map(SizeKind::Height)
+    } else {
+        parse_numbers::<u64>(rest, 2).map(|n| SizeKind::Exact(n[0], n[1]))
+    };
+    match kind {
+        Some(kind) => Ok(SizeParam { upscale, kind }),
+        None => invalid(format!("invalid size {}", size)),
+    }
+}
+
+fn parse_rotation(rotation: &str) -> Result<RotationParam, IiifError> {
+    let (mirror, degrees) = match rotation.strip_prefix('!') {
+        Some(degrees) => (true, degrees),
+        None => (false, rotation),
+    };
+    match degrees.parse::<f64>() {
+        Ok(degrees) if [0.0, 90.0, 180.0, 270.0, 360.0].contains(&degrees) => Ok(RotationParam {
+            mirror,
+            degrees: degrees as u16 % 360,
+        }),
+        _ => invalid(format!(
+            "unsupported rotation {}, only multiples of 90 are supported",
+            rotation
+        )),
+    }
+}
+
+fn parse_quality(quality: &str) -> Result<Quality, IiifError> {
+    match quality {
+        "default" => Ok(Quality::Default),
+        "color" => Ok(Quality::Color),
+        "gray" => Ok(Quality::Gray),
+        "bitonal" => Ok(Quality::Bitonal),
+        _ => invalid(format!("unsupported quality {}", quality)),
+    }
+}
+
+impl RegionParam {
+    /// The region in level 0 pixels of an image with the given dimensions, clipped to the image.
+    pub fn resolve(&self, (width, height): (u64, u64)) -> Result<Region, IiifError> {
+        let region = match *self {
+            RegionParam::Full => Region::new(0, 0, width, height),
+            RegionParam::Square => {
+                let side = width.min(height);
+                Region::new((width - side) / 2, (height - side) / 2, side, side)
+            }
+            RegionParam::Pixels(x, y, w, h) => Region::new(x, y, w, h),
+            RegionParam::Percent(x, y, w, h) => {
+                let scale = |pct: f64, size: u64| (pct / 100.0 * size as f64).round() as u64;
+                Region::new(
+                    scale(x, width),
+                    scale(y, height),
+                    scale(w, width),
+                    scale(h, height),
+                )
+            }
+        };
+        match region.clip((width, height)) {
+            Some(region) => Ok(region),
+            None => invalid(format!("region {:?} is outside of the image", self)),
+        }
+    }
+}
+
+impl SizeParam {
+    /// The output (width, height) for a region of the given dimensions.
+    pub fn resolve(&self, (width, height): (u64, u64)) -> Result<(u64, u64), IiifError> {
+        let (w, h) = (width as f64, height as f64);
+        // Size that fits in (max_w, max_h) while keeping the aspect ratio, and that is no larger
+        // than the region unless upscaling is allowed.
+        let confine = |max_w: f64, max_h: f64| {
+            let mut scale = (max_w / w).min(max_h / h);
+            if !self.upscale {
+                scale = scale.min(1.0);
+            }
+            ((w * scale).round(), (h * scale).round())
+        };
+        let (out_w, out_h) = match self.kind {
+            SizeKind::Max => {
+                let max = MAX_SIZE as f64;
+                confine(max, max)
+            }
+            SizeKind::Width(out_w) => (out_w as f64, (h * out_w as f64 / w).round()),
+            SizeKind::Height(out_h) => ((w * out_h as f64 / h).round(), out_h as f64),
+            SizeKind::Percent(pct) => ((w * pct / 100.0).round(), (h * pct / 100.0).round()),
+            SizeKind::Exact(out_w, out_h) => (out_w as f64, out_h as f64),
+            SizeKind::Confined(max_w, max_h) => confine(max_w as f64, max_h as f64),
+        };
+        let (out_w, out_h) = (out_w.max(1.0) as u64, out_h.max(1.0) as u64);
+        if !self.upscale && (out_w > width || out_h > height) {
+            return invalid(format!(
+                "size {}x{} is larger than the region, use ^ to upscale",
+                out_w, out_h
+            ));
+        }
+        if out_w > MAX_SIZE || out_h > MAX_SIZE {
+            return invalid(format!(
+                "size {}x{} exceeds the maximum of {}x{}",
+                out_w, out_h, MAX_SIZE, MAX_SIZE
+            ));
+        }
+        Ok((out_w, out_h))
+    }
+}
+
+/// Render the requested image from a slide. The result still has to be encoded in
+/// `request.format`.
+pub fn render(wsi: &dyn SlideBackend, request: &ImageRequest) -> Result<DynamicImage, IiifError> {
+    let region = request.region.resolve(wsi.level0_dimensions()?)?;
+    let (out_w, out_h) = request.size.resolve((region.width, region.height))?;
+    let image = read_scaled_region(wsi, region, (out_w as u32, out_h as u32))?;
+
+    let image = if request.rotation.mirror {
+        imageops::flip_horizontal(&image)
+    } else {
+        image
+    };
+    let image = match request.rotation.degrees {
+        90 => imageops::rotate90(&image),
+        180 => imageops::rotate180(&image),
+        270 => imageops::rotate270(&image),
+        _ => image,
+    };
+    let image = DynamicImage::ImageRgba8(image);
+    Ok(match request.quality {
+        Quality::Default | Quality::Color => image,
+        Quality::Gray => DynamicImage::ImageLuma8(image.to_luma8()),
+        Quality::Bitonal => {
+            let gray = image.to_luma8();
+            DynamicImage::ImageLuma8(GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+                Luma([if gray.get_pixel(x, y)[0] < 128 {
+                    0
+                } else {
+                    255
+                }])
+            }))
+        }
+    })
+}
+
+/// The base URI of a slide, under the IIIF endpoint at `base` (e.g. `http://host/iiif`).
+pub fn slide_uri(base: &str, slide: &str) -> String {
+    format!("{}/{}", base, utf8_percent_encode(slide, ID_ENCODE_SET))
+}
+
+/// The `info.json` of a slide, whose base URI is `id`.
+///
+/// The slide levels are advertised as the tile scale factors, and their dimensions as the
+/// preferred sizes of the full image.
+pub fn info(id: &str, wsi: &dyn SlideBackend) -> Result<Value, IiifError> {
+    let (width, height) = wsi.level0_dimensions()?;
+    let mut scale_factors: Vec<u64> = Vec::new();
+    let mut sizes = Vec::new();
+    for level in 0..wsi.level_count()? {
+        let factor = (wsi.level_downsample(level)?.round() as u64).max(1);
+        if !scale_factors.contains(&factor) {
+            scale_factors.push(factor);
+        }
+        let (level_w, level_h) = wsi.level_dimensions(level)?;
+        if level_w <= MAX_SIZE && level_h <= MAX_SIZE {
+            sizes.push(json!({ "width": level_w, "height": level_h }));
+        }
+    }
+    scale_factors.sort_unstable();
+    sizes.reverse();
+
+    Ok(json!({
+        "@context": "http://iiif.io/api/image/3/context.json",
+        "id": id,
+        "type": "ImageService3",
+        "protocol": "http://iiif.io/api/image",
+        "profile": "level1",
+        "width": width,
+        "height": height,
+        "maxWidth": MAX_SIZE,
+        "maxHeight": MAX_SIZE,
+        "sizes": sizes,
+        "tiles": [{
+            "width": TILE_SIZE,
+            "height": TILE_SIZE,
+            "scaleFactors": scale_factors,
+        }],
+        "extraQualities": ["color", "gray", "bitonal"],
+        "extraFormats": ["png", "webp"],
+        "extraFeatures": EXTRA_FEATURES,
+    }))
+}
+
+#[test]
+fn test_parse_image_request() {
+    let request = ImageRequest::parse("full", "max", "0", "default", "jpg").unwrap();
+    assert_eq!(request.region, RegionParam::Full);
+    assert_eq!(request.rotation.degrees, 0);
+    assert_eq!(request.quality, Quality::Default);
+    assert_eq!(request.format, TileFormat::Jpeg);
+
+    let request = ImageRequest::parse("10,20,300,400", "^!200,100", "!90", "gray", "png").unwrap();
+    assert_eq!(request.region, RegionParam::Pixels(10, 20, 300, 400));
+    assert_eq!(
+        request.size,
+        SizeParam {
+            upscale: true,
+            kind: SizeKind::Confined(200, 100)
+        }
+    );
+    assert_eq!(
+        request.rotation,
+        RotationParam {
+            mirror: true,
+            degrees: 90
+        }
+    );
+
+    assert_eq!(parse_size("256,").unwrap().kind, SizeKind::Width(256));
+    assert_eq!(parse_size(",256").unwrap().kind, SizeKind::Height(256));
+    assert_eq!(parse_size("pct:50").unwrap().kind, SizeKind::Percent(50.0));
+    assert_eq!(
+        parse_size("256,128").unwrap().kind,
+        SizeKind::Exact(256, 128)
+    );
+    assert_eq!(
+        parse_region("pct:0,0,50,50").unwrap(),
+        RegionParam::Percent(0.0, 0.0, 50.0, 50.0)
+    );
+
+    for (region, size, rotation, quality, format) in [
+        ("full,", "max", "0", "default", "jpg"),
+        ("0,0,10", "max", "0", "default", "jpg"),
+        ("full", "pct:0", "0", "default", "jpg"),
+        ("full", "a,b", "0", "default", "jpg"),
+        ("full", "max", "45", "default", "jpg"),
+        ("full", "max", "0", "sepia", "jpg"),
+        ("full", "max", "0", "default", "gif"),
+    ] {
+        assert!(ImageRequest::parse(region, size, rotation, quality, format).is_err());
+    }
+}
+
+#[test]
+fn test_resolve_region_and_size() {
+    let image = (1000, 500);
+    assert_eq!(
+        RegionParam::Full.resolve(image).unwrap(),
+        Region::new(0, 0, 1000, 500)
+    );
+    assert_eq!(
+        RegionParam::Square.resolve(image).unwrap(),
+        Region::new(250, 0, 500, 500)
+    );
+    assert_eq!(
+        RegionParam::Pixels(900, 400, 200, 200)
+            .resolve(image)
+            .unwrap(),
+        Region::new(900, 400, 100, 100)
+    );
+    assert_eq!(
+        RegionParam::Percent(50.0, 50.0, 50.0, 50.0)
+            .resolve(image)
+            .unwrap(),
+        Region::new(500, 250, 500, 250)
+    );
+    assert!(RegionParam::Pixels(1000, 0, 10, 10).resolve(image).is_err());
+
+    let size = |text: &str| parse_size(text).unwrap().resolve(image);
+    assert_eq!(size("max").unwrap(), (1000, 500));
+    assert_eq!(size("500,").unwrap(), (500, 250));
+    assert_eq!(size(",100").unwrap(), (200, 100));
+    assert_eq!(size("pct:10").unwrap(), (100, 50));
+    assert_eq!(size("!200,200").unwrap(), (200, 100));
+    // Confined sizes larger than the region are not upscaled, unless asked to.
+    assert_eq!(size("!2000,2000").unwrap(), (1000, 500));
+    assert_eq!(size("^!2000,2000").unwrap(), (2000, 1000));
+    assert_eq!(size("300,300").unwrap(), (300, 300));
+    assert!(size("2000,").is_err());
+    assert_eq!(size("^2000,").unwrap(), (2000, 1000));
+    assert_eq!(size("^max").unwrap(), (4096, 2048));
+    assert!(size("^8192,").is_err());
+    assert_eq!(
+        parse_size("max").unwrap().resolve((10000, 5000)).unwrap(),
+        (4096, 2048)
+    );
+}
+
+#[test]
+fn test_slide_uri() {
+    assert_eq!(
+        slide_uri("http://host/iiif", "lung~case 12~HE.svs"),
+        "http://host/iiif/lung~case%2012~HE.svs"
+    );
+    assert_eq!(slide_uri("/iiif", "a/b#c?.svs"), "/iiif/a%2Fb%23c%3F.svs");
+}
@@ -1 +1,9 @@
+pub mod backend;
+pub mod cache;
+pub mod config;
+pub mod export;
 pub mod generator;
+pub mod iiif;
+pub mod pool;
+pub mod region;
+pub mod slides;
@@ -1,17 +1,44 @@
-mod generator;
-
 use actix_files as fs;
 use actix_web::{
     error,
     http::{header::ContentType, StatusCode},
-    middleware, web, App, HttpResponse, HttpServer,
+    middleware, web, App, HttpRequest, HttpResponse, HttpServer,
 };
+use bytes::Bytes;
+use clap::{Args, Parser, Subcommand};
 use derive_more::{Display, Error};
 use env_logger::Env;
-use generator::DeepZoomGenerator;
-use image::ImageOutputFormat;
-use log::error;
-use std::{collections::HashMap, path::Path};
+use image::{DynamicImage, ImageOutputFormat};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use slidestream::{
+    backend,
+    cache::{TileCache, TileKey},
+    config::{Config, ConfigError, TileConfig},
+    export::{
+        deepzoom::DeepZoomExport,
+        tiff::{TiffCompression, TiffExport},
+        zarr::{ZarrExport, ZarrLevels},
+    },
+    generator::{
+        openslide::{properties::Properties, OpenSlideError},
+        resample::ResampleFilter,
+        DeepZoomGenerator, DeepZoomGeneratorOptions, GeneratorError, TileFormat,
+    },
+    iiif::{self, IiifError, ImageRequest},
+    pool::{BlockingPool, PoolError},
+    region::{self, Region, RegionError, RegionFormat, Resolution},
+    slides::{find_slides_in, RegistryError, Slide, SlideRegistry, DEFAULT_GEOMETRY},
+};
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    time::Duration,
+};
 
 #[derive(Debug, Display, Error)]
 enum DZIRetrievalError {
@@ -23,6 +50,21 @@ enum DZIRetrievalError {
 
     #[display(fmt = "Could not find slide.")]
     SlideNotFound,
+
+    #[display(fmt = "Could not find tile geometry.")]
+    GeometryNotFound,
+
+    #[display(fmt = "Could not find associated image.")]
+    AssociatedImageNotFound,
+
+    #[display(fmt = "Image format not supported.")]
+    UnsupportedFormat,
+
+    #[display(fmt = "Too many requests queued, try again later.")]
+    Busy,
+
+    #[display(fmt = "Invalid request: {}", _0)]
+    InvalidRequest(#[error(not(source))] String),
 }
 
 impl error::ResponseError for DZIRetrievalError {
@@ -37,83 +79,1094 @@ impl error::ResponseError for DZIRetrievalError {
             DZIRetrievalError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
             DZIRetrievalError::TileRequestInvalid => StatusCode::BAD_REQUEST,
             DZIRetrievalError::SlideNotFound => StatusCode::NOT_FOUND,
+            DZIRetrievalError::GeometryNotFound => StatusCode::NOT_FOUND,
+            DZIRetrievalError::AssociatedImageNotFound => StatusCode::NOT_FOUND,
+            DZIRetrievalError::UnsupportedFormat => StatusCode::BAD_REQUEST,
+            DZIRetrievalError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            DZIRetrievalError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl From<GeneratorError> for DZIRetrievalError {
+    fn from(err: GeneratorError) -> Self {
+        match err {
+            GeneratorError::LevelOutOfRange { .. } | GeneratorError::TileOutOfRange { .. } => {
+                DZIRetrievalError::TileRequestInvalid
+            }
+            GeneratorError::Slide(err) => err.into(),
+            GeneratorError::InvalidOptions(_) | GeneratorError::Encode(_) => {
+                DZIRetrievalError::InternalError
+            }
+        }
+    }
+}
+
+impl From<IiifError> for DZIRetrievalError {
+    fn from(err: IiifError) -> Self {
+        match err {
+            IiifError::Invalid(msg) => DZIRetrievalError::InvalidRequest(msg),
+            IiifError::Slide(err) => err.into(),
         }
     }
 }
 
+impl From<RegionError> for DZIRetrievalError {
+    fn from(err: RegionError) -> Self {
+        match err {
+            RegionError::Invalid(msg) => DZIRetrievalError::InvalidRequest(msg),
+            RegionError::Slide(err) => err.into(),
+            RegionError::Encode(_) => DZIRetrievalError::InternalError,
+        }
+    }
+}
+
+impl From<OpenSlideError> for DZIRetrievalError {
+    fn from(err: OpenSlideError) -> Self {
+        match err {
+            OpenSlideError::NotFound(_) => DZIRetrievalError::SlideNotFound,
+            OpenSlideError::AssociatedImageNotFound(_) => {
+                DZIRetrievalError::AssociatedImageNotFound
+            }
+            OpenSlideError::LevelOutOfRange { .. } | OpenSlideError::InvalidArgument(_) => {
+                DZIRetrievalError::TileRequestInvalid
+            }
+            OpenSlideError::Unsupported(_)
+            | OpenSlideError::InvalidPath(_)
+            | OpenSlideError::Read(_)
+            | OpenSlideError::Failed(_) => DZIRetrievalError::InternalError,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TileQuery {
+    quality: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TilePath {
+    geometry: Option<String>,
+    slide: String,
+    level: u64,
+    col: u64,
+    row: u64,
+    format: String,
+}
+
 async fn get_tile(
-    viewers: web::Data<HashMap<String, DeepZoomGenerator>>,
-    path: web::Path<(String, u64, u64, u64)>,
+    registry: web::Data<SlideRegistry>,
+    cache: web::Data<TileCache>,
+    pool: web::Data<BlockingPool>,
+    settings: web::Data<TileConfig>,
+    path: web::Path<TilePath>,
+    query: web::Query<TileQuery>,
 ) -> Result<HttpResponse, DZIRetrievalError> {
-    let (slide, level, col, row) = path.into_inner();
-    let gen = viewers.get(&slide).expect("slide not found");
-    let tile = match gen.get_tile(level, col, row) {
-        Ok(tile) => tile,
-        Err(err) => {
-            error!("Could not retrieve tile: {:?}", err);
-            return Err(DZIRetrievalError::TileRequestInvalid);
+    let TilePath {
+        geometry,
+        slide,
+        level,
+        col,
+        row,
+        format,
+    } = path.into_inner();
+    let format = TileFormat::from_extension(&format).ok_or(DZIRetrievalError::UnsupportedFormat)?;
+    let quality = query.quality.unwrap_or(settings.quality);
+    if !(1..=100).contains(&quality) {
+        return Err(DZIRetrievalError::TileRequestInvalid);
+    }
+    let geometry = geometry.as_deref().unwrap_or(DEFAULT_GEOMETRY);
+    if !registry.geometries().contains_key(geometry) {
+        error!("Unknown tile geometry: {}", geometry);
+        return Err(DZIRetrievalError::GeometryNotFound);
+    }
+
+    let key = TileKey::new(&slide, geometry, level, col, row, format, quality);
+    if let Some(buffer) = cache.get(&key) {
+        return Ok(tile_response(format, buffer));
+    }
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+    let gen = find_generator(&viewer, geometry)?;
+
+    // Reading and encoding the tile blocks, so it is done on the blocking pool. The tile is
+    // cached there too, so it is not wasted if the client has gone away in the meantime.
+    let buffer = pool
+        .run(move || -> Result<Bytes, DZIRetrievalError> {
+            let retrieve_error = |err: GeneratorError| {
+                error!("Could not retrieve tile: {}", err);
+                DZIRetrievalError::from(err)
+            };
+            // JPEG tiles stored in the slide are served as they are, whatever the quality.
+            let stored = match format {
+                TileFormat::Jpeg => gen.get_jpeg_tile(level, col, row).map_err(retrieve_error)?,
+                _ => None,
+            };
+            let buffer = match stored {
+                Some(buffer) => buffer,
+                None => {
+                    let tile = gen.get_tile(level, col, row).map_err(retrieve_error)?;
+                    format.encode(&tile, quality).map_err(|err| {
+                        error!("{:?} conversion failed: {}", format, err);
+                        DZIRetrievalError::from(err)
+                    })?
+                }
+            };
+            let buffer = Bytes::from(buffer);
+            cache.insert(key, buffer.clone());
+            Ok(buffer)
+        })
+        .await
+        .map_err(pool_error)??;
+
+    Ok(tile_response(format, buffer))
+}
+
+fn tile_response(format: TileFormat, buffer: Bytes) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(format.mime_type())
+        // TODO: caching is very aggressive and not private. Ensure URL is unique.
+        .insert_header(("Cache-Control", "public, max-age=604800, immutable"))
+        .body(buffer)
+}
+
+async fn get_cache_stats(cache: web::Data<TileCache>) -> HttpResponse {
+    HttpResponse::Ok().json(cache.stats())
+}
+
+#[derive(Debug, Deserialize)]
+struct DziPath {
+    geometry: Option<String>,
+    slide: String,
+}
+
+async fn get_dzi(
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    settings: web::Data<TileConfig>,
+    path: web::Path<DziPath>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    let DziPath { geometry, slide } = path.into_inner();
+    let geometry = geometry.as_deref().unwrap_or(DEFAULT_GEOMETRY);
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+    let gen = find_generator(&viewer, geometry)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        // TODO: caching is very aggressive and not private. Ensure URL is unique.
+        .insert_header(("Cache-Control", "public, max-age=604800, immutable"))
+        .body(gen.get_dzi(settings.format)))
+}
+
+/// The DZI descriptor in JSON form, as understood by OpenSeadragon.
+async fn get_dzi_json(
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    settings: web::Data<TileConfig>,
+    path: web::Path<DziPath>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    let DziPath { geometry, slide } = path.into_inner();
+    let geometry = geometry.as_deref().unwrap_or(DEFAULT_GEOMETRY);
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+    let gen = find_generator(&viewer, geometry)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        // TODO: caching is very aggressive and not private. Ensure URL is unique.
+        .insert_header(("Cache-Control", "public, max-age=604800, immutable"))
+        .body(gen.get_dzi_json(settings.format)))
+}
+
+/// Get a slide from the registry, opening it on the blocking pool if it is not open yet.
+async fn open_slide(
+    registry: &web::Data<SlideRegistry>,
+    pool: &BlockingPool,
+    slide: &str,
+) -> Result<Arc<Slide>, DZIRetrievalError> {
+    if let Some(opened) = registry.get_open(slide) {
+        return Ok(opened);
+    }
+    if !registry.contains(slide) {
+        error!("Could not find slide: {}", slide);
+        return Err(DZIRetrievalError::SlideNotFound);
+    }
+    let id = slide.to_string();
+    let registry = registry.clone();
+    pool.run(move || registry.get(&id))
+        .await
+        .map_err(pool_error)?
+        .map_err(|err| {
+            error!("Could not get slide {}: {}", slide, err);
+            match err {
+                RegistryError::NotFound => DZIRetrievalError::SlideNotFound,
+                RegistryError::Open(err) => err.into(),
+            }
+        })
+}
+
+fn find_generator(
+    slide: &Slide,
+    geometry: &str,
+) -> Result<Arc<DeepZoomGenerator>, DZIRetrievalError> {
+    match slide.generator(geometry) {
+        Some(gen) => Ok(gen.clone()),
+        None => {
+            error!("Unknown tile geometry: {}", geometry);
+            Err(DZIRetrievalError::GeometryNotFound)
         }
-    };
+    }
+}
 
-    let mut buffer = Vec::new();
-    match tile.write_to(&mut buffer, ImageOutputFormat::Jpeg(80)) {
-        Ok(()) => (),
-        Err(err) => {
-            error!("Jpeg conversion failed: {:?}", err);
-            return Err(DZIRetrievalError::InternalError);
+fn pool_error(err: PoolError) -> DZIRetrievalError {
+    error!("Blocking work failed: {}", err);
+    match err {
+        PoolError::Busy => DZIRetrievalError::Busy,
+        PoolError::Stopped | PoolError::Panicked => DZIRetrievalError::InternalError,
+    }
+}
+
+async fn iiif_redirect(path: web::Path<String>) -> HttpResponse {
+    let uri = iiif::slide_uri("/iiif", &path.into_inner());
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("{}/info.json", uri)))
+        .finish()
+}
+
+async fn get_iiif_info(
+    req: HttpRequest,
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    let slide = path.into_inner();
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+    let connection = req.connection_info();
+    let base = format!("{}://{}/iiif", connection.scheme(), connection.host());
+    let id = iiif::slide_uri(&base, &slide);
+    let info = iiif::info(&id, viewer.wsi()).map_err(|err| {
+        error!("Could not describe slide {}: {}", slide, err);
+        DZIRetrievalError::from(err)
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/ld+json;profile=\"http://iiif.io/api/image/3/context.json\"")
+        .json(info))
+}
+
+async fn get_iiif_image(
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    settings: web::Data<TileConfig>,
+    path: web::Path<(String, String, String, String, String, String)>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    let (slide, region, size, rotation, quality, format) = path.into_inner();
+    let request = ImageRequest::parse(&region, &size, &rotation, &quality, &format)?;
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+    let jpeg_quality = settings.quality;
+
+    let buffer = pool
+        .run(move || -> Result<Vec<u8>, DZIRetrievalError> {
+            let image = iiif::render(viewer.wsi(), &request).map_err(|err| {
+                error!("Could not render IIIF image of {}: {}", slide, err);
+                DZIRetrievalError::from(err)
+            })?;
+            request.format.encode(&image, jpeg_quality).map_err(|err| {
+                error!("{:?} conversion failed: {}", request.format, err);
+                DZIRetrievalError::from(err)
+            })
+        })
+        .await
+        .map_err(pool_error)??;
+
+    Ok(HttpResponse::Ok()
+        .content_type(request.format.mime_type())
+        .insert_header(("Cache-Control", "public, max-age=604800, immutable"))
+        .body(buffer))
+}
+
+#[derive(Debug, Deserialize)]
+struct RegionQuery {
+    x: u64,
+    y: u64,
+    w: u32,
+    h: u32,
+    level: Option<u32>,
+    mpp: Option<f64>,
+    format: Option<String>,
+}
+
+/// A region of a slide at a slide level or at a resolution in microns per pixel.
+async fn get_region(
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    path: web::Path<String>,
+    query: web::Query<RegionQuery>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    let slide = path.into_inner();
+    let query = query.into_inner();
+    let resolution = match (query.level, query.mpp) {
+        (Some(_), Some(_)) => {
+            return Err(DZIRetrievalError::InvalidRequest(
+                "give either a level or a resolution in mpp, not both".to_string(),
+            ))
+        }
+        (_, Some(mpp)) => Resolution::Mpp(mpp),
+        (level, None) => Resolution::Level(level.unwrap_or(0)),
+    };
+    let format = match query.format.as_deref() {
+        None => RegionFormat::Png,
+        Some(format) => {
+            RegionFormat::from_extension(format).ok_or(DZIRetrievalError::UnsupportedFormat)?
         }
     };
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+
+    let buffer = pool
+        .run(move || -> Result<Vec<u8>, DZIRetrievalError> {
+            let image = region::extract_region(
+                viewer.wsi(),
+                (query.x, query.y),
+                (query.w, query.h),
+                resolution,
+            )
+            .map_err(|err| {
+                error!("Could not extract region of {}: {}", slide, err);
+                DZIRetrievalError::from(err)
+            })?;
+            format.encode(&image).map_err(|err| {
+                error!("{:?} conversion failed: {}", format, err);
+                DZIRetrievalError::from(err)
+            })
+        })
+        .await
+        .map_err(pool_error)??;
 
     Ok(HttpResponse::Ok()
-        .content_type(ContentType::jpeg())
-        // TODO: caching is very aggressive and not private. Ensure URL is unique.
+        .content_type(format.mime_type())
         .insert_header(("Cache-Control", "public, max-age=604800, immutable"))
         .body(buffer))
 }
 
-async fn get_dzi(
-    viewers: web::Data<HashMap<String, DeepZoomGenerator>>,
+#[derive(Debug, Deserialize)]
+struct ThumbnailQuery {
+    max: Option<u32>,
+    format: Option<String>,
+}
+
+/// A thumbnail of a slide, at most `max` (default 512) pixels wide and high.
+async fn get_thumbnail(
+    registry: web::Data<SlideRegistry>,
+    cache: web::Data<TileCache>,
+    pool: web::Data<BlockingPool>,
+    settings: web::Data<TileConfig>,
     path: web::Path<String>,
+    query: web::Query<ThumbnailQuery>,
 ) -> Result<HttpResponse, DZIRetrievalError> {
     let slide = path.into_inner();
-    let gen = match viewers.get(slide.as_str()) {
-        Some(tile) => tile,
-        None => {
-            error!("Could not find slide: {}", slide);
-            return Err(DZIRetrievalError::SlideNotFound);
+    let max_size = query.max.unwrap_or(512);
+    let format = match query.format.as_deref() {
+        None => TileFormat::Jpeg,
+        Some(format) => {
+            TileFormat::from_extension(format).ok_or(DZIRetrievalError::UnsupportedFormat)?
+        }
+    };
+    let quality = settings.quality;
+
+    let key = TileKey::thumbnail(&slide, max_size, format, quality);
+    if let Some(buffer) = cache.get(&key) {
+        return Ok(tile_response(format, buffer));
+    }
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+
+    let buffer = pool
+        .run(move || -> Result<Bytes, DZIRetrievalError> {
+            let image = region::thumbnail(viewer.wsi(), max_size).map_err(|err| {
+                error!("Could not make thumbnail of {}: {}", slide, err);
+                DZIRetrievalError::from(err)
+            })?;
+            let buffer = format
+                .encode(&DynamicImage::ImageRgb8(image), quality)
+                .map_err(|err| {
+                    error!("{:?} conversion failed: {}", format, err);
+                    DZIRetrievalError::from(err)
+                })?;
+            let buffer = Bytes::from(buffer);
+            cache.insert(key, buffer.clone());
+            Ok(buffer)
+        })
+        .await
+        .map_err(pool_error)??;
+
+    Ok(tile_response(format, buffer))
+}
+
+#[derive(Debug, Deserialize)]
+struct SlidesQuery {
+    open: Option<bool>,
+}
+
+async fn list_slides(
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    query: web::Query<SlidesQuery>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    // Slides that have not been summarized yet are opened on the blocking pool, unless the client
+    // asked for only what is known without opening any slide (`?open=false`). Summaries are kept,
+    // so every slide is only opened once for the catalog.
+    let summaries: Vec<_> = if query.open.unwrap_or(true) {
+        let registry = registry.clone();
+        pool.run(move || {
+            registry
+                .ids()
+                .filter_map(|id| registry.summarize(id))
+                .collect()
+        })
+        .await
+        .map_err(pool_error)?
+    } else {
+        registry
+            .ids()
+            .filter_map(|id| registry.summary(id))
+            .collect()
+    };
+    Ok(HttpResponse::Ok().json(summaries))
+}
+
+async fn get_properties(
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    let slide = path.into_inner();
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+    let raw = match viewer.wsi().properties() {
+        Ok(raw) => raw,
+        Err(err) => {
+            error!("Could not read properties of slide {}: {}", slide, err);
+            return Err(err.into());
+        }
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "id": slide,
+        "properties": Properties::new(&raw),
+        "raw": raw.into_iter().collect::<BTreeMap<_, _>>(),
+    })))
+}
+
+async fn list_associated_images(
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    let slide = path.into_inner();
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+    match viewer.wsi().associated_image_names() {
+        Ok(names) => Ok(HttpResponse::Ok().json(names)),
+        Err(err) => {
+            error!("Could not list associated images of {}: {}", slide, err);
+            Err(err.into())
         }
+    }
+}
+
+async fn get_associated_image(
+    registry: web::Data<SlideRegistry>,
+    pool: web::Data<BlockingPool>,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, DZIRetrievalError> {
+    let (slide, name, format) = path.into_inner();
+    let (output_format, content_type) = match format.as_str() {
+        "png" => (ImageOutputFormat::Png, ContentType::png()),
+        "jpg" | "jpeg" => (ImageOutputFormat::Jpeg(90), ContentType::jpeg()),
+        _ => return Err(DZIRetrievalError::UnsupportedFormat),
     };
+    let viewer = open_slide(&registry, &pool, &slide).await?;
+
+    // The backend checks that the image exists.
+    let buffer = pool
+        .run(move || {
+            let image = match viewer.wsi().read_associated_image(&name) {
+                Ok(image) => image,
+                Err(OpenSlideError::AssociatedImageNotFound(_)) => {
+                    return Err(DZIRetrievalError::AssociatedImageNotFound);
+                }
+                Err(err) => {
+                    error!(
+                        "Could not read associated image {} of {}: {}",
+                        name, slide, err
+                    );
+                    return Err(err.into());
+                }
+            };
+
+            let mut buffer = Vec::new();
+            match DynamicImage::ImageRgba8(image).write_to(&mut buffer, output_format) {
+                Ok(()) => Ok(buffer),
+                Err(err) => {
+                    error!("Image conversion failed: {:?}", err);
+                    Err(DZIRetrievalError::InternalError)
+                }
+            }
+        })
+        .await
+        .map_err(pool_error)??;
+
     Ok(HttpResponse::Ok()
-        // TODO: caching is very aggressive and not private. Ensure URL is unique.
+        .content_type(content_type)
         .insert_header(("Cache-Control", "public, max-age=604800, immutable"))
-        .body(gen.get_dzi()))
+        .body(buffer))
+}
+
+/// The tile geometries every slide is served with, by name.
+///
+/// The default geometry is served at `/{slide}.dzi`, all geometries (including the default) at
+/// `/dz/{geometry}/{slide}.dzi`.
+fn tile_geometries(resample: ResampleFilter) -> BTreeMap<String, DeepZoomGeneratorOptions> {
+    let default = DeepZoomGeneratorOptions::default()
+        .limit_bounds(true)
+        .resample(resample);
+    BTreeMap::from([
+        (DEFAULT_GEOMETRY.to_string(), default),
+        // Fewer, larger tiles for fast viewing.
+        ("large".to_string(), default.tile_size(510).overlap(1)),
+        // Non-overlapping tiles of the input size of common ML models.
+        ("ml".to_string(), default.tile_size(224).overlap(0)),
+        // The tiles stored in most Aperio and generic TIFF slides, served without re-encoding.
+        ("svs".to_string(), default.tile_size(240).overlap(0)),
+        ("tiff".to_string(), default.tile_size(256).overlap(0)),
+    ])
+}
+
+/// Serve whole slide images to the browser using the DeepZoom protocol.
+///
+/// Settings given as flags override those in the config file.
+#[derive(Debug, Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+// The variant names are the subcommand names.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Write the Deep Zoom pyramid of a slide to a directory, e.g. for static hosting.
+    ExportDz(ExportDzArgs),
+    /// Write a slide, or a region of it, to an OME-Zarr multiscale image.
+    ExportZarr(ExportZarrArgs),
+    /// Write a slide, or a region of it, to a tiled pyramidal BigTIFF.
+    ExportTiff(ExportTiffArgs),
+}
+
+/// Serve slides (the default command).
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// Slide files, or directories that are searched for slides.
+    slides: Vec<PathBuf>,
+
+    /// TOML config file.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Host name or IP address to listen on [default: localhost].
+    #[arg(short, long)]
+    bind: Option<String>,
+
+    /// Port to listen on [default: 8080].
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Number of HTTP worker threads [default: number of CPUs].
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// Directory containing the viewer and its static assets [default: ./public].
+    #[arg(long)]
+    static_dir: Option<PathBuf>,
+
+    /// Size of the tile cache in MiB, 0 to disable [default: 256].
+    #[arg(long)]
+    tile_cache_mb: Option<usize>,
+
+    /// Maximum number of slides that are open at the same time [default: 64].
+    #[arg(long)]
+    max_open_slides: Option<usize>,
+
+    /// Close slides that have not been used for this many seconds [default: 600].
+    #[arg(long)]
+    slide_idle_timeout_secs: Option<u64>,
+
+    /// Default tile format: jpeg, png or webp [default: jpeg].
+    #[arg(long, value_parser = parse_tile_format)]
+    tile_format: Option<TileFormat>,
+
+    /// Default quality (1-100) of JPEG and WebP tiles [default: 80].
+    #[arg(long)]
+    tile_quality: Option<u8>,
+
+    /// Filter scaling tiles between slide levels: nearest, area, bilinear or lanczos3
+    /// [default: area].
+    #[arg(long, value_parser = parse_resample_filter)]
+    resample: Option<ResampleFilter>,
+
+    /// Number of threads producing tiles [default: number of CPUs].
+    #[arg(long)]
+    tile_threads: Option<usize>,
+
+    /// Number of tiles that can wait for a thread before requests are refused [default: 1024].
+    #[arg(long)]
+    tile_queue_size: Option<usize>,
+}
+
+fn parse_tile_format(format: &str) -> Result<TileFormat, String> {
+    TileFormat::from_extension(&format.to_lowercase()).ok_or_else(|| {
+        format!(
+            "unknown tile format '{}', expected jpeg, png or webp",
+            format
+        )
+    })
+}
+
+fn parse_resample_filter(filter: &str) -> Result<ResampleFilter, String> {
+    ResampleFilter::from_name(&filter.to_lowercase()).ok_or_else(|| {
+        format!(
+            "unknown resampling filter '{}', expected nearest, area, bilinear or lanczos3",
+            filter
+        )
+    })
+}
+
+impl ServeArgs {
+    /// Load the config file, if any, and apply the flags on top of it.
+    fn into_config(self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        if !self.slides.is_empty() {
+            config.slides = self.slides;
+        }
+        if let Some(bind) = self.bind {
+            config.bind = bind;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if self.workers.is_some() {
+            config.workers = self.workers;
+        }
+        if let Some(static_dir) = self.static_dir {
+            config.static_dir = static_dir;
+        }
+        if let Some(tile_cache_mb) = self.tile_cache_mb {
+            config.cache.tile_cache_mb = tile_cache_mb;
+        }
+        if let Some(max_open_slides) = self.max_open_slides {
+            config.cache.max_open_slides = max_open_slides;
+        }
+        if let Some(timeout) = self.slide_idle_timeout_secs {
+            config.cache.slide_idle_timeout_secs = timeout;
+        }
+        if let Some(format) = self.tile_format {
+            config.tiles.format = format;
+        }
+        if let Some(quality) = self.tile_quality {
+            config.tiles.quality = quality;
+        }
+        if let Some(resample) = self.resample {
+            config.tiles.resample = resample;
+        }
+        if self.tile_threads.is_some() {
+            config.tiles.threads = self.tile_threads;
+        }
+        if let Some(queue_size) = self.tile_queue_size {
+            config.tiles.queue_size = queue_size;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Args)]
+struct ExportDzArgs {
+    /// Slide file to export.
+    slide: PathBuf,
+
+    /// Directory to write `{name}.dzi` and `{name}_files/` to.
+    outdir: PathBuf,
+
+    /// Base name of the output [default: the file name of the slide].
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Tile format: jpeg, png or webp.
+    #[arg(short, long, default_value = "jpeg", value_parser = parse_tile_format)]
+    format: TileFormat,
+
+    /// Quality (1-100) of JPEG and WebP tiles.
+    #[arg(short, long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+
+    /// Width and height of the tiles, excluding the overlap.
+    #[arg(short = 's', long, default_value_t = 254)]
+    tile_size: u64,
+
+    /// Number of extra pixels on each interior edge of a tile.
+    #[arg(short, long, default_value_t = 1)]
+    overlap: u64,
+
+    /// Render the full slide rather than only its non-empty area.
+    #[arg(short = 'B', long)]
+    ignore_bounds: bool,
+
+    /// Filter scaling tiles between slide levels: nearest, area, bilinear or lanczos3.
+    #[arg(long, default_value = "area", value_parser = parse_resample_filter)]
+    resample: ResampleFilter,
+
+    /// Number of threads producing tiles [default: number of CPUs].
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+#[derive(Debug, Args)]
+struct ExportZarrArgs {
+    /// Slide file to export.
+    slide: PathBuf,
+
+    /// Zarr directory to write, e.g. `slide.ome.zarr`.
+    output: PathBuf,
+
+    /// Level 0 region to export as `x,y,width,height` [default: the whole slide].
+    #[arg(long, value_parser = parse_bbox)]
+    bbox: Option<Region>,
+
+    /// Resolutions to write: `slide` for the levels of the slide, `pow2` for power of two
+    /// downsamples.
+    #[arg(short, long, default_value = "slide", value_parser = parse_zarr_levels)]
+    levels: ZarrLevels,
+
+    /// Width and height of the chunks.
+    #[arg(short, long, default_value_t = 512)]
+    chunk_size: u32,
+
+    /// zlib compression level (0-9).
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+    compression: u32,
+
+    /// Number of threads producing chunks [default: number of CPUs].
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+#[derive(Debug, Args)]
+struct ExportTiffArgs {
+    /// Slide file to export.
+    slide: PathBuf,
+
+    /// TIFF file to write.
+    output: PathBuf,
+
+    /// Level 0 region to export as `x,y,width,height` [default: the whole slide].
+    #[arg(long, value_parser = parse_bbox)]
+    bbox: Option<Region>,
+
+    /// Width and height of the tiles, a multiple of 16.
+    #[arg(short, long, default_value_t = 512)]
+    tile_size: u32,
+
+    /// Deflate compression level (0-9), or `none` for uncompressed tiles.
+    #[arg(long, default_value = "6", value_parser = parse_tiff_compression)]
+    compression: TiffCompression,
+
+    /// Number of threads producing tiles [default: number of CPUs].
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+fn parse_tiff_compression(compression: &str) -> Result<TiffCompression, String> {
+    match compression {
+        "none" => Ok(TiffCompression::None),
+        level => match level.parse::<u32>() {
+            Ok(level) if level <= 9 => Ok(TiffCompression::Deflate(level)),
+            _ => Err(format!(
+                "invalid compression '{}', expected a level from 0 to 9 or none",
+                compression
+            )),
+        },
+    }
+}
+
+fn parse_bbox(bbox: &str) -> Result<Region, String> {
+    let values = bbox
+        .split(',')
+        .map(|value| value.trim().parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid bounding box '{}': {}", bbox, err))?;
+    match values[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok(Region::new(x, y, width, height)),
+        _ => Err(format!(
+            "invalid bounding box '{}', expected x,y,width,height",
+            bbox
+        )),
+    }
+}
+
+fn parse_zarr_levels(levels: &str) -> Result<ZarrLevels, String> {
+    match levels {
+        "slide" => Ok(ZarrLevels::Slide),
+        "pow2" => Ok(ZarrLevels::PowerOfTwo),
+        _ => Err(format!(
+            "unknown levels '{}', expected slide or pow2",
+            levels
+        )),
+    }
+}
+
+/// Number of threads for an export, defaulting to the number of CPUs.
+fn export_threads(jobs: Option<usize>) -> usize {
+    jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()))
+}
+
+/// A progress callback printing `done/total {unit} (percent%)` on stderr, redrawn only when the
+/// percentage changes.
+fn progress_printer(unit: &'static str) -> impl Fn(u64, u64) + Sync {
+    let shown = AtomicU64::new(u64::MAX);
+    move |done: u64, total: u64| {
+        let percent = done * 100 / total.max(1);
+        if shown.swap(percent, Ordering::Relaxed) != percent {
+            eprint!("\r{}/{} {} ({}%)", done, total, unit, percent);
+        }
+    }
+}
+
+/// The file name of a slide without its extension, to name exports by.
+fn slide_name(slide: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(slide
+        .file_stem()
+        .ok_or("slide path has no file name")?
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Export a slide to OME-Zarr, showing the progress on stderr.
+fn export_zarr(args: ExportZarrArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let name = slide_name(&args.slide)?;
+    let wsi = backend::open(&args.slide, ResampleFilter::default())?;
+    let export = ZarrExport {
+        levels: args.levels,
+        chunk_size: args.chunk_size,
+        compression: args.compression,
+        threads: export_threads(args.jobs),
+    };
+    info!(
+        "Exporting {} to {} with {} thread(s)",
+        args.slide.display(),
+        args.output.display(),
+        export.threads
+    );
+    export.run(
+        wsi.as_ref(),
+        args.bbox,
+        &args.output,
+        &name,
+        &progress_printer("chunks"),
+    )?;
+    eprintln!();
+    info!("Wrote {}", args.output.display());
+    Ok(())
+}
+
+/// Export a slide to a pyramidal TIFF, showing the progress on stderr.
+fn export_tiff(args: ExportTiffArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let wsi = backend::open(&args.slide, ResampleFilter::default())?;
+    let export = TiffExport {
+        tile_size: args.tile_size,
+        compression: args.compression,
+        threads: export_threads(args.jobs),
+    };
+    info!(
+        "Exporting {} to {} with {} thread(s)",
+        args.slide.display(),
+        args.output.display(),
+        export.threads
+    );
+    export.run(
+        wsi.as_ref(),
+        args.bbox,
+        &args.output,
+        &progress_printer("tiles"),
+    )?;
+    eprintln!();
+    info!("Wrote {}", args.output.display());
+    Ok(())
+}
+
+/// Export the Deep Zoom pyramid of a slide, showing the progress on stderr.
+fn export_dz(args: ExportDzArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let name = match args.name {
+        Some(name) => name,
+        None => slide_name(&args.slide)?,
+    };
+    let options = DeepZoomGeneratorOptions::default()
+        .tile_size(args.tile_size)
+        .overlap(args.overlap)
+        .limit_bounds(!args.ignore_bounds)
+        .resample(args.resample);
+    let generator =
+        DeepZoomGenerator::from_slide(backend::open(&args.slide, args.resample)?, options)?;
+    let export = DeepZoomExport {
+        format: args.format,
+        quality: args.quality,
+        threads: export_threads(args.jobs),
+    };
+    info!(
+        "Exporting {} tiles of {} to {} with {} thread(s)",
+        generator.tile_count(),
+        args.slide.display(),
+        args.outdir.display(),
+        export.threads
+    );
+
+    export.run(&generator, &args.outdir, &name, &progress_printer("tiles"))?;
+    eprintln!();
+    info!(
+        "Wrote {}",
+        args.outdir.join(format!("{}.dzi", name)).display()
+    );
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
-    HttpServer::new(|| {
-        let args: Vec<String> = std::env::args().collect();
-        let filename = Path::new(&args[1]);
-        let mut viewers = HashMap::new();
-        viewers.insert(
-            "slide_1".to_string(),
-            DeepZoomGenerator::new(filename).expect("Could not start DeepZoomGenerator"),
-        );
-        let state = web::Data::new(viewers);
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        let exported = match command {
+            Command::ExportDz(args) => export_dz(args),
+            Command::ExportZarr(args) => export_zarr(args),
+            Command::ExportTiff(args) => export_tiff(args),
+        };
+        if let Err(err) = exported {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let config = match cli.serve.into_config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(2);
+        }
+    };
+    let slides = find_slides_in(&config.slides)?;
+    info!(
+        "Serving {} slide(s) from {}",
+        slides.len(),
+        config
+            .slides
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    // Slides are opened on first use and shared by all workers.
+    let idle_timeout = Duration::from_secs(config.cache.slide_idle_timeout_secs);
+    let registry = web::Data::new(SlideRegistry::new(
+        slides,
+        tile_geometries(config.tiles.resample),
+        config.tiles.resample,
+        NonZeroUsize::new(config.cache.max_open_slides).expect("validated to be at least 1"),
+        idle_timeout,
+    ));
+    let idle_registry = registry.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(idle_timeout / 4);
+        loop {
+            interval.tick().await;
+            let closed = idle_registry.close_idle();
+            if closed > 0 {
+                info!("Closed {} idle slide(s)", closed);
+            }
+        }
+    });
+
+    let cache_bytes = config
+        .cache
+        .tile_cache_bytes()
+        .expect("validated to fit in a usize");
+    let cache = web::Data::new(TileCache::new(cache_bytes));
+    let tile_threads = config
+        .tiles
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()));
+    let pool = web::Data::new(BlockingPool::new(
+        "tile-worker",
+        tile_threads,
+        config.tiles.queue_size,
+    )?);
+    info!(
+        "Producing tiles on {} threads, queueing at most {} tiles",
+        pool.threads(),
+        pool.queue_size()
+    );
+    let tile_config = web::Data::new(config.tiles.clone());
+    let static_dir = config.static_dir.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
             .wrap(middleware::DefaultHeaders::new().add(("Access-Control-Allow-Origin", "*")))
-            .app_data(state)
+            .app_data(registry.clone())
+            .app_data(cache.clone())
+            .app_data(pool.clone())
+            .app_data(tile_config.clone())
+            .route("/api/cache", web::get().to(get_cache_stats))
+            .route("/api/slides", web::get().to(list_slides))
+            .route(
+                "/api/slides/{slide}/properties",
+                web::get().to(get_properties),
+            )
+            .route("/api/slides/{slide}/region", web::get().to(get_region))
+            .route(
+                "/api/slides/{slide}/thumbnail",
+                web::get().to(get_thumbnail),
+            )
+            .route(
+                "/api/slides/{slide}/associated",
+                web::get().to(list_associated_images),
+            )
+            .route(
+                "/api/slides/{slide}/associated/{name}.{format}",
+                web::get().to(get_associated_image),
+            )
+            .route("/iiif/{slide}", web::get().to(iiif_redirect))
+            .route("/iiif/{slide}/info.json", web::get().to(get_iiif_info))
+            .route(
+                "/iiif/{slide}/{region}/{size}/{rotation}/{quality}.{format}",
+                web::get().to(get_iiif_image),
+            )
             .route("/{slide}.dzi", web::get().to(get_dzi))
+            .route("/{slide}.json", web::get().to(get_dzi_json))
             .route(
-                "/{slide}_files/{level}/{col}_{row}.jpg",
+                "/{slide}_files/{level}/{col}_{row}.{format}",
                 web::get().to(get_tile),
             )
-            .service(fs::Files::new("/static", "./public/static").show_files_listing())
-            .service(fs::Files::new("/", "./public/index.html").show_files_listing())
-    })
-    .bind("localhost:8080")?
-    .run()
-    .await
+            .route("/dz/{geometry}/{slide}.dzi", web::get().to(get_dzi))
+            .route("/dz/{geometry}/{slide}.json", web::get().to(get_dzi_json))
+            .route(
+                "/dz/{geometry}/{slide}_files/{level}/{col}_{row}.{format}",
+                web::get().to(get_tile),
+            )
+            .service(fs::Files::new("/static", static_dir.join("static")).show_files_listing())
+            .service(fs::Files::new("/", static_dir.join("index.html")).show_files_listing())
+    });
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    info!("Listening on {}:{}", config.bind, config.port);
+    server
+        .bind((config.bind.as_str(), config.port))?
+        .run()
+        .await
 }
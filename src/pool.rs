@@ -0,0 +1,187 @@
+//! A bounded pool of threads for blocking work.
+//!
+//! Producing a tile calls into OpenSlide, which blocks on file I/O, and then resizes and encodes
+//! the tile, which keeps a CPU busy. Doing this on the async executor would stall every other
+//! request handled by the same worker. Instead, the work is queued on a dedicated pool of threads.
+
+use log::{debug, error};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Reasons a job could not be run on the pool.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PoolError {
+    /// The queue is full.
+    Busy,
+    /// The pool threads have stopped.
+    Stopped,
+    /// The job panicked. The thread that ran it carries on with the next job.
+    Panicked,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Busy => write!(f, "Too many jobs queued"),
+            PoolError::Stopped => write!(f, "Pool has stopped"),
+            PoolError::Panicked => write!(f, "Job panicked"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+/// A fixed number of threads working off a bounded queue of jobs.
+pub struct BlockingPool {
+    sender: SyncSender<Job>,
+    threads: usize,
+    queue_size: usize,
+}
+
+impl BlockingPool {
+    /// Start `threads` threads, which share a queue of at most `queue_size` waiting jobs.
+    pub fn new(name: &str, threads: usize, queue_size: usize) -> std::io::Result<BlockingPool> {
+        let threads = threads.max(1);
+        let (sender, receiver) = sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || work(receiver))?;
+        }
+        Ok(BlockingPool {
+            sender,
+            threads,
+            queue_size,
+        })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    /// Run `f` on the pool and wait for its result.
+    ///
+    /// Fails immediately with `PoolError::Busy` if the queue is full. If the returned future is
+    /// dropped before the job has started (e.g. because the client went away), the job is
+    /// skipped. A panic in `f` (e.g. on a corrupt slide) is returned as `PoolError::Panicked`.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            if result_sender.is_closed() {
+                debug!("Skipping cancelled job");
+                return;
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+                let msg = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown cause");
+                error!("Job panicked: {}", msg);
+                PoolError::Panicked
+            });
+            // The receiver may have gone away in the meantime, in which case the result is
+            // simply dropped.
+            let _ = result_sender.send(result);
+        });
+        match self.sender.try_send(job) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => return Err(PoolError::Busy),
+            Err(TrySendError::Disconnected(_)) => return Err(PoolError::Stopped),
+        }
+        result_receiver.await.map_err(|_| PoolError::Stopped)?
+    }
+}
+
+fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // Hold the lock only while waiting for a job, not while running it.
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => job(),
+            // The pool was dropped.
+            Err(_) => return,
+        }
+    }
+}
+
+#[test]
+fn test_blocking_pool() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let pool = BlockingPool::new("test", 1, 1).unwrap();
+    assert_eq!(runtime.block_on(pool.run(|| 40 + 2)), Ok(42));
+
+    // Block the only thread, so the next job stays queued.
+    let (unblock, blocked) = std::sync::mpsc::channel::<()>();
+    let (started, wait_started) = std::sync::mpsc::channel::<()>();
+    let mut blocker = Box::pin(pool.run(move || {
+        started.send(()).unwrap();
+        blocked.recv().unwrap();
+    }));
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counted = runs.clone();
+    let mut queued = Box::pin(pool.run(move || counted.fetch_add(1, Ordering::SeqCst)));
+    runtime.block_on(async {
+        // Jobs are submitted when their future is first polled.
+        assert!(poll_once(blocker.as_mut()).await.is_none());
+        wait_started.recv().unwrap();
+        assert!(poll_once(queued.as_mut()).await.is_none());
+
+        // The queue is full.
+        assert_eq!(pool.run(|| ()).await, Err(PoolError::Busy));
+
+        // Cancel the queued job by dropping its future, then unblock the pool.
+        drop(queued);
+        unblock.send(()).unwrap();
+        assert_eq!(blocker.await, Ok(()));
+        // The cancelled job takes up the queue until the thread has skipped it.
+        loop {
+            match pool.run(|| ()).await {
+                Err(PoolError::Busy) => thread::yield_now(),
+                result => break assert_eq!(result, Ok(())),
+            }
+        }
+    });
+    assert_eq!(runs.load(Ordering::SeqCst), 0);
+
+    // A panicking job fails on its own, and the thread lives on.
+    let panicked = runtime.block_on(pool.run(|| -> u32 { panic!("corrupt tile") }));
+    assert_eq!(panicked, Err(PoolError::Panicked));
+    assert_eq!(runtime.block_on(pool.run(|| 7)), Ok(7));
+}
+
+/// Poll a future once, returning its output if it is ready.
+#[cfg(test)]
+async fn poll_once<F: std::future::Future + Unpin>(future: F) -> Option<F::Output> {
+    let mut future = future;
+    std::future::poll_fn(|cx| {
+        std::task::Poll::Ready(match std::pin::Pin::new(&mut future).poll(cx) {
+            std::task::Poll::Ready(output) => Some(output),
+            std::task::Poll::Pending => None,
+        })
+    })
+    .await
+}
@@ -0,0 +1,415 @@
+//! Reading arbitrary regions of a slide, scaled to an arbitrary size.
+//!
+//! Unlike Deep Zoom tiles, such regions can be much larger than what is sensible to read from
+//! the slide in one go, so they are read in chunks from the slide level that best fits the
+//! requested scale.
+
+use crate::backend::SlideBackend;
+use crate::generator::openslide::OpenSlideError;
+use image::codecs::tiff::TiffEncoder;
+use image::imageops::{self, FilterType};
+use image::{ColorType, DynamicImage, ImageOutputFormat, Rgb, RgbImage, RgbaImage};
+use std::fmt;
+use std::io::Cursor;
+
+/// Largest width or height read from the slide in one call to its backend.
+const MAX_READ_SIZE: u64 = 4096;
+
+/// Largest width and height of an extracted region.
+pub const MAX_OUTPUT_SIZE: u32 = 8192;
+
+/// Largest width and height of a thumbnail.
+pub const MAX_THUMBNAIL_SIZE: u32 = 2048;
+
+/// Errors of region extraction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegionError {
+    /// The requested region or resolution is not valid for the slide.
+    Invalid(String),
+    /// The slide could not be read.
+    Slide(OpenSlideError),
+    /// The region could not be encoded.
+    Encode(String),
+}
+
+impl fmt::Display for RegionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegionError::Invalid(msg) => write!(f, "Invalid region request: {}", msg),
+            RegionError::Slide(err) => write!(f, "{}", err),
+            RegionError::Encode(msg) => write!(f, "Could not encode region: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+impl From<OpenSlideError> for RegionError {
+    fn from(err: OpenSlideError) -> Self {
+        RegionError::Slide(err)
+    }
+}
+
+impl From<image::ImageError> for RegionError {
+    fn from(err: image::ImageError) -> Self {
+        RegionError::Encode(err.to_string())
+    }
+}
+
+/// The resolution at which a region is extracted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    /// The resolution of a slide level.
+    Level(u32),
+    /// A resolution in microns per pixel, which may lie between (or beyond) the slide levels.
+    Mpp(f64),
+}
+
+/// Lossless formats extracted regions can be encoded in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionFormat {
+    Png,
+    Tiff,
+}
+
+impl RegionFormat {
+    pub fn from_extension(extension: &str) -> Option<RegionFormat> {
+        match extension {
+            "png" => Some(RegionFormat::Png),
+            "tif" | "tiff" => Some(RegionFormat::Tiff),
+            _ => None,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            RegionFormat::Png => "image/png",
+            RegionFormat::Tiff => "image/tiff",
+        }
+    }
+
+    pub fn encode(&self, image: &RgbaImage) -> Result<Vec<u8>, RegionError> {
+        let mut buffer = Vec::new();
+        match self {
+            RegionFormat::Png => DynamicImage::ImageRgba8(image.clone())
+                .write_to(&mut buffer, ImageOutputFormat::Png)?,
+            RegionFormat::Tiff => TiffEncoder::new(Cursor::new(&mut buffer)).encode(
+                image,
+                image.width(),
+                image.height(),
+                ColorType::Rgba8,
+            )?,
+        }
+        Ok(buffer)
+    }
+}
+
+/// A rectangle in level 0 pixel coordinates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: u64,
+    pub y: u64,
+    pub width: u64,
+    pub height: u64,
+}
+
+impl Region {
+    pub fn new(x: u64, y: u64, width: u64, height: u64) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Clip the region to an image of the given dimensions.
+    ///
+    /// Returns `None` if nothing of the region is left.
+    pub fn clip(&self, (width, height): (u64, u64)) -> Option<Region> {
+        if self.x >= width || self.y >= height || self.width == 0 || self.height == 0 {
+            return None;
+        }
+        Some(Region {
+            x: self.x,
+            y: self.y,
+            width: self.width.min(width - self.x),
+            height: self.height.min(height - self.y),
+        })
+    }
+}
+
+/// Read a level 0 `region` of the slide, scaled to `size` (width, height).
+///
+/// The region is read from the best slide level for the downsample, in chunks of at most
+/// `MAX_READ_SIZE` pixels, so memory use is bounded by the output size. Parts of the region outside
+/// the slide are transparent.
+pub fn read_scaled_region(
+    wsi: &dyn SlideBackend,
+    region: Region,
+    size: (u32, u32),
+) -> Result<RgbaImage, OpenSlideError> {
+    let (out_w, out_h) = size;
+    if out_w == 0 || out_h == 0 || region.width == 0 || region.height == 0 {
+        return Err(OpenSlideError::InvalidArgument(format!(
+            "Can not scale region {:?} to {:?}",
+            region, size
+        )));
+    }
+    let scale = (
+        region.width as f64 / out_w as f64,
+        region.height as f64 / out_h as f64,
+    );
+    let downsample = scale.0.min(scale.1).max(1.0);
+    let level = wsi.best_level_for_downsample(downsample)?;
+    let level_downsample = wsi.level_downsample(level)?;
+
+    // Number of output pixels per chunk, so a chunk read from the level stays within bounds.
+    let read_per_output = (scale.0.max(scale.1) / level_downsample).max(1.0);
+    if read_per_output > MAX_READ_SIZE as f64 {
+        return Err(OpenSlideError::InvalidArgument(format!(
+            "Output size {:?} is too small for the levels of the slide",
+            size
+        )));
+    }
+    let chunk = ((MAX_READ_SIZE as f64 / read_per_output).floor() as u32).max(1);
+
+    let mut output = RgbaImage::new(out_w, out_h);
+    for chunk_y in (0..out_h).step_by(chunk as usize) {
+        let chunk_h = chunk.min(out_h - chunk_y);
+        let (l0_y, l0_h) = chunk_span(region.y, scale.1, chunk_y, chunk_h);
+        for chunk_x in (0..out_w).step_by(chunk as usize) {
+            let chunk_w = chunk.min(out_w - chunk_x);
+            let (l0_x, l0_w) = chunk_span(region.x, scale.0, chunk_x, chunk_w);
+            let l_w = (l0_w as f64 / level_downsample).ceil().max(1.0) as u64;
+            let l_h = (l0_h as f64 / level_downsample).ceil().max(1.0) as u64;
+
+            // At the edge of the slide, a backend may return less than was asked for. Only what
+            // was read is scaled, into its share of the chunk, and the rest is left transparent.
+            let read = wsi.read_region((l0_x, l0_y), level, (l_w, l_h))?;
+            let part_w = read_share(chunk_w, read.width(), l_w);
+            let part_h = read_share(chunk_h, read.height(), l_h);
+            if part_w == 0 || part_h == 0 {
+                continue;
+            }
+            let scaled = if read.dimensions() == (part_w, part_h) {
+                read
+            } else if read.width() >= part_w && read.height() >= part_h {
+                imageops::thumbnail(&read, part_w, part_h)
+            } else {
+                imageops::resize(&read, part_w, part_h, FilterType::Triangle)
+            };
+            imageops::replace(&mut output, &scaled, chunk_x, chunk_y);
+        }
+    }
+    Ok(output)
+}
+
+/// Extract `width` x `height` pixels at the given resolution, starting at the level 0 pixel
+/// `(x, y)`.
+///
+/// This follows `openslide_read_region`: the origin is in level 0 coordinates, the size is in
+/// pixels of the requested resolution, which is also the size of the result. Parts of the region
+/// outside the slide are transparent.
+pub fn extract_region(
+    wsi: &dyn SlideBackend,
+    (x, y): (u64, u64),
+    (width, height): (u32, u32),
+    resolution: Resolution,
+) -> Result<RgbaImage, RegionError> {
+    if width == 0 || height == 0 {
+        return Err(RegionError::Invalid(
+            "width and height must be at least 1".to_string(),
+        ));
+    }
+    if width > MAX_OUTPUT_SIZE || height > MAX_OUTPUT_SIZE {
+        return Err(RegionError::Invalid(format!(
+            "size {}x{} exceeds the maximum of {}x{}",
+            width, height, MAX_OUTPUT_SIZE, MAX_OUTPUT_SIZE
+        )));
+    }
+    let (slide_w, slide_h) = wsi.level0_dimensions()?;
+    if x >= slide_w || y >= slide_h {
+        return Err(RegionError::Invalid(format!(
+            "origin ({}, {}) lies outside the slide of {}x{}",
+            x, y, slide_w, slide_h
+        )));
+    }
+
+    let (downsample_x, downsample_y) = match resolution {
+        Resolution::Level(level) => {
+            let level_count = wsi.level_count()?;
+            if level >= level_count {
+                return Err(RegionError::Invalid(format!(
+                    "level {} does not exist, the slide has {} levels",
+                    level, level_count
+                )));
+            }
+            let downsample = wsi.level_downsample(level)?;
+            (downsample, downsample)
+        }
+        Resolution::Mpp(mpp) => {
+            if !(mpp.is_finite() && mpp > 0.0) {
+                return Err(RegionError::Invalid(format!(
+                    "invalid resolution {} mpp",
+                    mpp
+                )));
+            }
+            match wsi.mpp() {
+                Some((mpp_x, mpp_y)) => (mpp / mpp_x, mpp / mpp_y),
+                None => {
+                    return Err(RegionError::Invalid(
+                        "the slide does not record its resolution".to_string(),
+                    ))
+                }
+            }
+        }
+    };
+
+    let region = Region::new(
+        x,
+        y,
+        ((width as f64 * downsample_x).round() as u64).max(1),
+        ((height as f64 * downsample_y).round() as u64).max(1),
+    );
+    Ok(read_scaled_region(wsi, region, (width, height))?)
+}
+
+/// A thumbnail of the whole slide that fits in `max_size` x `max_size` pixels, keeping the aspect
+/// ratio of the slide.
+///
+/// The thumbnail is read from the smallest slide level that is still large enough, and flattened
+/// with `flatten`.
+pub fn thumbnail(wsi: &dyn SlideBackend, max_size: u32) -> Result<RgbImage, RegionError> {
+    if max_size == 0 || max_size > MAX_THUMBNAIL_SIZE {
+        return Err(RegionError::Invalid(format!(
+            "thumbnail size must be between 1 and {}",
+            MAX_THUMBNAIL_SIZE
+        )));
+    }
+    let dimensions = wsi.level0_dimensions()?;
+    let size = thumbnail_size(dimensions, max_size);
+    let image = read_scaled_region(wsi, Region::new(0, 0, dimensions.0, dimensions.1), size)?;
+    Ok(flatten(&image))
+}
+
+/// Composite an image over a white background, as transparent parts of a slide are not scanned.
+pub fn flatten(image: &RgbaImage) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let over_white = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32) + 127) / 255) as u8;
+        Rgb([over_white(r), over_white(g), over_white(b)])
+    })
+}
+
+/// The size of an image of `(width, height)` scaled down to fit in `max_size` x `max_size`.
+fn thumbnail_size((width, height): (u64, u64), max_size: u32) -> (u32, u32) {
+    let scale = (max_size as f64 / width.max(height) as f64).min(1.0);
+    (
+        ((width as f64 * scale).round() as u32).max(1),
+        ((height as f64 * scale).round() as u32).max(1),
+    )
+}
+
+/// The level 0 start and length of the output pixels `start..start + len`, given the level 0
+/// `origin` of the region and its `scale` (level 0 pixels per output pixel).
+fn chunk_span(origin: u64, scale: f64, start: u32, len: u32) -> (u64, u64) {
+    let l0_start = (start as f64 * scale).floor() as u64;
+    let l0_end = ((start + len) as f64 * scale).ceil() as u64;
+    (origin + l0_start, (l0_end - l0_start).max(1))
+}
+
+/// The output pixels, out of `chunk`, taken by `read` pixels out of the `requested` ones.
+fn read_share(chunk: u32, read: u32, requested: u64) -> u32 {
+    if read as u64 >= requested {
+        return chunk;
+    }
+    ((chunk as u64 * read as u64 + requested / 2) / requested) as u32
+}
+
+#[test]
+fn test_clip_region() {
+    let region = Region::new(90, 10, 20, 20);
+    assert_eq!(region.clip((100, 100)), Some(Region::new(90, 10, 10, 20)));
+    assert_eq!(region.clip((90, 100)), None);
+    assert_eq!(Region::new(0, 0, 0, 10).clip((100, 100)), None);
+}
+
+#[test]
+fn test_thumbnail_size() {
+    assert_eq!(thumbnail_size((100_000, 50_000), 512), (512, 256));
+    assert_eq!(thumbnail_size((30_000, 90_001), 512), (171, 512));
+    assert_eq!(thumbnail_size((100_000, 10), 512), (512, 1));
+    assert_eq!(thumbnail_size((300, 200), 512), (300, 200));
+}
+
+#[test]
+fn test_region_format() {
+    assert_eq!(RegionFormat::from_extension("png"), Some(RegionFormat::Png));
+    assert_eq!(
+        RegionFormat::from_extension("tif"),
+        Some(RegionFormat::Tiff)
+    );
+    assert_eq!(RegionFormat::from_extension("jpg"), None);
+
+    let image = RgbaImage::from_pixel(3, 2, image::Rgba([255, 0, 0, 255]));
+    for format in [RegionFormat::Png, RegionFormat::Tiff] {
+        let encoded = format.encode(&image).unwrap();
+        let decoded = image::load_from_memory(&encoded).unwrap().to_rgba8();
+        assert_eq!(decoded, image);
+    }
+}
+
+#[test]
+fn test_chunk_span() {
+    assert_eq!(chunk_span(100, 1.0, 0, 10), (100, 10));
+    assert_eq!(chunk_span(100, 4.0, 10, 10), (140, 40));
+    assert_eq!(chunk_span(0, 2.5, 1, 1), (2, 3));
+    assert_eq!(chunk_span(0, 0.5, 3, 1), (1, 1));
+}
+
+#[test]
+fn test_read_scaled_region_over_the_edge() {
+    /// A single level slide that returns only the part of a region inside it, like OpenSlide.
+    struct ClippingSlide;
+
+    impl SlideBackend for ClippingSlide {
+        fn level_count(&self) -> Result<u32, OpenSlideError> {
+            Ok(1)
+        }
+
+        fn level_dimensions(&self, _level: u32) -> Result<(u64, u64), OpenSlideError> {
+            Ok((100, 100))
+        }
+
+        fn level_downsample(&self, _level: u32) -> Result<f64, OpenSlideError> {
+            Ok(1.0)
+        }
+
+        fn read_region(
+            &self,
+            (x, y): (u64, u64),
+            _level: u32,
+            (width, height): (u64, u64),
+        ) -> Result<RgbaImage, OpenSlideError> {
+            let width = width.min(100u64.saturating_sub(x)) as u32;
+            let height = height.min(100u64.saturating_sub(y)) as u32;
+            Ok(RgbaImage::from_fn(width, height, |dx, dy| {
+                image::Rgba([(x as u32 + dx) as u8, (y as u32 + dy) as u8, 0, 255])
+            }))
+        }
+    }
+
+    // Half of the region lies right of the slide.
+    let image =
+        read_scaled_region(&ClippingSlide, Region::new(50, 20, 100, 100), (50, 50)).unwrap();
+    assert_eq!(image.dimensions(), (50, 50));
+    // Output pixels cover 2x2 level 0 pixels, also next to the edge.
+    let red = image.get_pixel(10, 0)[0];
+    assert!((70..=71).contains(&red), "red is {}", red);
+    assert_eq!(image.get_pixel(24, 0)[3], 255);
+    assert_eq!(image.get_pixel(25, 0)[3], 0);
+    assert_eq!(image.get_pixel(49, 49)[3], 0);
+    assert_eq!(image.get_pixel(24, 39)[3], 255);
+    assert_eq!(image.get_pixel(24, 40)[3], 0);
+}
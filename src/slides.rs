@@ -0,0 +1,580 @@
+//! Discovery of the slides to serve.
+//!
+//! The server is pointed at a root directory, which is walked recursively. Every file with the
+//! extension of a slide format or of an ordinary image is served under an ID derived from its path
+//! relative to the root. A directory of DICOM whole slide image instances is a single slide. The
+//! files are not opened while walking, so that large trees on network filesystems are found fast.
+//!
+//! Slides are only opened when they are first requested, and are shared by all server workers
+//! through the `SlideRegistry`.
+
+use crate::backend::{self, SlideBackend};
+use crate::generator::openslide::OpenSlideError;
+use crate::generator::resample::ResampleFilter;
+use crate::generator::{DeepZoomGenerator, DeepZoomGeneratorOptions, GeneratorError};
+use log::{debug, info, warn};
+use lru::LruCache;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Separator used in place of the path separator when deriving a slide ID.
+///
+/// Slide IDs must fit in a single URL path segment (e.g. `/{slide}.dzi`), so the `/` of the
+/// relative path can not be used as is. `~` is an unreserved URL character and very rarely occurs
+/// in file names.
+const ID_SEPARATOR: &str = "~";
+
+/// Name of the tile geometry served at the plain `/{slide}.dzi` URL.
+pub const DEFAULT_GEOMETRY: &str = "default";
+
+/// An opened slide, with a Deep Zoom generator for every tile geometry it is served with.
+pub struct Slide {
+    wsi: Arc<dyn SlideBackend>,
+    generators: BTreeMap<String, Arc<DeepZoomGenerator>>,
+}
+
+impl Slide {
+    /// Open the slide at `path` and set up a generator for each of the named `geometries`.
+    ///
+    /// Backends that build their own pyramid downsample it with `resample`.
+    pub fn open(
+        path: &Path,
+        geometries: &BTreeMap<String, DeepZoomGeneratorOptions>,
+        resample: ResampleFilter,
+    ) -> Result<Slide, GeneratorError> {
+        let wsi = backend::open(path, resample)?;
+        let mut generators = BTreeMap::new();
+        for (name, options) in geometries {
+            generators.insert(
+                name.clone(),
+                Arc::new(DeepZoomGenerator::from_slide(wsi.clone(), *options)?),
+            );
+        }
+        Ok(Slide { wsi, generators })
+    }
+
+    pub fn wsi(&self) -> &dyn SlideBackend {
+        self.wsi.as_ref()
+    }
+
+    /// The generator for the tile geometry with the given name.
+    pub fn generator(&self, geometry: &str) -> Option<&Arc<DeepZoomGenerator>> {
+        self.generators.get(geometry)
+    }
+}
+
+/// Key metadata of a slide, as listed in the slide catalog.
+///
+/// Only the ID is known of a slide that has not been opened or summarized yet.
+#[derive(Clone, Debug, Serialize)]
+pub struct SlideSummary {
+    pub id: String,
+    /// Width of level 0, in pixels.
+    pub width: Option<u64>,
+    /// Height of level 0, in pixels.
+    pub height: Option<u64>,
+    pub level_count: Option<u32>,
+    pub vendor: Option<String>,
+    pub objective_power: Option<u32>,
+    /// Micrometer per pixel in the horizontal direction.
+    pub mpp_x: Option<f32>,
+    /// Micrometer per pixel in the vertical direction.
+    pub mpp_y: Option<f32>,
+    /// The last error of the slide, if it failed and could not be reopened since.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SlideSummary {
+    pub fn new(id: &str, slide: &dyn SlideBackend) -> Result<SlideSummary, OpenSlideError> {
+        let (width, height) = slide.level0_dimensions()?;
+        let mpp = slide.mpp();
+        Ok(SlideSummary {
+            id: id.to_string(),
+            width: Some(width),
+            height: Some(height),
+            level_count: Some(slide.level_count()?),
+            vendor: slide.vendor(),
+            objective_power: slide.objective_power(),
+            mpp_x: mpp.map(|(x, _)| x as f32),
+            mpp_y: mpp.map(|(_, y)| y as f32),
+            error: None,
+        })
+    }
+
+    /// The summary of a slide that has not been opened or summarized yet.
+    fn unopened(id: &str) -> SlideSummary {
+        SlideSummary {
+            id: id.to_string(),
+            width: None,
+            height: None,
+            level_count: None,
+            vendor: None,
+            objective_power: None,
+            mpp_x: None,
+            mpp_y: None,
+            error: None,
+        }
+    }
+}
+
+/// Reasons a slide could not be provided by the registry.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegistryError {
+    /// There is no slide with the requested ID.
+    NotFound,
+    /// The slide exists but could not be opened.
+    Open(GeneratorError),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::NotFound => write!(f, "Slide not found"),
+            RegistryError::Open(err) => write!(f, "Could not open slide: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+struct OpenSlideEntry {
+    slide: Arc<Slide>,
+    last_used: Instant,
+}
+
+/// All slides that can be served, opened on first use and shared between threads.
+///
+/// At most `max_open` slides are kept open; when another slide is opened, the least recently used
+/// one is closed. Slides that have not been used for `idle_timeout` are closed by `close_idle()`.
+/// A closed slide is reopened on its next use. Slides that are still in use when they are closed
+/// stay open until the last request using them is done.
+///
+/// A slide whose backend has failed (e.g. OpenSlide put it in the error state after an I/O error)
+/// is closed when it is next requested, and reopened. Until it has been reopened successfully, its
+/// error is reported in its summary.
+///
+/// A slide is opened only once when it is requested by several threads at the same time: the
+/// others wait for it to be opened.
+pub struct SlideRegistry {
+    paths: BTreeMap<String, PathBuf>,
+    /// Held while the slide with the ID is opened.
+    opening: BTreeMap<String, Mutex<()>>,
+    geometries: BTreeMap<String, DeepZoomGeneratorOptions>,
+    resample: ResampleFilter,
+    open: Mutex<LruCache<String, OpenSlideEntry>>,
+    summaries: Mutex<HashMap<String, SlideSummary>>,
+    failures: Mutex<HashMap<String, String>>,
+    idle_timeout: Duration,
+}
+
+impl SlideRegistry {
+    /// Create a registry of the slides in `paths`, keyed by slide ID, each of which is served with
+    /// all of the tile `geometries`. Nothing is opened yet.
+    ///
+    /// Slides that are not pyramids themselves (ordinary images) are downsampled with `resample`.
+    pub fn new(
+        paths: BTreeMap<String, PathBuf>,
+        geometries: BTreeMap<String, DeepZoomGeneratorOptions>,
+        resample: ResampleFilter,
+        max_open: NonZeroUsize,
+        idle_timeout: Duration,
+    ) -> SlideRegistry {
+        SlideRegistry {
+            opening: paths
+                .keys()
+                .map(|id| (id.clone(), Mutex::new(())))
+                .collect(),
+            paths,
+            geometries,
+            resample,
+            open: Mutex::new(LruCache::new(max_open)),
+            summaries: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            idle_timeout,
+        }
+    }
+
+    /// IDs of all slides, in order.
+    pub fn ids(&self) -> impl Iterator<Item = &String> {
+        self.paths.keys()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.paths.contains_key(id)
+    }
+
+    /// Names of the tile geometries the slides are served with.
+    pub fn geometries(&self) -> &BTreeMap<String, DeepZoomGeneratorOptions> {
+        &self.geometries
+    }
+
+    /// Number of slides that are currently open.
+    pub fn open_count(&self) -> usize {
+        lock(&self.open).len()
+    }
+
+    /// Get a slide if it is already open, without blocking on opening it.
+    ///
+    /// A slide in the error state is closed, so it is reopened by the next `get()`.
+    pub fn get_open(&self, id: &str) -> Option<Arc<Slide>> {
+        let mut open = lock(&self.open);
+        let entry = open.get_mut(id)?;
+        if let Some(msg) = entry.slide.wsi().error() {
+            warn!("Slide {} failed, closing it: {}", id, msg);
+            open.pop(id);
+            lock(&self.failures).insert(id.to_string(), msg);
+            return None;
+        }
+        entry.last_used = Instant::now();
+        Some(entry.slide.clone())
+    }
+
+    /// The last error of a slide that failed and has not been reopened since.
+    pub fn failure(&self, id: &str) -> Option<String> {
+        lock(&self.failures).get(id).cloned()
+    }
+
+    /// Get a slide, opening it if needed.
+    ///
+    /// Opening a slide blocks, so avoid calling this from async code.
+    pub fn get(&self, id: &str) -> Result<Arc<Slide>, RegistryError> {
+        if let Some(slide) = self.get_open(id) {
+            return Ok(slide);
+        }
+        let path = self.paths.get(id).ok_or(RegistryError::NotFound)?;
+
+        // Only the lock of this slide is held while opening, so other slides can be served in the
+        // meantime. Whoever waited for it finds the slide opened.
+        let _opening = lock(&self.opening[id]);
+        if let Some(slide) = self.get_open(id) {
+            return Ok(slide);
+        }
+        let opened = Slide::open(path, &self.geometries, self.resample).map_err(|err| {
+            lock(&self.failures).insert(id.to_string(), err.to_string());
+            RegistryError::Open(err)
+        })?;
+        info!("Opened slide {}", id);
+        lock(&self.failures).remove(id);
+        let summary =
+            SlideSummary::new(id, opened.wsi()).map_err(|err| RegistryError::Open(err.into()))?;
+        lock(&self.summaries).insert(id.to_string(), summary);
+
+        let mut open = lock(&self.open);
+        let slide = Arc::new(opened);
+        let entry = OpenSlideEntry {
+            slide: slide.clone(),
+            last_used: Instant::now(),
+        };
+        if let Some((closed, _)) = open.push(id.to_string(), entry) {
+            debug!("Closing least recently used slide {}", closed);
+        }
+        Ok(slide)
+    }
+
+    /// Get the summary of a slide, as far as it is known without opening the slide.
+    ///
+    /// A slide is summarized when it is first opened, and the summary is kept after the slide is
+    /// closed. Use `summarize()` to summarize a slide that has not been opened yet.
+    pub fn summary(&self, id: &str) -> Option<SlideSummary> {
+        if !self.contains(id) {
+            return None;
+        }
+        let mut summary = lock(&self.summaries)
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| SlideSummary::unopened(id));
+        summary.error = self.failure(id);
+        Some(summary)
+    }
+
+    /// Get the summary of a slide, opening the slide first if it has not been summarized yet.
+    ///
+    /// A slide that is opened only to be summarized is closed again right away, so that
+    /// summarizing many slides does not close the slides that are in use. A slide that failed to
+    /// open is not retried here; its error is reported in its summary until `get()` reopens it.
+    ///
+    /// Opening a slide blocks, so avoid calling this from async code.
+    pub fn summarize(&self, id: &str) -> Option<SlideSummary> {
+        let path = self.paths.get(id)?;
+        let known = |id: &str| lock(&self.summaries).contains_key(id) || self.failure(id).is_some();
+        if !known(id) {
+            let _opening = lock(&self.opening[id]);
+            if !known(id) {
+                let summary = backend::open(path, self.resample)
+                    .and_then(|wsi| SlideSummary::new(id, wsi.as_ref()))
+                    .map_err(GeneratorError::from);
+                match summary {
+                    Ok(summary) => {
+                        debug!("Summarized slide {}", id);
+                        lock(&self.summaries).insert(id.to_string(), summary);
+                    }
+                    Err(err) => {
+                        warn!("Could not summarize slide {}: {}", id, err);
+                        lock(&self.failures).insert(id.to_string(), err.to_string());
+                    }
+                }
+            }
+        }
+        self.summary(id)
+    }
+
+    /// Close all slides that have not been used for longer than the idle timeout.
+    ///
+    /// Returns the number of slides that were closed.
+    pub fn close_idle(&self) -> usize {
+        let mut open = lock(&self.open);
+        let idle: Vec<String> = open
+            .iter()
+            .filter(|(_, entry)| entry.last_used.elapsed() > self.idle_timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &idle {
+            debug!("Closing idle slide {}", id);
+            open.pop(id);
+        }
+        idle.len()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Derive the slide ID from the path of a slide relative to the slide root.
+///
+/// The ID is stable for as long as the slide is not moved within the root directory.
+pub fn slide_id(relative_path: &Path) -> String {
+    relative_path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(ID_SEPARATOR)
+}
+
+/// Find all slides below `root` that one of the backends can open, keyed by slide ID.
+///
+/// If `root` is a file or a DICOM slide rather than a directory of slides, it is served on its own
+/// under its file name.
+pub fn find_slides(root: &Path) -> io::Result<BTreeMap<String, PathBuf>> {
+    let mut slides = BTreeMap::new();
+    if root.is_dir() && !backend::is_supported(root) {
+        walk(root, root, &mut slides)?;
+    } else {
+        let file_name = root.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Not a slide or directory: {}", root.display()),
+            )
+        })?;
+        if !backend::is_supported(root) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Not a supported slide or image: {}", root.display()),
+            ));
+        }
+        slides.insert(slide_id(Path::new(file_name)), root.to_path_buf());
+    }
+    Ok(slides)
+}
+
+/// Find all slides below each of the `roots`, as with `find_slides()`.
+///
+/// When two roots contain a slide with the same ID, the one in the root listed first is kept.
+pub fn find_slides_in(roots: &[PathBuf]) -> io::Result<BTreeMap<String, PathBuf>> {
+    let mut slides: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for root in roots {
+        for (id, path) in find_slides(root)? {
+            if let Some(existing) = slides.get(&id) {
+                warn!(
+                    "Skipping {}: slide ID {} is already taken by {}",
+                    path.display(),
+                    id,
+                    existing.display()
+                );
+                continue;
+            }
+            slides.insert(id, path);
+        }
+    }
+    Ok(slides)
+}
+
+fn walk(root: &Path, dir: &Path, slides: &mut BTreeMap<String, PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // Symlinked directories are not followed to avoid walking in circles. DICOM slides are
+        // directories themselves.
+        let is_dir = entry.file_type()?.is_dir();
+        if is_dir && is_deep_zoom_tiles(&path) {
+            debug!("Skipping Deep Zoom tiles in {}", path.display());
+            continue;
+        }
+        let slide = (is_dir || path.is_file()) && backend::is_supported(&path);
+        if is_dir && !slide {
+            walk(root, &path, slides)?;
+        } else if slide {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let id = slide_id(relative);
+            if let Some(existing) = slides.get(&id) {
+                warn!(
+                    "Skipping {}: slide ID {} is already taken by {}",
+                    path.display(),
+                    id,
+                    existing.display()
+                );
+                continue;
+            }
+            debug!("Found slide {} at {}", id, path.display());
+            slides.insert(id, path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `dir` holds the tiles of an exported Deep Zoom image: `{name}_files` next to
+/// `{name}.dzi`. Its tiles are images, but not slides.
+fn is_deep_zoom_tiles(dir: &Path) -> bool {
+    let name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix("_files"));
+    match name {
+        Some(name) => dir.with_file_name(format!("{}.dzi", name)).is_file(),
+        None => false,
+    }
+}
+
+#[test]
+fn test_slide_id() {
+    assert_eq!(slide_id(Path::new("slide.svs")), "slide.svs");
+    assert_eq!(
+        slide_id(Path::new("lung/case 12/HE.svs")),
+        "lung~case 12~HE.svs"
+    );
+    assert_eq!(slide_id(Path::new("./lung/HE.svs")), "lung~HE.svs");
+}
+
+#[test]
+fn test_registry_unknown_slide() {
+    let mut paths = BTreeMap::new();
+    paths.insert(
+        "missing.svs".to_string(),
+        PathBuf::from("/nonexistent/missing.svs"),
+    );
+    let registry = SlideRegistry::new(
+        paths,
+        BTreeMap::new(),
+        ResampleFilter::Area,
+        NonZeroUsize::new(2).unwrap(),
+        Duration::from_secs(60),
+    );
+
+    assert!(registry.contains("missing.svs"));
+    assert_eq!(
+        registry.get("other.svs").err(),
+        Some(RegistryError::NotFound)
+    );
+    assert!(matches!(
+        registry.get("missing.svs"),
+        Err(RegistryError::Open(GeneratorError::Slide(
+            OpenSlideError::NotFound(_)
+        )))
+    ));
+    assert!(registry.failure("missing.svs").is_some());
+    assert_eq!(registry.failure("other.svs"), None);
+    assert_eq!(registry.open_count(), 0);
+    assert_eq!(registry.close_idle(), 0);
+}
+
+#[test]
+fn test_registry_opens_slide_once() {
+    let mut paths = BTreeMap::new();
+    paths.insert(
+        "CMU-1-Small-Region.svs".to_string(),
+        PathBuf::from("./assets/CMU-1-Small-Region.svs"),
+    );
+    let registry = SlideRegistry::new(
+        paths,
+        BTreeMap::new(),
+        ResampleFilter::Area,
+        NonZeroUsize::new(2).unwrap(),
+        Duration::from_secs(60),
+    );
+    let summary = registry.summary("CMU-1-Small-Region.svs").unwrap();
+    assert_eq!(summary.width, None);
+    assert!(registry.summary("other.svs").is_none());
+
+    let slides: Vec<_> = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| registry.get("CMU-1-Small-Region.svs").unwrap()))
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect()
+    });
+    assert!(slides.iter().all(|slide| Arc::ptr_eq(slide, &slides[0])));
+    assert_eq!(registry.open_count(), 1);
+    let summary = registry.summary("CMU-1-Small-Region.svs").unwrap();
+    assert_eq!(summary.width, Some(2220));
+}
+
+#[test]
+fn test_registry_summarizes_slide() {
+    let mut paths = BTreeMap::new();
+    paths.insert(
+        "CMU-1-Small-Region.svs".to_string(),
+        PathBuf::from("./assets/CMU-1-Small-Region.svs"),
+    );
+    paths.insert(
+        "missing.svs".to_string(),
+        PathBuf::from("/nonexistent/missing.svs"),
+    );
+    let registry = SlideRegistry::new(
+        paths,
+        BTreeMap::new(),
+        ResampleFilter::Area,
+        NonZeroUsize::new(2).unwrap(),
+        Duration::from_secs(60),
+    );
+
+    let summary = registry.summarize("CMU-1-Small-Region.svs").unwrap();
+    assert_eq!(summary.width, Some(2220));
+    assert_eq!(summary.error, None);
+    assert_eq!(registry.open_count(), 0);
+    assert_eq!(
+        registry.summary("CMU-1-Small-Region.svs").unwrap().width,
+        Some(2220)
+    );
+    let summary = registry.summarize("missing.svs").unwrap();
+    assert_eq!(summary.width, None);
+    assert!(summary.error.is_some());
+    assert!(registry.summarize("other.svs").is_none());
+}
+
+#[test]
+fn test_find_slides_skips_deep_zoom_tiles() {
+    let root = std::env::temp_dir().join(format!("slidestream-find-{}", std::process::id()));
+    let tiles = root.join("export").join("HE_files").join("0");
+    std::fs::create_dir_all(&tiles).unwrap();
+    std::fs::write(root.join("export").join("HE.dzi"), "").unwrap();
+    std::fs::write(tiles.join("0_0.jpeg"), "").unwrap();
+    std::fs::write(root.join("overview.png"), "").unwrap();
+    std::fs::write(root.join("notes.txt"), "").unwrap();
+    let slides = find_slides(&root);
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let ids: Vec<_> = slides.unwrap().into_keys().collect();
+    assert_eq!(ids, vec!["overview.png"]);
+}